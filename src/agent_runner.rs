@@ -1,30 +1,134 @@
 use rand::prelude::*;
 
 use super::board;
+use std::sync::Arc;
+
 use super::game;
+use super::introspect::{Explanation, Stats};
+use super::rules;
+use super::spawn;
 use super::utils;
 
+// What a player can see of a game between moves
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub board: board::Board,
+    // What's shown of the tile the next move will place
+    pub next_hint: spawn::NextHint,
+}
+
+impl Observation {
+    pub fn of(game: &game::Game) -> Observation {
+        Observation {
+            board: game.cur_board,
+            next_hint: game.next_hint(),
+        }
+    }
+}
+
+// One move of a game being trained on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub before: Observation,
+    pub action: board::Direction,
+    pub after: Observation,
+    pub reward: f64,
+    // The move ended the game, so nothing follows `after`
+    pub terminal: bool,
+}
+
 pub trait Agent {
     fn take_action(&mut self, game: &game::Game, train_mode: bool) -> board::Direction;
-    fn update(
-        &mut self,
-        board: &board::Board,
-        action: board::Direction,
-        new_board: &board::Board,
-        reward: f64,
-    );
-    fn print(&self);
+    // Learn from a move, in train mode
+    fn update(&mut self, transition: &Transition);
+    // Called before the first move of every game, and after the last with how it went, so agents
+    // can learn from whole games rather than single moves
+    fn start_episode(&mut self, _seed: u64, _train_mode: bool) {}
+    fn end_episode(&mut self, _result: &game::GameResult, _train_mode: bool) {}
+    // A report of what the agent has done or learned, such as how much of a table it has filled.
+    // Agents with nothing to report needn't say more than that they're an agent.
+    fn stats(&self) -> Stats {
+        Stats::new("agent")
+    }
+    // Replace the agent's random number generator, so that training can be resumed exactly from
+    // the start of any generation. Agents which never train can ignore this. Training with
+    // several workers at once is never exact, however the workers are reseeded.
+    fn reseed(&mut self, _seed: &mut StdRng) {}
+    // Called by the trainer before each generation, for agents whose settings change over
+    // training, such as how much they explore
+    fn start_generation(&mut self, _generation: i32, _num_generations: i32) {}
+    // Why the agent would make the move it makes in `game`, for agents which can say: how it
+    // rates each available move and what it took to get there
+    fn explain(&mut self, _game: &game::Game) -> Option<Explanation> {
+        None
+    }
+    // What went wrong, if the agent couldn't choose its own moves, such as an external program
+    // which stopped answering. The moves it made after that aren't really its own.
+    fn failure(&self) -> Option<String> {
+        None
+    }
+}
+
+// So that agents picked at run time can be played like any other
+impl<A: Agent + ?Sized> Agent for Box<A> {
+    fn take_action(&mut self, game: &game::Game, train_mode: bool) -> board::Direction {
+        (**self).take_action(game, train_mode)
+    }
+    fn update(&mut self, transition: &Transition) {
+        (**self).update(transition)
+    }
+    fn start_episode(&mut self, seed: u64, train_mode: bool) {
+        (**self).start_episode(seed, train_mode)
+    }
+    fn end_episode(&mut self, result: &game::GameResult, train_mode: bool) {
+        (**self).end_episode(result, train_mode)
+    }
+    fn stats(&self) -> Stats {
+        (**self).stats()
+    }
+    fn reseed(&mut self, seed: &mut StdRng) {
+        (**self).reseed(seed)
+    }
+    fn start_generation(&mut self, generation: i32, num_generations: i32) {
+        (**self).start_generation(generation, num_generations)
+    }
+    fn explain(&mut self, game: &game::Game) -> Option<Explanation> {
+        (**self).explain(game)
+    }
+    fn failure(&self) -> Option<String> {
+        (**self).failure()
+    }
 }
 
 pub fn play_game<A: Agent>(
     seed: Option<&mut StdRng>,
     agent: &mut A,
     train_mode: bool,
+) -> game::GameResult {
+    play_game_with_rules(seed, rules::classic(), agent, train_mode)
+}
+
+pub fn play_game_with_rules<A: Agent>(
+    seed: Option<&mut StdRng>,
+    rules: Arc<dyn rules::RuleSet>,
+    agent: &mut A,
+    train_mode: bool,
 ) -> game::GameResult {
     let mut rng = utils::resolve_rng_from_seed(seed);
+    play_seeded_game(rng.next_u64(), rules, agent, train_mode)
+}
+
+// Play the game with this seed to the end
+pub fn play_seeded_game<A: Agent>(
+    seed: u64,
+    rules: Arc<dyn rules::RuleSet>,
+    agent: &mut A,
+    train_mode: bool,
+) -> game::GameResult {
     // Only log if we're testing
     let do_logging = !train_mode;
-    let mut game = game::Game::new(Some(rng.next_u64()), do_logging);
+    let mut game = game::Game::with_rules(Some(seed), do_logging, rules);
+    agent.start_episode(seed, train_mode);
 
     let first_direction = agent.take_action(&game, train_mode);
     game.update(first_direction);
@@ -33,18 +137,25 @@ pub fn play_game<A: Agent>(
         let options = game.available_moves();
         assert!(!options.is_empty());
         let direction = agent.take_action(&game, train_mode);
-        let prev_board = game.cur_board;
+        let before = Observation::of(&game);
         let prev_score = game.cur_score();
         let move_result = game.update(direction);
         let new_score = game.cur_score();
         let reward = new_score - prev_score;
         if train_mode {
-            agent.update(&prev_board, direction, &game.cur_board, reward as f64);
+            agent.update(&Transition {
+                before,
+                action: direction,
+                after: Observation::of(&game),
+                reward: reward as f64,
+                terminal: matches!(move_result, game::MoveResult::Moved(Some(_))),
+            });
         }
         // We already checked the available moves, this should work
         match move_result {
             game::MoveResult::Moved(Some(result)) => {
                 assert!(game.available_moves().is_empty());
+                agent.end_episode(&result, train_mode);
 
                 return result;
             }
@@ -53,3 +164,64 @@ pub fn play_game<A: Agent>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::introspect::Stats;
+    use crate::random_agent::RandomAgent;
+
+    // Plays randomly, keeping track of what it's told
+    struct Recorder {
+        random: RandomAgent,
+        started: Vec<u64>,
+        transitions: Vec<Transition>,
+        ended: Vec<game::Score>,
+    }
+
+    impl Agent for Recorder {
+        fn take_action(&mut self, game: &game::Game, train_mode: bool) -> board::Direction {
+            self.random.take_action(game, train_mode)
+        }
+        fn update(&mut self, transition: &Transition) {
+            self.transitions.push(*transition);
+        }
+        fn start_episode(&mut self, seed: u64, _train_mode: bool) {
+            self.started.push(seed);
+        }
+        fn end_episode(&mut self, result: &game::GameResult, _train_mode: bool) {
+            self.ended.push(result.score);
+        }
+        fn stats(&self) -> Stats {
+            Stats::new("recorder")
+        }
+    }
+
+    #[test]
+    fn test_episode_hooks() {
+        let mut agent = Recorder {
+            random: RandomAgent::new(Some(&mut StdRng::seed_from_u64(0))),
+            started: Vec::new(),
+            transitions: Vec::new(),
+            ended: Vec::new(),
+        };
+        let result = play_seeded_game(7, rules::classic(), &mut agent, true);
+        assert_eq!(agent.started, vec![7]);
+        assert_eq!(agent.ended, vec![result.score]);
+        // Every move but the first, which only places the first tile, is learned from, and only
+        // the last ends the game
+        assert_eq!(agent.transitions.len(), result.num_moves as usize - 1);
+        let (last, rest) = agent.transitions.split_last().unwrap();
+        assert!(last.terminal && rest.iter().all(|t| !t.terminal));
+        assert_eq!(last.after.board, result.final_board);
+        for (t, next) in rest.iter().zip(agent.transitions[1..].iter()) {
+            assert_eq!(t.after, next.before);
+        }
+
+        // Games which aren't trained on still start and end
+        play_seeded_game(8, rules::classic(), &mut agent, false);
+        assert_eq!(agent.started, vec![7, 8]);
+        assert_eq!(agent.ended.len(), 2);
+        assert_eq!(agent.transitions.len(), result.num_moves as usize - 1);
+    }
+}
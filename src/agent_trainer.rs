@@ -1,9 +1,20 @@
+use rand::prelude::*;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
 
 use super::agent_runner;
 use super::agent_runner::Agent;
+use super::eval;
+use super::exploration;
 use super::game::GameLog;
 use super::game::Score;
+use super::introspect::Stats;
+use super::rules;
 use super::utils;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,9 +24,80 @@ pub struct PlayedGame {
     pub game_log: Option<GameLog>,
 }
 
+// Everything needed to reproduce a training run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrainConfig {
+    // The master seed, which the agent's rng and every game's seed are drawn from
+    pub seed: u64,
+    pub agent: String,
+    pub num_generations: i32,
+    pub num_episodes_per_gen: i32,
+    pub learning_rate: f64,
+    pub discount_factor: f64,
+    pub explore_rate: f64,
+    // Only used by the n-tuple agent
+    pub trace_decay: Option<f64>,
+    pub network: Option<String>,
+    // Where the agent was loaded from, if training didn't start from scratch
+    pub load_agent: Option<String>,
+    // How many agents play training episodes at the same time. Runs with more than one aren't
+    // reproducible, since the order the agents learn in depends on how their threads are timed.
+    #[serde(default = "default_num_workers")]
+    pub num_workers: usize,
+    // How a q agent explores, and how its explore rate changes over the run
+    #[serde(default)]
+    pub exploration: exploration::Strategy,
+    #[serde(default)]
+    pub explore_schedule: exploration::Schedule,
+    // The seed suite the agent is evaluated on after each generation, if any
+    #[serde(default)]
+    pub eval_suite: Option<String>,
+}
+
+fn default_num_workers() -> usize {
+    1
+}
+
+// The train command's defaults, without evaluating after each generation
+impl Default for TrainConfig {
+    fn default() -> TrainConfig {
+        TrainConfig {
+            seed: 0,
+            agent: "q".to_string(),
+            num_generations: 100,
+            num_episodes_per_gen: 1000,
+            learning_rate: 0.5,
+            discount_factor: 0.9,
+            explore_rate: 0.1,
+            trace_decay: None,
+            network: None,
+            load_agent: None,
+            num_workers: default_num_workers(),
+            exploration: exploration::Strategy::default(),
+            explore_schedule: exploration::Schedule::default(),
+            eval_suite: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GenerationEvaluation {
+    pub gen_id: i32,
+    pub evaluation: eval::Evaluation,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TrainingOutcomes {
+    // Missing from results saved before runs were seeded
+    #[serde(default)]
+    pub config: Option<TrainConfig>,
     pub games_played: Vec<PlayedGame>,
+    // Only present if the run had an evaluation suite
+    #[serde(default)]
+    pub evaluations: Vec<GenerationEvaluation>,
+    // What the agent had learned by the end of the run
+    #[serde(default)]
+    pub stats: Option<Stats>,
 }
 
 pub struct TrainResult<'a, A: Agent> {
@@ -23,33 +105,307 @@ pub struct TrainResult<'a, A: Agent> {
     pub agent: &'a A,
 }
 
-pub fn train_agent_from_scratch<A: Agent>(
-    agent: &mut A,
-    num_generations: i32,
-    num_episodes_per_gen: i32,
-) -> TrainResult<A> {
-    let mut rng = utils::resolve_rng_from_seed(None);
+// Checkpoints start with this, then the version, then the progress as JSON, then the agent in its
+// own binary format
+const CHECKPOINT_MAGIC: &[u8; 4] = b"THCK";
+pub const CHECKPOINT_VERSION: u32 = 1;
+// Far more than any run's progress takes, so that a corrupt length fails rather than allocating
+const MAX_CHECKPOINT_HEADER_LEN: u64 = 1 << 30;
+
+// How far a training run has got. Each generation's rngs are drawn from the master seed and the
+// generation's index, so along with the agent this is all that's needed to carry on the run.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrainProgress {
+    // How many generations are finished
+    pub generation: i32,
+    pub outcomes: TrainingOutcomes,
+}
+
+impl TrainProgress {
+    pub fn new(config: &TrainConfig) -> TrainProgress {
+        TrainProgress {
+            generation: 0,
+            outcomes: TrainingOutcomes {
+                config: Some(config.clone()),
+                games_played: Vec::new(),
+                evaluations: Vec::new(),
+                stats: None,
+            },
+        }
+    }
+
+    pub fn config(&self) -> &TrainConfig {
+        self.outcomes
+            .config
+            .as_ref()
+            .expect("training progress has no config")
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.generation >= self.config().num_generations
+    }
+}
+
+// The rng which generation `gen_id` of a run with `config` is played with
+fn generation_rng(config: &TrainConfig, gen_id: i32) -> StdRng {
+    let mut seed = <StdRng as SeedableRng>::Seed::default();
+    seed[..8].copy_from_slice(&config.seed.to_le_bytes());
+    seed[8..12].copy_from_slice(&gen_id.to_le_bytes());
+    StdRng::from_seed(seed)
+}
+
+pub fn train_agent_from_scratch<'a, A: Agent>(
+    agent: &'a mut A,
+    config: &TrainConfig,
+) -> TrainResult<'a, A> {
+    let mut progress = TrainProgress::new(config);
+    continue_training(agent, &mut progress, |_, _| false);
+    TrainResult {
+        outcomes: progress.outcomes,
+        agent,
+    }
+}
+
+// Train `agent` from wherever `progress` is up to. `after_generation` is called once each
+// generation is finished, and training stops early if it returns true.
+pub fn continue_training<A, F>(agent: &mut A, progress: &mut TrainProgress, mut after_generation: F)
+where
+    A: Agent,
+    F: FnMut(&A, &TrainProgress) -> bool,
+{
+    while !progress.is_finished() {
+        let mut rng = generation_rng(progress.config(), progress.generation);
+        agent.reseed(&mut rng);
+        agent.start_generation(progress.generation, progress.config().num_generations);
 
-    let mut games_played = Vec::new();
-    for gen_id in 0..num_generations {
         // Train
-        for _episode in 0..num_episodes_per_gen {
+        for _episode in 0..progress.config().num_episodes_per_gen {
             // Note that we're running with the SAME game every time here
             let mut new_rng = utils::resolve_rng_from_seed(Some(&mut rng));
             let _result = agent_runner::play_game(Some(&mut new_rng), agent, true);
         }
 
-        // Test
-        let mut new_rng = utils::resolve_rng_from_seed(Some(&mut rng));
-        let result = agent_runner::play_game(Some(&mut new_rng), agent, false);
-        let score = result.score;
-        let game_log = result.log;
-        games_played.push(PlayedGame {
-            gen_id,
-            score,
-            game_log,
+        test_generation(agent, &mut rng, progress);
+        if after_generation(agent, progress) {
+            break;
+        }
+    }
+}
+
+// Like `continue_training`, but each generation's training episodes are shared out among
+// `agents`, which all play at once. The agents should learn into the same place, such as a
+// SharedQTable; the first one plays the test games. The agents' updates interleave however the
+// threads happen to run, so the same seed doesn't always train the same table, and resuming from
+// a checkpoint doesn't repeat the run that wrote it.
+pub fn continue_training_in_parallel<A, F>(
+    agents: &mut [A],
+    progress: &mut TrainProgress,
+    mut after_generation: F,
+) where
+    A: Agent + Send,
+    F: FnMut(&A, &TrainProgress) -> bool,
+{
+    let num_agents = agents.len();
+    while !progress.is_finished() {
+        let mut rng = generation_rng(progress.config(), progress.generation);
+        for agent in agents.iter_mut() {
+            agent.reseed(&mut rng);
+            agent.start_generation(progress.generation, progress.config().num_generations);
+        }
+
+        // Train
+        let episode_rngs = (0..progress.config().num_episodes_per_gen)
+            .map(|_| utils::resolve_rng_from_seed(Some(&mut rng)))
+            .collect::<Vec<StdRng>>();
+        agents.par_iter_mut().enumerate().for_each(|(i, agent)| {
+            for mut episode_rng in episode_rngs.iter().skip(i).step_by(num_agents).cloned() {
+                let _result = agent_runner::play_game(Some(&mut episode_rng), agent, true);
+            }
+        });
+
+        test_generation(&mut agents[0], &mut rng, progress);
+        if after_generation(&agents[0], progress) {
+            break;
+        }
+    }
+}
+
+// Play and record the game which shows how well the agent is doing after a generation, and
+// evaluate the agent on the run's suite
+fn test_generation<A: Agent>(agent: &mut A, rng: &mut StdRng, progress: &mut TrainProgress) {
+    let mut new_rng = utils::resolve_rng_from_seed(Some(rng));
+    let result = agent_runner::play_game(Some(&mut new_rng), agent, false);
+    progress.outcomes.games_played.push(PlayedGame {
+        gen_id: progress.generation,
+        score: result.score,
+        game_log: result.log,
+    });
+    if let Some(name) = progress.config().eval_suite.as_ref() {
+        let suite = eval::SeedSuite::by_name(name)
+            .unwrap_or_else(|| panic!("unknown evaluation suite {}", name));
+        let evaluation = eval::evaluate(agent, rules::classic(), &suite)
+            .unwrap_or_else(|e| panic!("could not evaluate the agent: {}", e));
+        progress.outcomes.evaluations.push(GenerationEvaluation {
+            gen_id: progress.generation,
+            evaluation,
+        });
+    }
+    progress.generation += 1;
+}
+
+// Save `progress`, and the agent using `write_agent`. The checkpoint is written next to `path`
+// first and then moved into place, so a crash never leaves a half-written checkpoint behind.
+pub fn save_checkpoint<F>(path: &Path, progress: &TrainProgress, write_agent: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(CHECKPOINT_MAGIC)?;
+    writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+    let header = serde_json::to_vec(progress)?;
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(&header)?;
+    write_agent(&mut writer)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+// Load the progress from a checkpoint, returning a reader for the agent which follows it
+pub fn load_checkpoint(path: &Path) -> io::Result<(TrainProgress, BufReader<File>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != CHECKPOINT_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a training checkpoint",
+        ));
+    }
+    let mut word = [0; 4];
+    reader.read_exact(&mut word)?;
+    let version = u32::from_le_bytes(word);
+    if version != CHECKPOINT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "checkpoint is version {} but only version {} can be read",
+                version, CHECKPOINT_VERSION
+            ),
+        ));
+    }
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_CHECKPOINT_HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checkpoint header claims to be {} bytes long", len),
+        ));
+    }
+    let mut header = vec![0; len as usize];
+    reader.read_exact(&mut header)?;
+    let progress = serde_json::from_slice(&header)?;
+    Ok((progress, reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::q_agent::{QAgent, QTable};
+
+    fn config(seed: u64) -> TrainConfig {
+        TrainConfig {
+            seed,
+            num_generations: 4,
+            num_episodes_per_gen: 5,
+            learning_rate: 0.1,
+            eval_suite: Some(eval::SMALL.to_string()),
+            ..TrainConfig::default()
+        }
+    }
+
+    fn new_agent(config: &TrainConfig) -> QAgent {
+        QAgent::new(
+            None,
+            config.learning_rate,
+            config.discount_factor,
+            config.explore_rate,
+        )
+    }
+
+    fn train(config: &TrainConfig) -> (String, usize) {
+        let mut agent = new_agent(config);
+        let result = train_agent_from_scratch(&mut agent, config);
+        (
+            serde_json::to_string(&result.outcomes).unwrap(),
+            agent.q_table().len(),
+        )
+    }
+
+    #[test]
+    fn test_seeded_runs_are_reproducible() {
+        let config = config(7);
+        let (outcomes, num_entries) = train(&config);
+        assert_eq!(train(&config), (outcomes.clone(), num_entries));
+
+        let saved: TrainingOutcomes = serde_json::from_str(&outcomes).unwrap();
+        assert_eq!(saved.config, Some(config.clone()));
+        assert_eq!(saved.games_played.len(), 4);
+        assert_eq!(saved.evaluations.len(), 4);
+        assert_eq!(saved.evaluations[3].gen_id, 3);
+        assert_eq!(saved.evaluations[3].evaluation.num_games, 20);
+
+        assert_ne!(train(&self::config(8)).0, outcomes);
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let config = config(3);
+        let path = std::env::temp_dir().join("threes_test_checkpoint");
+
+        // Stop halfway through, as if interrupted
+        let mut agent = new_agent(&config);
+        let mut progress = TrainProgress::new(&config);
+        continue_training(&mut agent, &mut progress, |agent, progress| {
+            save_checkpoint(&path, progress, |w| agent.q_table().write_binary(w)).unwrap();
+            progress.generation == 2
         });
+        assert!(!progress.is_finished());
+
+        let (mut progress, mut reader) = load_checkpoint(&path).unwrap();
+        assert_eq!(progress.generation, 2);
+        assert_eq!(progress.outcomes.games_played.len(), 2);
+        let mut agent = new_agent(progress.config());
+        agent.set_q_table(QTable::read_binary(&mut reader).unwrap());
+        continue_training(&mut agent, &mut progress, |_, _| false);
+        assert!(progress.is_finished());
+
+        // The interrupted run ends up exactly where an uninterrupted one does
+        assert_eq!(
+            (
+                serde_json::to_string(&progress.outcomes).unwrap(),
+                agent.q_table().len()
+            ),
+            train(&config)
+        );
+    }
+
+    #[test]
+    fn test_load_bad_checkpoint() {
+        let path = std::env::temp_dir().join("threes_test_bad_checkpoint");
+        let mut contents = CHECKPOINT_MAGIC.to_vec();
+        contents.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        contents.extend_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &contents).unwrap();
+        let e = load_checkpoint(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, b"not a checkpoint").unwrap();
+        assert!(load_checkpoint(&path).is_err());
     }
-    let outcomes = TrainingOutcomes { games_played };
-    TrainResult { outcomes, agent }
 }
@@ -1,4 +1,5 @@
 import os
+import random
 import subprocess
 import time
 import json
@@ -33,13 +34,28 @@ class TrainingOutcomes:
     def scores(self):
         return [g["score"] for g in self.d()["games_played"]]
 
+    def agent_stats(self):
+        stats = self.d().get("stats")
+        return None if stats is None else stats["values"]
+
     def validate(self):
-        assert set(self._outcomes.keys()) == {"games_played"}
+        # Results from before runs were seeded have no config, from before runs were evaluated
+        # have no evaluations, and from before agents reported stats have no stats
+        assert (
+            {"games_played"}
+            <= set(self._outcomes.keys())
+            <= {"games_played", "config", "evaluations", "stats"}
+        )
+        config = self.d().get("config")
+        if config is not None:
+            assert {"seed", "agent", "num_generations", "num_episodes_per_gen"} <= set(
+                config.keys()
+            )
         for game in self.d()["games_played"]:
             assert set(game.keys()) == {"score", "gen_id", "game_log"}
         # spot check a game
         game_log = self.d()["games_played"][0]["game_log"]
-        assert set(game_log.keys()) == {"seed", "moves"}
+        assert {"seed", "moves"} <= set(game_log.keys()) <= {"seed", "moves", "rules"}
         assert set(game_log["moves"]).issubset({"Up", "Down", "Left", "Right"})
 
     def as_dataframe(self):
@@ -56,6 +72,7 @@ def run_training(
     explore_rate=None,
     num_generations=NUM_GEN,
     num_episodes_per_gen=100,
+    seed=None,
 ):
 
     cmdline = [
@@ -81,6 +98,10 @@ def run_training(
     if num_episodes_per_gen is not None:
         cmdline.extend(["--num_episodes_per_gen", str(num_episodes_per_gen)])
 
+    if seed is not None:
+        cmdline.extend(["--seed", str(seed)])
+
+    # Without a seed, an earlier run of the same settings with any seed will do
     cmd_hash = hashlib.md5(" ".join(cmdline).encode("utf-8")).hexdigest()
 
     result_file = f"train_file_{cmd_hash}.json"
@@ -90,6 +111,11 @@ def run_training(
     if retrain:
         start = time.time()
 
+        if seed is None:
+            # The trainer records the seed in the result's config, so the run can be repeated
+            seed = random.randrange(2**64)
+            cmdline.extend(["--seed", str(seed)])
+
         cmdline.extend(["--result_file", result_file])
         result = subprocess.run(cmdline)
         result.check_returncode()
@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::OnceLock;
 
 pub const WIDTH: usize = 4;
 const NUM_BLOCKS: usize = WIDTH * WIDTH;
@@ -19,8 +22,117 @@ pub const ALL_DIRECTIONS: [Direction; 4] = [
     Direction::Right,
 ];
 
+impl Direction {
+    // The direction this becomes when the board is transposed
+    pub fn transpose(self) -> Direction {
+        match self {
+            Direction::Down => Direction::Right,
+            Direction::Up => Direction::Left,
+            Direction::Left => Direction::Up,
+            Direction::Right => Direction::Down,
+        }
+    }
+
+    // The direction this becomes when the board is mirrored left to right
+    pub fn reflect_horizontal(self) -> Direction {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            d => d,
+        }
+    }
+
+    // The direction this becomes when the board is mirrored top to bottom
+    pub fn reflect_vertical(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            d => d,
+        }
+    }
+
+    pub fn rotate_clockwise(self) -> Direction {
+        self.transpose().reflect_horizontal()
+    }
+}
+
+// One of the 8 ways to rotate or reflect the board: transpose if asked, then mirror the rows
+// and columns if asked. Shoving commutes with all of them as long as the direction is mapped
+// too, so boards which are symmetries of each other are equally good positions.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Symmetry {
+    transpose: bool,
+    reflect_horizontal: bool,
+    reflect_vertical: bool,
+}
+
+pub const ALL_SYMMETRIES: [Symmetry; 8] = {
+    let mut symmetries = [Symmetry::IDENTITY; 8];
+    let mut i = 0;
+    while i < 8 {
+        symmetries[i] = Symmetry {
+            transpose: i & 1 != 0,
+            reflect_horizontal: i & 2 != 0,
+            reflect_vertical: i & 4 != 0,
+        };
+        i += 1;
+    }
+    symmetries
+};
+
+impl Symmetry {
+    pub const IDENTITY: Symmetry = Symmetry {
+        transpose: false,
+        reflect_horizontal: false,
+        reflect_vertical: false,
+    };
+
+    pub fn apply(&self, board: &Board) -> Board {
+        let mut out = *board;
+        if self.transpose {
+            out = out.transpose();
+        }
+        if self.reflect_horizontal {
+            out = out.reflect_horizontal();
+        }
+        if self.reflect_vertical {
+            out = out.reflect_vertical();
+        }
+        out
+    }
+
+    // Where a move in direction `d` on a board is a move to on the transformed board
+    pub fn map_direction(&self, d: Direction) -> Direction {
+        let mut out = d;
+        if self.transpose {
+            out = out.transpose();
+        }
+        if self.reflect_horizontal {
+            out = out.reflect_horizontal();
+        }
+        if self.reflect_vertical {
+            out = out.reflect_vertical();
+        }
+        out
+    }
+
+    // The symmetry which undoes this one
+    pub fn inverse(&self) -> Symmetry {
+        if self.transpose {
+            // Mirroring after transposing is the same as transposing after the opposite mirror
+            Symmetry {
+                transpose: true,
+                reflect_horizontal: self.reflect_vertical,
+                reflect_vertical: self.reflect_horizontal,
+            }
+        } else {
+            *self
+        }
+    }
+}
+
 pub type Rank = i32;
-// This is 2 ** 16 * 3, which is the max possible tile. If you have 16 tiles
+// This is 2 ** 15 * 3, which is the max possible tile. If you have 16 tiles
 // going from 3, 6, 12,... 98304, then it is impossible to combine any more tiles and the game has been won.
 pub const MAX_RANK: Rank = 98304;
 type BoardBlocks = [Rank; NUM_BLOCKS];
@@ -30,12 +142,61 @@ const ZERO_BOARD_BLOCKS: BoardBlocks = [0; NUM_BLOCKS];
 const ZERO_SECTION: Section = [0; WIDTH];
 const ZERO_BOARD_SECTIONS: BoardSections = [ZERO_SECTION; WIDTH];
 
+// The index of a rank within RANKS; this is what is actually stored in the board. Rule sets
+// other than Threes give their own meaning to each index.
+pub type RankIndex = u8;
+pub type IndexSection = [RankIndex; WIDTH];
+pub const NUM_RANKS: usize = 19;
+const RANKS: [Rank; NUM_RANKS] = [
+    0, 1, 2, 3, 6, 12, 24, 48, 96, 192, 384, 768, 1536, 3072, 6144, 12288, 24576, 49152, 98304,
+];
+const BITS_PER_BLOCK: usize = 5;
+const BLOCK_MASK: u128 = 0x1F;
+const BITS_PER_ROW: usize = BITS_PER_BLOCK * WIDTH;
+const ROW_MASK: u128 = (1 << BITS_PER_ROW) - 1;
+const BOARD_MASK: u128 = (1 << (BITS_PER_ROW * WIDTH)) - 1;
+const NUM_ROW_STATES: usize = 1 << BITS_PER_ROW;
+
+// A mask covering the given blocks of a packed board
+const fn block_mask(blocks: &[usize]) -> u128 {
+    let mut mask = 0;
+    let mut i = 0;
+    while i < blocks.len() {
+        mask |= BLOCK_MASK << (blocks[i] * BITS_PER_BLOCK);
+        i += 1;
+    }
+    mask
+}
+
+const fn column_mask(col: usize) -> u128 {
+    block_mask(&[col, WIDTH + col, 2 * WIDTH + col, 3 * WIDTH + col])
+}
+
+pub fn rank_to_index(rank: Rank) -> RankIndex {
+    match try_rank_to_index(rank) {
+        Some(idx) => idx,
+        None => panic!("{} is not a valid rank", rank),
+    }
+}
+
+pub fn try_rank_to_index(rank: Rank) -> Option<RankIndex> {
+    RANKS
+        .iter()
+        .position(|r| *r == rank)
+        .map(|idx| idx as RankIndex)
+}
+
+#[inline]
+pub fn index_to_rank(idx: RankIndex) -> Rank {
+    RANKS[idx as usize]
+}
+
 pub fn combine(in1: Rank, in2: Rank) -> Option<Rank> {
     if in1 == 0 || in2 == 0 {
         None
     } else if (in1 == 1 && in2 == 2) || (in1 == 2 && in2 == 1) {
         Some(3)
-    } else if in1 == in2 && ![1, 2].contains(&in1) {
+    } else if in1 == in2 && ![1, 2].contains(&in1) && in1 != MAX_RANK {
         Some(2 * in1)
     } else {
         None
@@ -43,6 +204,7 @@ pub fn combine(in1: Rank, in2: Rank) -> Option<Rank> {
 }
 
 // Returns the new section and whether there was a shift in the section
+#[cfg(test)]
 fn shift(in_sec: &Section, increasing: bool) -> (Section, bool) {
     let mut oriented_sec: Section = *in_sec;
     if increasing {
@@ -86,29 +248,225 @@ fn shift_down(in_sec: &Section) -> (Section, bool) {
     (out_sec, block_moved)
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+fn decode_index_row(row: u32) -> IndexSection {
+    let mut sec = [0; WIDTH];
+    for (i, idx) in sec.iter_mut().enumerate() {
+        *idx = ((row as u128 >> (i * BITS_PER_BLOCK)) & BLOCK_MASK) as RankIndex;
+    }
+    sec
+}
+
+fn encode_index_row(sec: &IndexSection) -> u32 {
+    sec.iter().enumerate().fold(0, |row, (i, idx)| {
+        row | (*idx as u32) << (i * BITS_PER_BLOCK)
+    })
+}
+
+fn decode_row(row: u32) -> Section {
+    let mut sec = ZERO_SECTION;
+    for (rank, idx) in sec.iter_mut().zip(decode_index_row(row).iter()) {
+        *rank = index_to_rank(*idx);
+    }
+    sec
+}
+
+fn encode_row(sec: &Section) -> u32 {
+    let mut idx_sec = [0; WIDTH];
+    for (idx, rank) in idx_sec.iter_mut().zip(sec.iter()) {
+        *idx = rank_to_index(*rank);
+    }
+    encode_index_row(&idx_sec)
+}
+
+// The result of shifting every possible packed row towards column 0 (left) or column 3 (right).
+// Up and down are handled by transposing the board and using these same tables.
+pub struct RowTables {
+    left: Vec<u32>,
+    right: Vec<u32>,
+}
+
+impl RowTables {
+    // Build the tables from a function which shifts a section towards index 0. Rows holding
+    // indices past the last rank can't be on a board, and are left as they are.
+    pub fn from_shift_down<F: Fn(&IndexSection) -> IndexSection>(shift_down: F) -> RowTables {
+        let mut left = (0..NUM_ROW_STATES as u32).collect::<Vec<u32>>();
+        let mut right = left.clone();
+        for row in 0..NUM_ROW_STATES {
+            let sec = decode_index_row(row as u32);
+            if sec.iter().any(|idx| *idx as usize >= NUM_RANKS) {
+                continue;
+            }
+            left[row] = encode_index_row(&shift_down(&sec));
+            let mut reversed = sec;
+            reversed.reverse();
+            let mut shifted = shift_down(&reversed);
+            shifted.reverse();
+            right[row] = encode_index_row(&shifted);
+        }
+        RowTables { left, right }
+    }
+}
+
+// The tables for the standard Threes movement rules
+fn row_tables() -> &'static RowTables {
+    static TABLES: OnceLock<RowTables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        RowTables::from_shift_down(|sec| {
+            let mut ranks = ZERO_SECTION;
+            for (rank, idx) in ranks.iter_mut().zip(sec.iter()) {
+                *rank = index_to_rank(*idx);
+            }
+            let mut shifted = [0; WIDTH];
+            for (idx, rank) in shifted.iter_mut().zip(shift_down(&ranks).0.iter()) {
+                *idx = rank_to_index(*rank);
+            }
+            shifted
+        })
+    })
+}
+
+// Swap rows and columns of a packed board: first swap the off diagonal blocks within each 2x2
+// quarter, then swap the two off diagonal quarters
+fn transpose(cells: u128) -> u128 {
+    const KEEP_BLOCKS: u128 = block_mask(&[0, 2, 5, 7, 8, 10, 13, 15]);
+    const UPPER_BLOCKS: u128 = block_mask(&[1, 3, 9, 11]);
+    const LOWER_BLOCKS: u128 = block_mask(&[4, 6, 12, 14]);
+    const KEEP_QUARTERS: u128 = block_mask(&[0, 1, 4, 5, 10, 11, 14, 15]);
+    const UPPER_QUARTER: u128 = block_mask(&[2, 3, 6, 7]);
+    const LOWER_QUARTER: u128 = block_mask(&[8, 9, 12, 13]);
+    let a = (cells & KEEP_BLOCKS)
+        | ((cells & UPPER_BLOCKS) << (3 * BITS_PER_BLOCK))
+        | ((cells & LOWER_BLOCKS) >> (3 * BITS_PER_BLOCK));
+    (a & KEEP_QUARTERS)
+        | ((a & LOWER_QUARTER) >> (6 * BITS_PER_BLOCK))
+        | ((a & UPPER_QUARTER) << (6 * BITS_PER_BLOCK))
+}
+
+fn shove_rows(cells: u128, table: &[u32]) -> u128 {
+    (0..WIDTH).fold(0, |out, r| {
+        let row = (cells >> (r * BITS_PER_ROW)) & ROW_MASK;
+        out | (table[row as usize] as u128) << (r * BITS_PER_ROW)
+    })
+}
+
+// The board is a packed set of 5 bit rank indices. Block (r, c) lives at bits
+// 5 * (r * WIDTH + c), so each row is a contiguous 20 bits.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(try_from = "SerializedBoard", into = "SerializedBoard")]
 pub struct Board {
+    cells: u128,
+}
+
+// Boards are serialized as their ranks so that logs stay readable
+#[derive(Serialize, Deserialize)]
+struct SerializedBoard {
     blocks: BoardBlocks,
 }
 
+impl From<Board> for SerializedBoard {
+    fn from(board: Board) -> SerializedBoard {
+        SerializedBoard {
+            blocks: board.values(),
+        }
+    }
+}
+
+impl TryFrom<SerializedBoard> for Board {
+    type Error = String;
+
+    fn try_from(serialized: SerializedBoard) -> Result<Board, String> {
+        let mut board = Board::new();
+        for (i, rank) in serialized.blocks.iter().enumerate() {
+            let idx = try_rank_to_index(*rank).ok_or_else(|| format!("{} is not a rank", rank))?;
+            board.set_index(i / WIDTH, i % WIDTH, idx);
+        }
+        Ok(board)
+    }
+}
+
+impl fmt::Debug for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Board").field("rows", &self.rows()).finish()
+    }
+}
+
 impl Board {
     pub fn new() -> Board {
-        Board {
-            blocks: ZERO_BOARD_BLOCKS,
-        }
+        Board { cells: 0 }
     }
 
-    #[cfg(test)]
     pub fn from_rows(rows: &BoardSections) -> Board {
         let mut board = Board::new();
-        for i in 0..WIDTH {
-            for c in 0..WIDTH {
-                board.blocks[i * WIDTH + c] = rows[i][c];
-            }
+        for (i, row) in rows.iter().enumerate() {
+            board.set_row(i, row);
         }
         board
     }
 
+    // The packed blocks, for storing boards compactly
+    pub fn to_bits(&self) -> u128 {
+        self.cells
+    }
+
+    // The board packed into `cells` by `to_bits`, if every block holds a rank
+    pub fn from_bits(cells: u128) -> Option<Board> {
+        let board = Board { cells };
+        if cells & !BOARD_MASK == 0
+            && board
+                .indices()
+                .iter()
+                .all(|idx| (*idx as usize) < NUM_RANKS)
+        {
+            Some(board)
+        } else {
+            None
+        }
+    }
+
+    // Swap rows and columns
+    pub fn transpose(&self) -> Board {
+        Board {
+            cells: transpose(self.cells),
+        }
+    }
+
+    // Mirror left to right
+    pub fn reflect_horizontal(&self) -> Board {
+        let c = self.cells;
+        Board {
+            cells: ((c & column_mask(0)) << (3 * BITS_PER_BLOCK))
+                | ((c & column_mask(1)) << BITS_PER_BLOCK)
+                | ((c & column_mask(2)) >> BITS_PER_BLOCK)
+                | ((c & column_mask(3)) >> (3 * BITS_PER_BLOCK)),
+        }
+    }
+
+    // Mirror top to bottom
+    pub fn reflect_vertical(&self) -> Board {
+        let c = self.cells;
+        let row = |r: usize| (c >> (r * BITS_PER_ROW)) & ROW_MASK;
+        Board {
+            cells: (row(0) << (3 * BITS_PER_ROW))
+                | (row(1) << (2 * BITS_PER_ROW))
+                | (row(2) << BITS_PER_ROW)
+                | row(3),
+        }
+    }
+
+    pub fn rotate_clockwise(&self) -> Board {
+        self.transpose().reflect_horizontal()
+    }
+
+    // The same representative of this board and all its symmetries, with the symmetry which
+    // turns this board into it
+    pub fn canonical(&self) -> (Board, Symmetry) {
+        ALL_SYMMETRIES
+            .iter()
+            .map(|sym| (sym.apply(self), *sym))
+            .min_by_key(|(board, _)| board.cells)
+            .unwrap()
+    }
+
     pub fn simple_render(&self) -> String {
         let rows = self
             .rows()
@@ -124,89 +482,81 @@ impl Board {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.blocks == ZERO_BOARD_BLOCKS
+        self.cells == 0
     }
 
     fn get_row(&self, r: usize) -> Section {
-        let mut new_row = ZERO_SECTION;
-        for i in 0..WIDTH {
-            new_row[i] = self.blocks[r * WIDTH + i];
-        }
-        new_row
+        decode_row(((self.cells >> (r * BITS_PER_ROW)) & ROW_MASK) as u32)
     }
 
     fn set_row(&mut self, r: usize, sec: &Section) {
-        for i in 0..WIDTH {
-            self.blocks[r * WIDTH + i] = sec[i];
-        }
+        let shift = r * BITS_PER_ROW;
+        self.cells = (self.cells & !(ROW_MASK << shift)) | (encode_row(sec) as u128) << shift;
     }
 
     pub fn rows(&self) -> BoardSections {
         let mut sections = ZERO_BOARD_SECTIONS;
-        for i in 0..WIDTH {
-            sections[i] = self.get_row(i);
+        for (i, section) in sections.iter_mut().enumerate() {
+            *section = self.get_row(i);
         }
         sections
     }
 
-    fn get_col(&self, c: usize) -> Section {
-        let mut new_col = ZERO_SECTION;
-        for i in 0..WIDTH {
-            new_col[i] = self.blocks[i * WIDTH + c];
+    pub fn cols(&self) -> BoardSections {
+        Board {
+            cells: transpose(self.cells),
         }
-        new_col
+        .rows()
     }
 
-    fn set_col(&mut self, c: usize, sec: &Section) {
-        for i in 0..WIDTH {
-            self.blocks[i * WIDTH + c] = sec[i];
+    // The Threes rank of every block
+    pub fn values(&self) -> BoardBlocks {
+        let mut blocks = ZERO_BOARD_BLOCKS;
+        for (block, idx) in blocks.iter_mut().zip(self.indices().iter()) {
+            *block = index_to_rank(*idx);
         }
+        blocks
     }
 
-    pub fn cols(&self) -> BoardSections {
-        let mut sections = ZERO_BOARD_SECTIONS;
-        for i in 0..WIDTH {
-            sections[i] = self.get_col(i);
+    pub fn set_value(&mut self, row: usize, col: usize, value: Rank) {
+        self.set_index(row, col, rank_to_index(value));
+    }
+
+    // The rank index of every block, in the same order as values()
+    pub fn indices(&self) -> [RankIndex; NUM_BLOCKS] {
+        let mut indices = [0; NUM_BLOCKS];
+        for (i, idx) in indices.iter_mut().enumerate() {
+            *idx = ((self.cells >> (i * BITS_PER_BLOCK)) & BLOCK_MASK) as RankIndex;
         }
-        sections
+        indices
     }
 
-    pub fn values(&self) -> BoardBlocks {
-        self.blocks
+    pub fn get_index(&self, row: usize, col: usize) -> RankIndex {
+        ((self.cells >> ((row * WIDTH + col) * BITS_PER_BLOCK)) & BLOCK_MASK) as RankIndex
     }
 
-    pub fn set_value(&mut self, row: usize, col: usize, value: Rank) {
-        self.blocks[row * WIDTH + col] = value;
+    pub fn set_index(&mut self, row: usize, col: usize, idx: RankIndex) {
+        assert!((idx as usize) < NUM_RANKS);
+        let shift = (row * WIDTH + col) * BITS_PER_BLOCK;
+        self.cells = (self.cells & !(BLOCK_MASK << shift)) | (idx as u128) << shift;
     }
 
     // Push the board in a certain direction
     // Returns true if the board was modified
     pub fn shove(&mut self, d: Direction) -> bool {
-        let increasing = matches!(d, Direction::Down | Direction::Right);
+        self.shove_with(row_tables(), d)
+    }
 
-        let mut modified = false;
-        match d {
-            Direction::Down | Direction::Up => {
-                for i in 0..WIDTH {
-                    let section = self.get_col(i);
-                    let (new_col, col_modified) = shift(&section, increasing);
-                    if col_modified {
-                        modified = true;
-                        self.set_col(i, &new_col);
-                    }
-                }
-            }
-            Direction::Left | Direction::Right => {
-                for i in 0..WIDTH {
-                    let section = self.get_row(i);
-                    let (new_row, row_modified) = shift(&section, increasing);
-                    if row_modified {
-                        modified = true;
-                        self.set_row(i, &new_row);
-                    }
-                }
-            }
+    // Push the board in a certain direction using some other movement rules
+    pub fn shove_with(&mut self, tables: &RowTables, d: Direction) -> bool {
+        let new_cells = match d {
+            Direction::Left => shove_rows(self.cells, &tables.left),
+            Direction::Right => shove_rows(self.cells, &tables.right),
+            Direction::Up => transpose(shove_rows(transpose(self.cells), &tables.left)),
+            Direction::Down => transpose(shove_rows(transpose(self.cells), &tables.right)),
         };
+        let modified = new_cells != self.cells;
+        self.cells = new_cells;
         modified
     }
 }
@@ -215,6 +565,31 @@ impl Board {
 mod tests {
     use super::*;
 
+    // The original section-by-section shove, kept to check the packed implementation against
+    fn reference_shove(board: &Board, d: Direction) -> (Board, bool) {
+        let increasing = matches!(d, Direction::Down | Direction::Right);
+        let vertical = matches!(d, Direction::Down | Direction::Up);
+        let sections = if vertical { board.cols() } else { board.rows() };
+        let mut new_sections = ZERO_BOARD_SECTIONS;
+        let mut modified = false;
+        for (i, section) in sections.iter().enumerate() {
+            let (new_section, section_modified) = shift(section, increasing);
+            modified |= section_modified;
+            new_sections[i] = new_section;
+        }
+        let new_board = Board::from_rows(&new_sections);
+        if vertical {
+            (
+                Board {
+                    cells: transpose(new_board.cells),
+                },
+                modified,
+            )
+        } else {
+            (new_board, modified)
+        }
+    }
+
     #[test]
     fn test_shift() {
         // Test simple shifting for forwards and backwards
@@ -237,6 +612,17 @@ mod tests {
         assert_eq!(shift(&[3, 3, 0, 0], false), ([6, 0, 0, 0], true));
         assert_eq!(shift(&[3, 6, 0, 0], false), ([3, 6, 0, 0], false));
         assert_eq!(shift(&[6, 6, 0, 0], false), ([12, 0, 0, 0], true));
+
+        assert_eq!(
+            shift(&[49152, 49152, 0, 0], false),
+            ([MAX_RANK, 0, 0, 0], true)
+        );
+
+        // The largest tile does not combine
+        assert_eq!(
+            shift(&[MAX_RANK, MAX_RANK, 0, 0], false),
+            ([MAX_RANK, MAX_RANK, 0, 0], false)
+        );
     }
 
     #[test]
@@ -249,10 +635,163 @@ mod tests {
         let b = Board::from_rows(&[[1, 2, 3, 6], [3, 3, 6, 6], [3, 3, 6, 6], [3, 3, 6, 6]]);
         assert_eq!(b, b);
         let mut board = Board::new();
-        board.set_row(0, &[1, 2, 8, 4]);
+        board.set_row(0, &[1, 2, 48, 3]);
         assert_eq!(
             board,
-            Board::from_rows(&[[1, 2, 8, 4], ZERO_SECTION, ZERO_SECTION, ZERO_SECTION])
+            Board::from_rows(&[[1, 2, 48, 3], ZERO_SECTION, ZERO_SECTION, ZERO_SECTION])
         );
+        assert_eq!(board.values()[2], 48);
+        assert_eq!(board.cols()[2], [48, 0, 0, 0]);
+        board.set_value(3, 2, MAX_RANK);
+        assert_eq!(board.rows()[3], [0, 0, MAX_RANK, 0]);
+    }
+
+    // Every packed row which holds only ranks
+    fn valid_rows() -> impl Iterator<Item = u32> {
+        (0..NUM_ROW_STATES as u32).filter(|row| {
+            decode_index_row(*row)
+                .iter()
+                .all(|idx| (*idx as usize) < NUM_RANKS)
+        })
+    }
+
+    #[test]
+    fn test_row_encoding() {
+        for row in valid_rows() {
+            assert_eq!(encode_row(&decode_row(row)), row);
+        }
+    }
+
+    #[test]
+    fn test_bits() {
+        let b = Board::from_rows(&[
+            [1, 2, 3, 6],
+            [0, 0, 0, 0],
+            [0, MAX_RANK, 0, 0],
+            [0, 0, 3, 6],
+        ]);
+        assert_eq!(Board::from_bits(b.to_bits()), Some(b));
+        // Blocks past the last rank, and bits past the last block
+        assert_eq!(Board::from_bits(b.to_bits() | 0x1F), None);
+        assert_eq!(Board::from_bits(b.to_bits() | 1 << 100), None);
+    }
+
+    #[test]
+    fn test_transpose() {
+        let b = Board::from_rows(&[
+            [1, 2, 3, 6],
+            [12, 24, 48, 96],
+            [0, 0, 0, MAX_RANK],
+            [0, 49152, 3, 6],
+        ]);
+        let t = Board {
+            cells: transpose(b.cells),
+        };
+        assert_eq!(t.rows(), b.cols());
+        assert_eq!(transpose(t.cells), b.cells);
+    }
+
+    #[test]
+    fn test_shove_matches_shift_down() {
+        // Every possible row, and a sample of them in every row and column of the board, in every
+        // direction
+        for (n, row) in valid_rows().enumerate() {
+            let sec = decode_row(row);
+            let (shifted, moved) = shift_down(&sec);
+            assert_eq!(row_tables().left[row as usize] != row, moved);
+            assert_eq!(decode_row(row_tables().left[row as usize]), shifted);
+            if n % 16 != 0 {
+                continue;
+            }
+
+            for i in 0..WIDTH {
+                let mut board = Board::new();
+                board.set_row(i, &sec);
+                for transposed in &[false, true] {
+                    if *transposed {
+                        board.cells = transpose(board.cells);
+                    }
+                    for d in &ALL_DIRECTIONS {
+                        let (expected, expected_modified) = reference_shove(&board, *d);
+                        let mut shoved = board;
+                        assert_eq!(shoved.shove(*d), expected_modified);
+                        assert_eq!(shoved, expected);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_symmetries() {
+        let b = Board::from_rows(&[[1, 2, 3, 6], [12, 24, 48, 96], [0, 0, 0, 0], [0, 0, 3, 6]]);
+        assert_eq!(
+            b.reflect_horizontal().rows(),
+            [[6, 3, 2, 1], [96, 48, 24, 12], [0, 0, 0, 0], [6, 3, 0, 0]]
+        );
+        assert_eq!(
+            b.reflect_vertical().rows(),
+            [[0, 0, 3, 6], [0, 0, 0, 0], [12, 24, 48, 96], [1, 2, 3, 6]]
+        );
+        assert_eq!(
+            b.rotate_clockwise().rows(),
+            [[0, 0, 12, 1], [0, 0, 24, 2], [3, 0, 48, 3], [6, 0, 96, 6]]
+        );
+        assert_eq!(
+            b.rotate_clockwise()
+                .rotate_clockwise()
+                .rotate_clockwise()
+                .rotate_clockwise(),
+            b
+        );
+        assert_eq!(Direction::Up.rotate_clockwise(), Direction::Right);
+
+        // All 8 symmetries are different, and each has an inverse
+        let images = ALL_SYMMETRIES
+            .iter()
+            .map(|sym| sym.apply(&b))
+            .collect::<Vec<Board>>();
+        for (i, image) in images.iter().enumerate() {
+            assert!(!images[..i].contains(image));
+            assert_eq!(ALL_SYMMETRIES[i].inverse().apply(image), b);
+            // Every image has the same canonical board
+            let (canonical, sym) = image.canonical();
+            assert_eq!(canonical, b.canonical().0);
+            assert_eq!(sym.apply(image), canonical);
+        }
+        for d in &ALL_DIRECTIONS {
+            for sym in &ALL_SYMMETRIES {
+                assert_eq!(sym.inverse().map_direction(sym.map_direction(*d)), *d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shove_commutes_with_symmetries() {
+        let boards = [
+            Board::from_rows(&[[1, 2, 3, 6], [12, 24, 48, 96], [0, 0, 0, 0], [0, 0, 3, 6]]),
+            Board::from_rows(&[[1, 0, 2, 0], [3, 3, 0, 6], [0, 2, 1, 0], [6, 6, 6, 0]]),
+            Board::from_rows(&[[0, 0, 0, 1], [0, 2, 0, 0], [3, 0, 0, 3], [0, 1, 2, 3]]),
+        ];
+        for board in boards.iter() {
+            for sym in &ALL_SYMMETRIES {
+                for d in &ALL_DIRECTIONS {
+                    let mut shoved = *board;
+                    let modified = shoved.shove(*d);
+                    let mut image = sym.apply(board);
+                    assert_eq!(image.shove(sym.map_direction(*d)), modified);
+                    assert_eq!(image, sym.apply(&shoved));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let b = Board::from_rows(&[[1, 2, 3, 6], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 3, 6]]);
+        let serialized = serde_json::to_string(&b).unwrap();
+        assert_eq!(serialized, "{\"blocks\":[1,2,3,6,0,0,0,0,0,0,0,0,0,0,3,6]}");
+        assert_eq!(serde_json::from_str::<Board>(&serialized).unwrap(), b);
+        assert!(serde_json::from_str::<Board>(&serialized.replace("[1,", "[5,")).is_err());
     }
 }
@@ -0,0 +1,383 @@
+use rayon::prelude::*;
+use std::sync::Arc;
+
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::rules;
+use super::spawn;
+
+// How the board is turned into numbers for a model
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Encoding {
+    // The number shown on each block
+    Ranks,
+    // The rank index of each block, which grows with log2 of the rank
+    Log2,
+    // One plane of 16 blocks per rank index, each 1 where the block has that index
+    OneHot,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s {
+            "ranks" => Ok(Encoding::Ranks),
+            "log2" => Ok(Encoding::Log2),
+            "one_hot" => Ok(Encoding::OneHot),
+            _ => Err(format!("unknown encoding {}", s)),
+        }
+    }
+}
+
+impl Encoding {
+    // How many numbers an encoded board has
+    pub fn size(&self) -> usize {
+        let num_blocks = board::WIDTH * board::WIDTH;
+        match self {
+            Encoding::OneHot => num_blocks * board::NUM_RANKS,
+            _ => num_blocks,
+        }
+    }
+
+    pub fn encode(&self, rules: &dyn rules::RuleSet, board: &board::Board) -> Vec<f32> {
+        let indices = board.indices();
+        match self {
+            Encoding::Ranks => indices.iter().map(|i| rules.rank(*i) as f32).collect(),
+            Encoding::Log2 => indices.iter().map(|i| *i as f32).collect(),
+            Encoding::OneHot => {
+                let mut planes = vec![0.0; self.size()];
+                for (block, idx) in indices.iter().enumerate() {
+                    planes[*idx as usize * indices.len() + block] = 1.0;
+                }
+                planes
+            }
+        }
+    }
+}
+
+// What each step is rewarded with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RewardShaping {
+    // The points gained, as when agents are trained by agent_runner
+    ScoreDelta,
+    // The change in ln(1 + score), which keeps late game rewards from swamping early ones
+    LogScore,
+    // 1 for every move which doesn't end the game
+    Survival,
+}
+
+impl std::str::FromStr for RewardShaping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RewardShaping, String> {
+        match s {
+            "score" => Ok(RewardShaping::ScoreDelta),
+            "log_score" => Ok(RewardShaping::LogScore),
+            "survival" => Ok(RewardShaping::Survival),
+            _ => Err(format!("unknown reward shaping {}", s)),
+        }
+    }
+}
+
+impl RewardShaping {
+    fn reward(&self, prev_score: game::Score, score: game::Score, done: bool) -> f64 {
+        match self {
+            RewardShaping::ScoreDelta => (score - prev_score) as f64,
+            RewardShaping::LogScore => (score as f64).ln_1p() - (prev_score as f64).ln_1p(),
+            RewardShaping::Survival => {
+                if done {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EnvConfig {
+    pub rules: Arc<dyn rules::RuleSet>,
+    pub encoding: Encoding,
+    pub reward: RewardShaping,
+}
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            rules: rules::classic(),
+            encoding: Encoding::Log2,
+            reward: RewardShaping::ScoreDelta,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub board: Vec<f32>,
+    // What's shown of the tile the next move will place
+    pub next_hint: spawn::NextHint,
+    // Which moves are allowed, in the order of ALL_DIRECTIONS
+    pub action_mask: [bool; 4],
+}
+
+#[derive(Debug)]
+pub struct StepInfo {
+    // False if the action wasn't allowed, in which case nothing changed
+    pub moved: bool,
+    pub score: game::Score,
+    pub num_moves: i32,
+    // Only present once the game is over
+    pub result: Option<game::GameResult>,
+}
+
+pub struct StepResult {
+    pub observation: Observation,
+    pub reward: f64,
+    pub done: bool,
+    pub info: StepInfo,
+}
+
+// A game driven one step at a time, in the style of a reinforcement learning environment
+pub struct Env {
+    config: EnvConfig,
+    game: game::Game,
+}
+
+impl Env {
+    pub fn new(config: EnvConfig) -> Env {
+        let game = game::Game::with_rules(None, false, config.rules.clone());
+        Env { config, game }
+    }
+
+    // Start a new game
+    pub fn reset(&mut self, seed: u64) -> Observation {
+        self.game = game::Game::with_rules(Some(seed), true, self.config.rules.clone());
+        self.observe()
+    }
+
+    pub fn step(&mut self, action: Direction) -> StepResult {
+        let prev_score = self.game.cur_score();
+        let (moved, result) = match self.game.update(action) {
+            game::MoveResult::Moved(result) => (true, result),
+            game::MoveResult::Failed => (false, None),
+        };
+        let done = self.game.available_moves().is_empty();
+        let score = self.game.cur_score();
+        let reward = if moved {
+            self.config.reward.reward(prev_score, score, done)
+        } else {
+            0.0
+        };
+        StepResult {
+            observation: self.observe(),
+            reward,
+            done,
+            info: StepInfo {
+                moved,
+                score,
+                num_moves: self.game.num_moves(),
+                result,
+            },
+        }
+    }
+
+    pub fn action_mask(&self) -> [bool; 4] {
+        let available = self.game.available_moves();
+        let mut mask = [false; 4];
+        for (allowed, d) in mask.iter_mut().zip(board::ALL_DIRECTIONS.iter()) {
+            *allowed = available.contains(d);
+        }
+        mask
+    }
+
+    pub fn observe(&self) -> Observation {
+        Observation {
+            board: self
+                .config
+                .encoding
+                .encode(self.game.rules(), &self.game.cur_board),
+            next_hint: self.game.next_hint(),
+            action_mask: self.action_mask(),
+        }
+    }
+
+    pub fn game(&self) -> &game::Game {
+        &self.game
+    }
+}
+
+// Many environments stepped together, in parallel
+pub struct VecEnv {
+    envs: Vec<Env>,
+}
+
+impl VecEnv {
+    pub fn new(num_envs: usize, config: EnvConfig) -> VecEnv {
+        VecEnv {
+            envs: (0..num_envs).map(|_| Env::new(config.clone())).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    // Start a new game in every environment, one seed each
+    pub fn reset(&mut self, seeds: &[u64]) -> Vec<Observation> {
+        assert_eq!(seeds.len(), self.envs.len());
+        self.envs
+            .par_iter_mut()
+            .zip(seeds.par_iter())
+            .map(|(env, seed)| env.reset(*seed))
+            .collect()
+    }
+
+    // Start a new game in a single environment, e.g. once its game is over
+    pub fn reset_one(&mut self, i: usize, seed: u64) -> Observation {
+        self.envs[i].reset(seed)
+    }
+
+    // Take one action in every environment
+    pub fn step(&mut self, actions: &[Direction]) -> Vec<StepResult> {
+        assert_eq!(actions.len(), self.envs.len());
+        self.envs
+            .par_iter_mut()
+            .zip(actions.par_iter())
+            .map(|(env, action)| env.step(*action))
+            .collect()
+    }
+
+    pub fn envs(&self) -> &[Env] {
+        &self.envs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn random_action(mask: &[bool; 4], rng: &mut StdRng) -> Direction {
+        let allowed = board::ALL_DIRECTIONS
+            .iter()
+            .zip(mask.iter())
+            .filter(|(_, allowed)| **allowed)
+            .map(|(d, _)| *d)
+            .collect::<Vec<Direction>>();
+        allowed[rng.gen_range(0..allowed.len())]
+    }
+
+    #[test]
+    fn test_play_to_the_end() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut env = Env::new(EnvConfig::default());
+        let mut observation = env.reset(3);
+        assert_eq!(observation.board, vec![0.0; 16]);
+        let mut total_reward = 0.0;
+        let result = loop {
+            let step = env.step(random_action(&observation.action_mask, &mut rng));
+            assert!(step.info.moved);
+            total_reward += step.reward;
+            observation = step.observation;
+            assert_eq!(observation.next_hint, env.game().next_hint());
+            if step.done {
+                assert_eq!(observation.action_mask, [false; 4]);
+                break step.info.result.unwrap();
+            }
+            assert!(step.info.result.is_none());
+        };
+        assert_eq!(total_reward, result.score as f64);
+        assert_eq!(env.game().cur_score(), result.score);
+        assert_eq!(result.log.unwrap().seed, 3);
+    }
+
+    #[test]
+    fn test_disallowed_action() {
+        let mut env = Env::new(EnvConfig::default());
+        env.reset(0);
+        let step = env.step(Direction::Left);
+        let blocked = board::ALL_DIRECTIONS
+            .iter()
+            .zip(step.observation.action_mask.iter())
+            .find(|(_, allowed)| !**allowed)
+            .map(|(d, _)| *d);
+        if let Some(d) = blocked {
+            let board = env.game().cur_board;
+            let step = env.step(d);
+            assert!(!step.info.moved);
+            assert_eq!(step.reward, 0.0);
+            assert_eq!(step.info.num_moves, 1);
+            assert_eq!(env.game().cur_board, board);
+        }
+    }
+
+    #[test]
+    fn test_encodings() {
+        let rules = rules::classic();
+        let board =
+            board::Board::from_rows(&[[1, 2, 3, 6], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 48]]);
+        let ranks = Encoding::Ranks.encode(rules.as_ref(), &board);
+        assert_eq!(ranks[..4], [1.0, 2.0, 3.0, 6.0]);
+        assert_eq!(ranks[15], 48.0);
+        let log2 = Encoding::Log2.encode(rules.as_ref(), &board);
+        assert_eq!(log2[..4], [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(log2[15], 7.0);
+        let one_hot = Encoding::OneHot.encode(rules.as_ref(), &board);
+        assert_eq!(one_hot.len(), Encoding::OneHot.size());
+        assert_eq!(one_hot.iter().sum::<f32>(), 16.0);
+        assert_eq!(one_hot[7 * 16 + 15], 1.0);
+        assert_eq!(one_hot[4], 1.0);
+    }
+
+    #[test]
+    fn test_reward_shaping() {
+        assert_eq!(RewardShaping::ScoreDelta.reward(3, 12, false), 9.0);
+        assert_eq!(RewardShaping::LogScore.reward(0, 0, false), 0.0);
+        assert!((RewardShaping::LogScore.reward(0, 9, false) - 10.0_f64.ln()).abs() < 1e-12);
+        assert_eq!(RewardShaping::Survival.reward(0, 9, false), 1.0);
+        assert_eq!(RewardShaping::Survival.reward(0, 9, true), 0.0);
+    }
+
+    #[test]
+    fn test_vec_env_matches_env() {
+        let num_envs = 4;
+        let seeds = (0..num_envs as u64).collect::<Vec<u64>>();
+        let mut vec_env = VecEnv::new(num_envs, EnvConfig::default());
+        let mut envs = (0..num_envs)
+            .map(|_| Env::new(EnvConfig::default()))
+            .collect::<Vec<Env>>();
+        let mut observations = vec_env.reset(&seeds);
+        for (env, seed) in envs.iter_mut().zip(seeds.iter()) {
+            env.reset(*seed);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let actions = observations
+                .iter()
+                .map(|o| {
+                    if o.action_mask.iter().any(|a| *a) {
+                        random_action(&o.action_mask, &mut rng)
+                    } else {
+                        Direction::Up
+                    }
+                })
+                .collect::<Vec<Direction>>();
+            let steps = vec_env.step(&actions);
+            for ((env, action), step) in envs.iter_mut().zip(actions).zip(steps.iter()) {
+                let expected = env.step(action);
+                assert_eq!(step.observation, expected.observation);
+                assert_eq!(step.reward, expected.reward);
+                assert_eq!(step.done, expected.done);
+            }
+            observations = steps.into_iter().map(|s| s.observation).collect();
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+
+use super::agent_runner;
+use super::agent_runner::Agent;
+use super::board;
+use super::game;
+use super::rules;
+
+// Named suites of seeds. Every agent evaluated on a suite plays exactly the same games, so their
+// results can be compared directly. Bigger suites start with the games of the smaller ones.
+pub const SMALL: &str = "small";
+pub const STANDARD: &str = "standard";
+pub const LARGE: &str = "large";
+pub const ALL_SUITE_NAMES: [&str; 3] = [SMALL, STANDARD, LARGE];
+
+// Suites are drawn from this, with an rng whose output is fixed for good
+const SUITE_SEED: u64 = 0x7468_7265_6573;
+// How many times scores are resampled to find confidence intervals
+const NUM_RESAMPLES: usize = 1000;
+// The percentiles which are reported
+const PERCENTILES: [f64; 7] = [0.0, 10.0, 25.0, 50.0, 75.0, 90.0, 100.0];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeedSuite {
+    pub name: String,
+    pub seeds: Vec<u64>,
+}
+
+impl SeedSuite {
+    pub fn by_name(name: &str) -> Option<SeedSuite> {
+        let num_games = match name {
+            SMALL => 20,
+            STANDARD => 100,
+            LARGE => 1000,
+            _ => return None,
+        };
+        let mut rng = game::RngType::seed_from_u64(SUITE_SEED);
+        Some(SeedSuite {
+            name: name.to_string(),
+            seeds: (0..num_games).map(|_| rng.next_u64()).collect(),
+        })
+    }
+}
+
+// A statistic, with a 95% confidence interval
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Estimate {
+    pub value: f64,
+    pub low: f64,
+    pub high: f64,
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} ({:.1}..{:.1})", self.value, self.low, self.high)
+    }
+}
+
+// How an agent did over a suite
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    pub suite: String,
+    pub rules: String,
+    pub num_games: usize,
+    pub mean_score: Estimate,
+    pub median_score: Estimate,
+    // (percentile, score) pairs, from the lowest score to the highest
+    pub percentiles: Vec<(f64, game::Score)>,
+    // For each tile which was the highest on some final board, the fraction of games which got at
+    // least that far
+    pub max_tile_rates: Vec<(board::Rank, f64)>,
+    pub mean_moves: f64,
+    // In the order of the suite's seeds
+    pub scores: Vec<game::Score>,
+}
+
+impl Evaluation {
+    fn new(suite: &SeedSuite, rules: &dyn rules::RuleSet, results: &[game::GameResult]) -> Self {
+        let scores = results
+            .iter()
+            .map(|r| r.score)
+            .collect::<Vec<game::Score>>();
+        let values = scores.iter().map(|s| *s as f64).collect::<Vec<f64>>();
+        let mut sorted = scores.clone();
+        sorted.sort_unstable();
+
+        let max_tiles = results
+            .iter()
+            .map(|r| *r.final_board.indices().iter().max().unwrap())
+            .collect::<Vec<board::RankIndex>>();
+        let mut reached = max_tiles.clone();
+        reached.sort_unstable();
+        reached.dedup();
+        let max_tile_rates = reached
+            .iter()
+            .map(|idx| {
+                let count = max_tiles.iter().filter(|t| *t >= idx).count();
+                (rules.rank(*idx), count as f64 / results.len() as f64)
+            })
+            .collect();
+
+        Evaluation {
+            suite: suite.name.clone(),
+            rules: rules.name().to_string(),
+            num_games: results.len(),
+            mean_score: bootstrap(&values, mean),
+            median_score: bootstrap(&values, median),
+            percentiles: PERCENTILES
+                .iter()
+                .map(|p| (*p, percentile(&sorted, *p)))
+                .collect(),
+            max_tile_rates,
+            mean_moves: mean(
+                &results
+                    .iter()
+                    .map(|r| r.num_moves as f64)
+                    .collect::<Vec<f64>>(),
+            ),
+            scores,
+        }
+    }
+}
+
+impl fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} games of {} on the {} suite",
+            self.num_games, self.rules, self.suite
+        )?;
+        writeln!(f, "  mean score: {}", self.mean_score)?;
+        writeln!(f, "  median score: {}", self.median_score)?;
+        for (p, score) in self.percentiles.iter() {
+            writeln!(f, "  p{:<3}: {}", p, score)?;
+        }
+        writeln!(f, "  mean moves: {:.1}", self.mean_moves)?;
+        write!(f, "  highest tile reached:")?;
+        for (rank, rate) in self.max_tile_rates.iter() {
+            write!(f, " {}: {:.1}%", rank, 100.0 * rate)?;
+        }
+        Ok(())
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable_by(|l, r| l.partial_cmp(r).unwrap());
+    let mid = sorted.len() / 2;
+    match sorted.len() % 2 {
+        0 => (sorted[mid - 1] + sorted[mid]) / 2.0,
+        _ => sorted[mid],
+    }
+}
+
+// The nearest-rank percentile of some sorted scores
+fn percentile(sorted: &[game::Score], p: f64) -> game::Score {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// `statistic` of `values`, with a percentile bootstrap confidence interval. The resampling is
+// seeded, so the same scores always get the same interval.
+fn bootstrap(values: &[f64], statistic: fn(&[f64]) -> f64) -> Estimate {
+    let mut rng = game::RngType::seed_from_u64(SUITE_SEED);
+    let mut resample = vec![0.0; values.len()];
+    let mut estimates = (0..NUM_RESAMPLES)
+        .map(|_| {
+            for v in resample.iter_mut() {
+                *v = values[rng.gen_range(0..values.len())];
+            }
+            statistic(&resample)
+        })
+        .collect::<Vec<f64>>();
+    estimates.sort_unstable_by(|l, r| l.partial_cmp(r).unwrap());
+    Estimate {
+        value: statistic(values),
+        low: estimates[NUM_RESAMPLES * 25 / 1000],
+        high: estimates[NUM_RESAMPLES * 975 / 1000 - 1],
+    }
+}
+
+// How one agent did against another over the same games
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PairedComparison {
+    pub agent: String,
+    pub opponent: String,
+    // Games where the agent scored more than, less than and the same as its opponent
+    pub wins: usize,
+    pub losses: usize,
+    pub ties: usize,
+    // How much more the agent scored than its opponent per game
+    pub mean_difference: Estimate,
+    // The two-sided p-value of a paired sign-flip permutation test of the mean difference. Small
+    // values mean the difference is unlikely to be down to which games happened to be played.
+    pub p_value: f64,
+}
+
+impl PairedComparison {
+    fn new(
+        agent: &str,
+        opponent: &str,
+        scores: &[game::Score],
+        opponent_scores: &[game::Score],
+    ) -> Self {
+        let differences = scores
+            .iter()
+            .zip(opponent_scores.iter())
+            .map(|(s, o)| *s as f64 - *o as f64)
+            .collect::<Vec<f64>>();
+        PairedComparison {
+            agent: agent.to_string(),
+            opponent: opponent.to_string(),
+            wins: differences.iter().filter(|d| **d > 0.0).count(),
+            losses: differences.iter().filter(|d| **d < 0.0).count(),
+            ties: differences.iter().filter(|d| **d == 0.0).count(),
+            mean_difference: bootstrap(&differences, mean),
+            p_value: sign_flip_test(&differences),
+        }
+    }
+}
+
+// A head to head of several agents which all played the same suite
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub suite: String,
+    pub rules: String,
+    // (agent, how it did), in the order the agents were given
+    pub evaluations: Vec<(String, Evaluation)>,
+    // Every pair of agents, each only once
+    pub pairs: Vec<PairedComparison>,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, evaluation) in self.evaluations.iter() {
+            writeln!(f, "{}: {}", name, evaluation)?;
+        }
+        for pair in self.pairs.iter() {
+            writeln!(
+                f,
+                "{} vs {}: {} wins, {} losses, {} ties, mean difference {}, p = {:.4}",
+                pair.agent,
+                pair.opponent,
+                pair.wins,
+                pair.losses,
+                pair.ties,
+                pair.mean_difference,
+                pair.p_value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Compare agents' evaluations game by game. They must all come from the same suite and rules,
+// so that each game was dealt the same tiles for every agent.
+pub fn compare(evaluations: Vec<(String, Evaluation)>) -> Comparison {
+    let (suite, rules) = match evaluations.first() {
+        Some((_, first)) => (first.suite.clone(), first.rules.clone()),
+        None => panic!("there are no agents to compare"),
+    };
+    for (name, evaluation) in evaluations.iter() {
+        assert!(
+            evaluation.suite == suite && evaluation.rules == rules,
+            "{} was evaluated on different games to the other agents",
+            name
+        );
+    }
+    let mut pairs = Vec::new();
+    for (i, (name, evaluation)) in evaluations.iter().enumerate() {
+        for (opponent, opponent_evaluation) in evaluations[i + 1..].iter() {
+            pairs.push(PairedComparison::new(
+                name,
+                opponent,
+                &evaluation.scores,
+                &opponent_evaluation.scores,
+            ));
+        }
+    }
+    Comparison {
+        suite,
+        rules,
+        evaluations,
+        pairs,
+    }
+}
+
+// Under the null hypothesis that neither agent is better, each paired difference is as likely to
+// have had the opposite sign, so see how often random sign flips give a mean at least as extreme.
+fn sign_flip_test(differences: &[f64]) -> f64 {
+    let observed = mean(differences).abs();
+    let mut rng = game::RngType::seed_from_u64(SUITE_SEED);
+    let as_extreme = (0..NUM_RESAMPLES)
+        .filter(|_| {
+            let flipped = differences
+                .iter()
+                .map(|d| if rng.gen() { *d } else { -*d })
+                .sum::<f64>()
+                / differences.len() as f64;
+            // Allow for rounding, so that ties count as being as extreme
+            flipped.abs() >= observed - 1e-9
+        })
+        .count();
+    (as_extreme + 1) as f64 / (NUM_RESAMPLES + 1) as f64
+}
+
+// Play every game of `suite` with `agent`, one after another
+pub fn evaluate<A: Agent>(
+    agent: &mut A,
+    rules: Arc<dyn rules::RuleSet>,
+    suite: &SeedSuite,
+) -> Result<Evaluation, String> {
+    let results = suite
+        .seeds
+        .iter()
+        .map(|seed| play_own_game(*seed, rules.clone(), agent))
+        .collect::<Result<Vec<game::GameResult>, String>>()?;
+    Ok(Evaluation::new(suite, rules.as_ref(), &results))
+}
+
+// Play the game with this seed, failing if the agent couldn't choose every move itself
+fn play_own_game<A: Agent>(
+    seed: u64,
+    rules: Arc<dyn rules::RuleSet>,
+    agent: &mut A,
+) -> Result<game::GameResult, String> {
+    let result = agent_runner::play_seeded_game(seed, rules, agent, false);
+    match agent.failure() {
+        Some(failure) => Err(format!("the agent failed in game {}: {}", seed, failure)),
+        None => Ok(result),
+    }
+}
+
+// Play the games of `suite` in parallel, each with a new agent. Agents are given an rng seeded by
+// their game, so evaluations of agents which make random choices are repeatable too.
+pub fn evaluate_in_parallel<A, F>(
+    make_agent: F,
+    rules: Arc<dyn rules::RuleSet>,
+    suite: &SeedSuite,
+) -> Result<Evaluation, String>
+where
+    A: Agent,
+    F: Fn(&mut StdRng) -> A + Sync,
+{
+    let results = suite
+        .seeds
+        .par_iter()
+        .map(|seed| {
+            let mut agent = make_agent(&mut StdRng::seed_from_u64(*seed));
+            play_own_game(*seed, rules.clone(), &mut agent)
+        })
+        .collect::<Result<Vec<game::GameResult>, String>>()?;
+    Ok(Evaluation::new(suite, rules.as_ref(), &results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expectimax_agent::{evaluator_by_name, ExpectimaxAgent};
+    use crate::random_agent::RandomAgent;
+
+    #[test]
+    fn test_suites() {
+        let small = SeedSuite::by_name(SMALL).unwrap();
+        let standard = SeedSuite::by_name(STANDARD).unwrap();
+        assert_eq!(small.seeds.len(), 20);
+        assert_eq!(small.seeds[..], standard.seeds[..20]);
+        assert_eq!(SeedSuite::by_name(STANDARD), Some(standard));
+        assert!(SeedSuite::by_name("huge").is_none());
+    }
+
+    #[test]
+    fn test_statistics() {
+        assert_eq!(median(&[3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&[4.0, 1.0, 2.0, 3.0]), 2.5);
+        let sorted = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        assert_eq!(percentile(&sorted, 0.0), 1);
+        assert_eq!(percentile(&sorted, 50.0), 5);
+        assert_eq!(percentile(&sorted, 90.0), 9);
+        assert_eq!(percentile(&sorted, 100.0), 10);
+
+        let values = (0..100).map(|v| v as f64).collect::<Vec<f64>>();
+        let estimate = bootstrap(&values, mean);
+        assert_eq!(estimate.value, 49.5);
+        assert!(estimate.low < 49.5 && estimate.high > 49.5);
+        // The standard error of the mean is about 2.9
+        assert!(estimate.high - estimate.low > 8.0 && estimate.high - estimate.low < 15.0);
+        assert_eq!(bootstrap(&[7.0; 10], median).low, 7.0);
+    }
+
+    #[test]
+    fn test_sign_flip_test() {
+        // Always better by a similar amount is significant
+        let better = (0..50).map(|i| 10.0 + (i % 3) as f64).collect::<Vec<f64>>();
+        assert!(sign_flip_test(&better) < 0.01);
+        // As often worse as better is not
+        let even = (0..50)
+            .map(|i| if i % 2 == 0 { 10.0 } else { -10.0 })
+            .collect::<Vec<f64>>();
+        assert!(sign_flip_test(&even) > 0.5);
+        assert_eq!(sign_flip_test(&[0.0; 10]), 1.0);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules = rules::classic();
+        let suite = SeedSuite::by_name(SMALL).unwrap();
+        let evaluation =
+            evaluate_in_parallel(|rng| RandomAgent::new(Some(rng)), rules.clone(), &suite).unwrap();
+        assert_eq!(evaluation.num_games, 20);
+        assert_eq!(evaluation.scores.len(), 20);
+        let (low, high) = (evaluation.mean_score.low, evaluation.mean_score.high);
+        assert!(low <= evaluation.mean_score.value && evaluation.mean_score.value <= high);
+        assert_eq!(
+            evaluation.percentiles[0].1,
+            *evaluation.scores.iter().min().unwrap()
+        );
+        assert!(evaluation.mean_moves > 0.0);
+        // Every game reaches its own highest tile, and fewer games reach higher ones
+        assert_eq!(evaluation.max_tile_rates[0].1, 1.0);
+        assert!(evaluation
+            .max_tile_rates
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1 >= w[1].1));
+
+        // The same agent on the same suite does exactly the same
+        let again = evaluate_in_parallel(|rng| RandomAgent::new(Some(rng)), rules, &suite).unwrap();
+        assert_eq!(again, evaluation);
+    }
+
+    #[test]
+    fn test_compare() {
+        let rules = rules::classic();
+        let suite = SeedSuite::by_name(SMALL).unwrap();
+        let random =
+            evaluate_in_parallel(|rng| RandomAgent::new(Some(rng)), rules.clone(), &suite).unwrap();
+        let expectimax = evaluate_in_parallel(
+            |_rng| ExpectimaxAgent::new(1, evaluator_by_name("score").unwrap()),
+            rules,
+            &suite,
+        )
+        .unwrap();
+        let comparison = compare(vec![
+            ("random".to_string(), random.clone()),
+            ("expectimax".to_string(), expectimax),
+            ("random again".to_string(), random),
+        ]);
+        assert_eq!(comparison.pairs.len(), 3);
+        let pair = &comparison.pairs[0];
+        assert_eq!(
+            (pair.agent.as_str(), pair.opponent.as_str()),
+            ("random", "expectimax")
+        );
+        assert_eq!(pair.wins + pair.losses + pair.ties, 20);
+        assert!(pair.losses > pair.wins);
+        assert!(pair.mean_difference.value < 0.0);
+        assert!(pair.p_value < 0.05);
+        // An agent is no better than itself
+        let pair = &comparison.pairs[1];
+        assert_eq!(pair.opponent, "random again");
+        assert_eq!(pair.ties, 20);
+        assert_eq!(pair.p_value, 1.0);
+    }
+}
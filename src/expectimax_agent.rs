@@ -0,0 +1,279 @@
+use fnv::FnvHashMap;
+
+use super::agent_runner::{Agent, Transition};
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::introspect::{Explanation, Stats};
+use super::rules::RuleSet;
+use super::spawn::NextHint;
+
+// Scores a board which the search will not look past
+pub type Evaluator = Box<dyn Fn(&dyn RuleSet, &board::Board) -> f64 + Send + Sync>;
+
+// How much an empty block is worth to the heuristic evaluation
+const EMPTY_BLOCK_VALUE: f64 = 10.0;
+// How much a pair of neighboring blocks which could merge is worth
+const MERGEABLE_PAIR_VALUE: f64 = 5.0;
+
+pub fn score_evaluator() -> Evaluator {
+    Box::new(|rules, board| rules.score(board) as f64)
+}
+
+// The score, plus a bonus for open space and for blocks which are ready to be merged
+pub fn heuristic_evaluator() -> Evaluator {
+    Box::new(|rules, board| {
+        let num_empty = board.indices().iter().filter(|idx| **idx == 0).count();
+        let mut num_mergeable = 0;
+        for i in 0..board::WIDTH {
+            for j in 0..board::WIDTH - 1 {
+                let across = (board.get_index(i, j), board.get_index(i, j + 1));
+                let down = (board.get_index(j, i), board.get_index(j + 1, i));
+                for (in1, in2) in &[across, down] {
+                    if rules.combine(*in1, *in2).is_some() {
+                        num_mergeable += 1;
+                    }
+                }
+            }
+        }
+        rules.score(board) as f64
+            + EMPTY_BLOCK_VALUE * num_empty as f64
+            + MERGEABLE_PAIR_VALUE * num_mergeable as f64
+    })
+}
+
+pub fn evaluator_by_name(name: &str) -> Option<Evaluator> {
+    match name {
+        "score" => Some(score_evaluator()),
+        "heuristic" => Some(heuristic_evaluator()),
+        _ => None,
+    }
+}
+
+type CacheKey = (board::Board, NextHint, (i32, [u8; 3]), usize);
+
+type MoveValues = Vec<(Direction, f64)>;
+
+// Looks ahead a fixed number of moves, taking the move with the best expected evaluation.
+// Max nodes choose between directions; chance nodes average over every outcome of the move.
+pub struct ExpectimaxAgent {
+    depth: usize,
+    evaluator: Evaluator,
+    // Values of max nodes, keyed on the board, what's next, what the spawner will draw after and
+    // how much deeper to search. Cleared between moves.
+    cache: FnvHashMap<CacheKey, f64>,
+    cache_hits: u64,
+    nodes_searched: u64,
+    // The board and move number of a game which `explain` searched, with the values it found, so
+    // the move that follows doesn't search again
+    explained: Option<((board::Board, i32), MoveValues)>,
+}
+
+impl ExpectimaxAgent {
+    pub fn new(depth: usize, evaluator: Evaluator) -> ExpectimaxAgent {
+        assert!(depth > 0);
+        ExpectimaxAgent {
+            depth,
+            evaluator,
+            cache: FnvHashMap::default(),
+            cache_hits: 0,
+            nodes_searched: 0,
+            explained: None,
+        }
+    }
+
+    fn max_value(&mut self, game: &game::Game, depth: usize) -> f64 {
+        let key = (
+            game.cur_board,
+            game.next_hint(),
+            game.spawner().chance_state(),
+            depth,
+        );
+        if let Some(value) = self.cache.get(&key) {
+            self.cache_hits += 1;
+            return *value;
+        }
+        self.nodes_searched += 1;
+        let moves = game.available_moves();
+        let value = if moves.is_empty() {
+            // The game is over, so the board is scored as any other the search stops at
+            (self.evaluator)(game.rules(), &game.cur_board)
+        } else {
+            moves
+                .iter()
+                .map(|d| self.chance_value(game, *d, depth))
+                .fold(f64::MIN, f64::max)
+        };
+        self.cache.insert(key, value);
+        value
+    }
+
+    // The expected value of moving in direction `d`
+    fn chance_value(&mut self, game: &game::Game, d: Direction, depth: usize) -> f64 {
+        let rules = game.rules();
+        let mut expected = 0.0;
+        // Outcomes which only differ by the tile after are next to each other
+        let mut last_evaluated: Option<(board::Board, f64)> = None;
+        for outcome in game.outcomes(d) {
+            if depth == 1 {
+                let value = match last_evaluated {
+                    Some((board, value)) if board == outcome.board => value,
+                    _ => (self.evaluator)(rules, &outcome.board),
+                };
+                last_evaluated = Some((outcome.board, value));
+                expected += outcome.probability * value;
+                continue;
+            }
+            let mut next_game = game.clone();
+            next_game.apply_outcome(d, &outcome);
+            expected += outcome.probability * self.max_value(&next_game, depth - 1);
+        }
+        expected
+    }
+
+    // The expected value of each available move
+    fn move_values(&mut self, game: &game::Game) -> MoveValues {
+        self.cache.clear();
+        // Search a copy which doesn't know the order of the deck or keep a log
+        let game = game.fork(0);
+        game.available_moves()
+            .into_iter()
+            .map(|d| (d, self.chance_value(&game, d, self.depth)))
+            .collect()
+    }
+}
+
+impl Agent for ExpectimaxAgent {
+    fn take_action(&mut self, game: &game::Game, _train_mode: bool) -> Direction {
+        let values = match self.explained.take() {
+            Some((explained, values)) if explained == (game.cur_board, game.num_moves()) => values,
+            _ => self.move_values(game),
+        };
+        assert!(!values.is_empty());
+        let mut best = (values[0].0, f64::MIN);
+        for (d, value) in values {
+            if value > best.1 {
+                best = (d, value);
+            }
+        }
+        best.0
+    }
+
+    fn update(&mut self, _transition: &Transition) {
+        // We don't learn
+    }
+
+    fn explain(&mut self, game: &game::Game) -> Option<Explanation> {
+        let (nodes_searched, cache_hits) = (self.nodes_searched, self.cache_hits);
+        let values = self.move_values(game);
+        self.explained = Some(((game.cur_board, game.num_moves()), values.clone()));
+        Some(
+            Explanation::from_values(values)
+                .with_detail(
+                    "nodes_searched",
+                    (self.nodes_searched - nodes_searched) as f64,
+                )
+                .with_detail("cache_hits", (self.cache_hits - cache_hits) as f64),
+        )
+    }
+
+    fn stats(&self) -> Stats {
+        Stats::new("expectimax")
+            .value("depth", self.depth as f64)
+            .value("nodes_searched", self.nodes_searched as f64)
+            .value("cache_hits", self.cache_hits as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::agent_runner;
+    use super::super::random_agent::RandomAgent;
+    use super::super::rules;
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_takes_best_move() {
+        // Only pushing left merges the 3s
+        let mut game = game::Game::new(Some(0), false);
+        game.set_board(board::Board::from_rows(&[
+            [3, 3, 0, 0],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+        ]));
+        let mut agent = ExpectimaxAgent::new(1, score_evaluator());
+        assert_eq!(agent.take_action(&game, false), Direction::Left);
+        // The move it makes is the one it values most
+        let explanation = agent.explain(&game).unwrap();
+        let best = explanation
+            .moves
+            .iter()
+            .max_by(|l, r| l.value.partial_cmp(&r.value).unwrap())
+            .unwrap();
+        assert_eq!(best.direction, Direction::Left);
+        assert_eq!(explanation.moves.len(), game.available_moves().len());
+        assert!(explanation.details.contains_key("nodes_searched"));
+        // and it doesn't search again to make it
+        let nodes_searched = agent.stats().values["nodes_searched"];
+        assert_eq!(agent.take_action(&game, false), Direction::Left);
+        assert_eq!(agent.stats().values["nodes_searched"], nodes_searched);
+    }
+
+    #[test]
+    fn test_beats_random() {
+        let num_games = 5;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut expectimax_total = 0;
+        let mut random_total = 0;
+        for _ in 0..num_games {
+            let seed = rng.next_u64();
+            let mut agent = ExpectimaxAgent::new(1, heuristic_evaluator());
+            let result = agent_runner::play_game_with_rules(
+                Some(&mut StdRng::seed_from_u64(seed)),
+                rules::classic(),
+                &mut agent,
+                false,
+            );
+            expectimax_total += result.score;
+            let mut random = RandomAgent::new(Some(&mut rng));
+            let result = agent_runner::play_game_with_rules(
+                Some(&mut StdRng::seed_from_u64(seed)),
+                rules::classic(),
+                &mut random,
+                false,
+            );
+            random_total += result.score;
+        }
+        assert!(expectimax_total > random_total);
+    }
+
+    #[test]
+    fn test_deeper_search_uses_cache() {
+        let mut game = game::Game::with_rules(Some(1), false, rules::by_name("threes").unwrap());
+        let mut agent = ExpectimaxAgent::new(2, heuristic_evaluator());
+        for _ in 0..5 {
+            let d = agent.take_action(&game, false);
+            assert!(game.available_moves().contains(&d));
+            game.update(d);
+        }
+        assert!(agent.nodes_searched > 0);
+        assert!(agent.cache_hits > 0);
+    }
+
+    #[test]
+    fn test_game_over_uses_evaluator() {
+        // No two neighbors can merge, so there are no moves
+        let mut game = game::Game::new(Some(0), false);
+        game.set_board(board::Board::from_rows(&[
+            [1, 3, 1, 3],
+            [3, 1, 3, 1],
+            [1, 3, 1, 3],
+            [3, 1, 3, 1],
+        ]));
+        assert!(game.available_moves().is_empty());
+        let mut agent = ExpectimaxAgent::new(1, Box::new(|_, _| -1000.0));
+        assert_eq!(agent.max_value(&game, 1), -1000.0);
+    }
+}
@@ -0,0 +1,209 @@
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use super::agent_runner::Agent;
+use super::board::Direction;
+use super::game;
+use super::random_agent::RandomAgent;
+
+pub const ALL_STRATEGY_NAMES: [&str; 3] = ["epsilon_greedy", "boltzmann", "count_bonus"];
+pub const ALL_SCHEDULE_NAMES: [&str; 3] = ["constant", "linear", "exponential"];
+
+// How a learning agent tries moves it doesn't yet think are best. The exploration rate means
+// something different to each.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    // Make a random move with a chance of the rate
+    #[default]
+    EpsilonGreedy,
+    // Pick moves with a softmax over their values, with the rate as the temperature. Values are
+    // in points, so useful temperatures are in the tens.
+    Boltzmann,
+    // Always make the best move, but learn as if reaching a board is worth an extra rate /
+    // sqrt(times the board has been read), so rarely seen boards look better than they are
+    CountBonus,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "epsilon_greedy" => Ok(Strategy::EpsilonGreedy),
+            "boltzmann" => Ok(Strategy::Boltzmann),
+            "count_bonus" => Ok(Strategy::CountBonus),
+            _ => Err(format!("unknown exploration strategy {}", s)),
+        }
+    }
+}
+
+// How the exploration rate changes over a training run, from the rate it was started with in
+// the first generation to `final_rate` in the last
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Schedule {
+    #[default]
+    Constant,
+    Linear {
+        final_rate: f64,
+    },
+    // Both rates must be above 0
+    Exponential {
+        final_rate: f64,
+    },
+}
+
+impl Schedule {
+    pub fn by_name(name: &str, final_rate: Option<f64>) -> Result<Schedule, String> {
+        let final_rate =
+            || final_rate.ok_or_else(|| format!("a {} schedule needs a final rate", name));
+        match name {
+            "constant" => Ok(Schedule::Constant),
+            "linear" => Ok(Schedule::Linear {
+                final_rate: final_rate()?,
+            }),
+            "exponential" => Ok(Schedule::Exponential {
+                final_rate: final_rate()?,
+            }),
+            _ => Err(format!("unknown exploration schedule {}", name)),
+        }
+    }
+
+    pub fn rate(&self, initial_rate: f64, generation: i32, num_generations: i32) -> f64 {
+        let progress = generation as f64 / (num_generations - 1).max(1) as f64;
+        match *self {
+            Schedule::Constant => initial_rate,
+            Schedule::Linear { final_rate } => {
+                initial_rate + (final_rate - initial_rate) * progress
+            }
+            Schedule::Exponential { final_rate } => {
+                initial_rate * (final_rate / initial_rate).powf(progress)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Exploration {
+    pub strategy: Strategy,
+    pub schedule: Schedule,
+    pub initial_rate: f64,
+    // The rate for the current generation
+    pub rate: f64,
+}
+
+impl Exploration {
+    pub fn new(strategy: Strategy, schedule: Schedule, initial_rate: f64) -> Exploration {
+        Exploration {
+            strategy,
+            schedule,
+            initial_rate,
+            rate: initial_rate,
+        }
+    }
+
+    pub fn start_generation(&mut self, generation: i32, num_generations: i32) {
+        self.rate = self
+            .schedule
+            .rate(self.initial_rate, generation, num_generations);
+    }
+
+    // The move to explore with, or None to make the best move. `action_values` gives the values
+    // of the available moves.
+    pub fn explore<F>(
+        &self,
+        rng: &mut StdRng,
+        random_agent: &mut RandomAgent,
+        game: &game::Game,
+        action_values: F,
+    ) -> Option<Direction>
+    where
+        F: FnOnce() -> Vec<(Direction, f64)>,
+    {
+        match self.strategy {
+            Strategy::EpsilonGreedy if rng.gen_bool(self.rate.clamp(0.0, 1.0)) => {
+                Some(random_agent.take_action(game, true))
+            }
+            Strategy::Boltzmann if self.rate > 0.0 => {
+                Some(boltzmann_choice(&action_values(), self.rate, rng))
+            }
+            _ => None,
+        }
+    }
+
+    // What reaching a board which has been read `read_count` times is worth on top of its reward
+    pub fn bonus(&self, read_count: i64) -> f64 {
+        match self.strategy {
+            Strategy::CountBonus => self.rate / (read_count.max(1) as f64).sqrt(),
+            _ => 0.0,
+        }
+    }
+}
+
+fn boltzmann_choice(values: &[(Direction, f64)], temperature: f64, rng: &mut StdRng) -> Direction {
+    // Subtracting the best value keeps the exponentials from overflowing
+    let best = values
+        .iter()
+        .map(|(_, v)| *v)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let weights = values
+        .iter()
+        .map(|(_, v)| ((v - best) / temperature).exp())
+        .collect::<Vec<f64>>();
+    let mut pick = rng.gen::<f64>() * weights.iter().sum::<f64>();
+    for ((d, _), weight) in values.iter().zip(weights.iter()) {
+        if pick < *weight {
+            return *d;
+        }
+        pick -= weight;
+    }
+    values.last().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedules() {
+        assert_eq!(Schedule::Constant.rate(0.1, 5, 10), 0.1);
+        let linear = Schedule::by_name("linear", Some(0.0)).unwrap();
+        assert_eq!(linear.rate(0.2, 0, 5), 0.2);
+        assert!((linear.rate(0.2, 2, 5) - 0.1).abs() < 1e-9);
+        assert_eq!(linear.rate(0.2, 4, 5), 0.0);
+        let exponential = Schedule::by_name("exponential", Some(0.01)).unwrap();
+        assert!((exponential.rate(1.0, 1, 3) - 0.1).abs() < 1e-9);
+        assert!((exponential.rate(1.0, 2, 3) - 0.01).abs() < 1e-9);
+        assert!(Schedule::by_name("linear", None).is_err());
+    }
+
+    #[test]
+    fn test_boltzmann() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let values = [
+            (Direction::Up, 10.0),
+            (Direction::Down, 0.0),
+            (Direction::Left, -10.0),
+        ];
+        let count_up = |temperature: f64, rng: &mut StdRng| {
+            (0..1000)
+                .filter(|_| boltzmann_choice(&values, temperature, rng) == Direction::Up)
+                .count()
+        };
+        // Cold picks the best move, hot picks any
+        assert_eq!(count_up(0.1, &mut rng), 1000);
+        let hot = count_up(1000.0, &mut rng);
+        assert!(hot > 280 && hot < 390);
+    }
+
+    #[test]
+    fn test_count_bonus() {
+        let exploration = Exploration::new(Strategy::CountBonus, Schedule::Constant, 10.0);
+        assert_eq!(exploration.bonus(1), 10.0);
+        assert_eq!(exploration.bonus(100), 1.0);
+        let greedy = Exploration::new(Strategy::EpsilonGreedy, Schedule::Constant, 10.0);
+        assert_eq!(greedy.bonus(1), 0.0);
+    }
+}
@@ -1,15 +1,21 @@
 extern crate termion;
 
-use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
 
 //use rand::prelude::*;
 use crate::rand::Rng;
-use fnv::FnvHashMap;
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoroshiro128StarStar;
 use serde::{Deserialize, Serialize};
 
 use super::board;
+use super::rules;
+use super::rules::RuleSet;
+use super::spawn;
 
 pub type Score = i64;
 
@@ -21,14 +27,60 @@ pub struct GameResult {
     pub num_moves: i32,
     pub final_board: board::Board,
     pub final_render: String,
-    // Only present if game was played with logging on
+    // Only present if game was played with logging on and every tile was drawn from the seed
     pub log: Option<GameLog>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GameLog {
     pub seed: u64,
     pub moves: Vec<board::Direction>,
+    // The name of the rule set the game was played with
+    #[serde(default = "rules::default_name")]
+    pub rules: String,
+}
+
+// Everything needed to carry on a game exactly where it was left, including the random number
+// generator, so a restored game spawns the same tiles the original would have
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GameState {
+    pub seed: u64,
+    // The name of the rule set the game is played with
+    pub rules: String,
+    pub board: board::Board,
+    pub next_rank: board::RankIndex,
+    pub next_hint: spawn::NextHint,
+    pub spawner: spawn::Spawner,
+    pub num_moves: i32,
+    // Only present if the game is logging its moves
+    pub moves: Option<Vec<board::Direction>>,
+    pub rng: RngType,
+}
+
+impl GameState {
+    // Save as JSON
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self).map_err(io::Error::from)
+    }
+
+    pub fn load(path: &Path) -> io::Result<GameState> {
+        let reader = BufReader::new(File::open(path)?);
+        serde_json::from_reader(reader).map_err(io::Error::from)
+    }
+}
+
+// One way a move could turn out, as far as the player can tell
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outcome {
+    // The board after the move, with the new tile placed
+    pub board: board::Board,
+    // The tile which was placed
+    pub placed: board::RankIndex,
+    // The tile which will be placed by the move after
+    pub next_rank: board::RankIndex,
+    pub next_hint: spawn::NextHint,
+    pub probability: f64,
 }
 
 pub enum MoveResult {
@@ -36,18 +88,16 @@ pub enum MoveResult {
     Failed,
 }
 
-struct GameScorer {
-    rank_score_map: FnvHashMap<board::Rank, i64>,
-}
-
+#[derive(Clone)]
 pub struct Game {
     //
     // Game state
     //
     pub cur_board: board::Board,
-    next_rank: board::Rank,
-    // (count of 2s - count of 1s) used to bias the RNG towards balanced 1s and 2s
-    parity: i32,
+    next_rank: board::RankIndex,
+    next_hint: spawn::NextHint,
+    spawner: spawn::Spawner,
+    rules: Arc<dyn rules::RuleSet>,
     //
     // Accelerator Structures
     //
@@ -55,7 +105,6 @@ pub struct Game {
     shifted_boards: crate::EnumMap<board::Direction, Option<board::Board>>,
     // whether the board is empty; could be moved down into Board, but it's more efficient here.
     empty: bool,
-    scorer: GameScorer,
     //
     // Game history
     //
@@ -88,109 +137,188 @@ fn color_rank(rank: board::Rank) -> String {
     }
 }
 
-impl GameScorer {
-    pub fn new() -> GameScorer {
-        let mut rank_score_map = FnvHashMap::default();
-        rank_score_map.insert(0, 0);
-        rank_score_map.insert(1, 0);
-        rank_score_map.insert(2, 0);
-        let mut r: board::Rank = 3;
-        loop {
-            rank_score_map.insert(r, GameScorer::raw_score_tile(r));
-            r = board::combine(r, r).unwrap();
-            if r == board::MAX_RANK {
-                break;
-            }
-        }
-
-        GameScorer { rank_score_map }
-    }
-
-    fn raw_score_tile(rank: board::Rank) -> i64 {
-        let base: f64 = 3.0;
-        let raw: f64 = rank.try_into().unwrap();
-        base.powf(((raw / 3.0).log2() + 1.0).try_into().unwrap()) as i64
-    }
-
-    #[inline]
-    fn score_tile(&self, rank: board::Rank) -> i64 {
-        self.rank_score_map[&rank]
-    }
-
-    pub fn score(&self, board: &board::Board) -> Score {
-        board.values().iter().map(|v| self.score_tile(*v)).sum()
-    }
-}
-
 impl Game {
     pub fn new(seed: Option<u64>, do_logging: bool) -> Game {
+        Game::with_rules(seed, do_logging, rules::classic())
+    }
+
+    pub fn with_rules(seed: Option<u64>, do_logging: bool, rules: Arc<dyn rules::RuleSet>) -> Game {
         let seed = seed.unwrap_or(0);
         let mut rng = RngType::seed_from_u64(seed);
-        let first_rank = Game::rand_rank(&mut rng);
+        let mut spawner = spawn::Spawner::new(rules.spawn_model());
+        let (first_rank, first_hint) = spawner.first(&mut rng);
         let new_board = board::Board::new();
         Game {
             seed,
             cur_board: new_board,
-            shifted_boards: Self::take_all_moves(&new_board),
+            shifted_boards: Self::take_all_moves(rules.as_ref(), &new_board),
             empty: true,
             num_moves: 0,
             moves: if do_logging { Some(Vec::new()) } else { None },
             rng,
-            parity: 0,
             next_rank: first_rank,
-            scorer: GameScorer::new(),
+            next_hint: first_hint,
+            spawner,
+            rules,
         }
     }
 
-    // Score an arbitrary board.
-    // NOTE: This function is somewhat slow. For high performance, use the
-    pub fn score(board: &board::Board) -> Score {
-        GameScorer::new().score(board)
+    // A copy of the game for simulating possible futures. It knows no more than the player
+    // does: where tiles will spawn, the order of the deck and the value of a bonus tile are all
+    // redrawn using `seed`.
+    pub fn fork(&self, seed: u64) -> Game {
+        let mut rng = RngType::seed_from_u64(seed);
+        let mut spawner = self.spawner.clone();
+        spawner.shuffle(&mut rng);
+        let next_rank = match self.next_hint {
+            spawn::NextHint::Tile(idx) => idx,
+            spawn::NextHint::Bonus { min, max } => rng.gen_range(min..=max),
+        };
+        Game {
+            seed,
+            cur_board: self.cur_board,
+            shifted_boards: self.shifted_boards,
+            empty: self.empty,
+            num_moves: self.num_moves,
+            moves: None,
+            rng,
+            next_rank,
+            next_hint: self.next_hint,
+            spawner,
+            rules: self.rules.clone(),
+        }
+    }
+
+    // Replace the board, e.g. to set up a position. Use this rather than assigning to
+    // `cur_board` so the available moves are kept up to date.
+    pub fn set_board(&mut self, board: board::Board) {
+        self.cur_board = board;
+        self.empty = board.is_empty();
+        self.shifted_boards = Self::take_all_moves(self.rules.as_ref(), &self.cur_board);
     }
 
-    fn rand_rank(rng: &mut RngType) -> board::Rank {
-        rng.gen_range(1..=2)
+    // Capture the game so it can be restored later, e.g. to undo moves
+    pub fn snapshot(&self) -> GameState {
+        GameState {
+            seed: self.seed,
+            rules: self.rules.name().to_string(),
+            board: self.cur_board,
+            next_rank: self.next_rank,
+            next_hint: self.next_hint,
+            spawner: self.spawner.clone(),
+            num_moves: self.num_moves,
+            moves: self.moves.clone(),
+            rng: self.rng.clone(),
+        }
     }
 
-    // Returns the indexes of all the sections which are available
-    fn elligible_sections(sections: board::BoardSections, sec_idx: usize) -> Vec<usize> {
-        sections
+    // The game as it was when `state` was captured. Fails if the rules are unknown, or the state
+    // isn't one a game with those rules could be in.
+    pub fn restore(state: &GameState) -> Result<Game, String> {
+        let rules =
+            rules::by_name(&state.rules).ok_or_else(|| format!("unknown rules {}", state.rules))?;
+        if let Some(idx) = state
+            .board
+            .indices()
             .iter()
-            .enumerate()
-            .filter_map(|(col_idx, col)| {
-                if col[sec_idx] == 0 {
-                    Some(col_idx)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<usize>>()
+            .find(|idx| **idx > rules.max_index())
+        {
+            return Err(format!(
+                "the board has a tile {} beyond the rules' largest",
+                idx
+            ));
+        }
+        if state.spawner.model() != rules.spawn_model() {
+            return Err("the spawner doesn't use the rules' spawn model".to_string());
+        }
+        state.spawner.check(state.next_rank, state.next_hint)?;
+        let num_logged = state
+            .moves
+            .as_ref()
+            .map_or(state.num_moves as usize, Vec::len);
+        if state.num_moves < 0 || num_logged != state.num_moves as usize {
+            return Err(format!("impossible number of moves {}", state.num_moves));
+        }
+        Ok(Game {
+            seed: state.seed,
+            cur_board: state.board,
+            shifted_boards: Self::take_all_moves(rules.as_ref(), &state.board),
+            empty: state.board.is_empty(),
+            num_moves: state.num_moves,
+            moves: state.moves.clone(),
+            rng: state.rng.clone(),
+            next_rank: state.next_rank,
+            next_hint: state.next_hint,
+            spawner: state.spawner.clone(),
+            rules,
+        })
+    }
+
+    // Score an arbitrary board with the classic rules
+    pub fn score(board: &board::Board) -> Score {
+        rules::ThreesRules::classic().score(board)
+    }
+
+    pub fn rules(&self) -> &dyn rules::RuleSet {
+        self.rules.as_ref()
+    }
+
+    // What the player can see of the tile which will be placed by the next move
+    pub fn next_hint(&self) -> spawn::NextHint {
+        self.next_hint
+    }
+
+    // The tile the next move will place, when the player is shown exactly which it is. Bonus
+    // tiles are only shown as a range, so which one is coming stays hidden.
+    pub fn next_tile(&self) -> Option<board::RankIndex> {
+        match self.next_hint {
+            spawn::NextHint::Tile(idx) => Some(idx),
+            spawn::NextHint::Bonus { .. } => None,
+        }
+    }
+
+    // The range the next tile is in, when it's a bonus tile, for rules which have them
+    pub fn bonus_hint(&self) -> Option<(board::RankIndex, board::RankIndex)> {
+        match self.next_hint {
+            spawn::NextHint::Tile(_) => None,
+            spawn::NextHint::Bonus { min, max } => Some((min, max)),
+        }
+    }
+
+    // The state of the tile spawner, which a player can work out by counting tiles
+    pub fn spawner(&self) -> &spawn::Spawner {
+        &self.spawner
     }
 
     pub fn render(&self) -> String {
         let mut rows = self
             .cur_board
-            .rows()
-            .iter()
+            .indices()
+            .chunks(board::WIDTH)
             .map(|row| {
                 row.iter()
-                    .map(|c| color_rank(*c))
+                    .map(|idx| color_rank(self.rules.rank(*idx)))
                     .collect::<Vec<String>>()
                     .join("|")
             })
             .collect::<Vec<String>>();
-        rows[0] = format!(
-            "{}{:5}|{}| <- next up",
-            rows[0],
-            "",
-            color_rank(self.next_rank)
-        );
+        let next_up = match self.next_hint {
+            spawn::NextHint::Tile(idx) => color_rank(self.rules.rank(idx)),
+            spawn::NextHint::Bonus { min, max } => {
+                format!("+{}..{}", self.rules.rank(min), self.rules.rank(max))
+            }
+        };
+        rows[0] = format!("{}{:5}|{}| <- next up", rows[0], "", next_up);
         rows.join("\r\n")
     }
 
+    pub fn num_moves(&self) -> i32 {
+        self.num_moves
+    }
+
     // Get the current score of the game
     pub fn cur_score(&self) -> Score {
-        self.scorer.score(&self.cur_board)
+        self.rules.score(&self.cur_board)
     }
 
     fn check_game_over(&self) -> Option<GameResult> {
@@ -209,35 +337,28 @@ impl Game {
             log: self.moves.as_ref().map(|moves| GameLog {
                 seed: self.seed,
                 moves: moves.to_vec(),
+                rules: self.rules.name().to_string(),
             }),
         })
     }
 
-    fn take_next_rank(&mut self) -> board::Rank {
-        let ret = self.next_rank;
-        let range = 100;
-        let multiplier = 3;
-        let threshold = 50 + multiplier * self.parity;
-        self.next_rank = if self.rng.gen_range(0..range) > threshold {
-            2
-        } else {
-            1
-        };
-        if ret == 2 {
-            self.parity += 1;
-        } else if ret == 1 {
-            self.parity -= 1;
-        }
-        ret
+    // Replace the tile which was just placed with the next one
+    fn draw_next_rank(&mut self) {
+        let (next_rank, next_hint) =
+            self.spawner
+                .next(self.next_rank, &self.cur_board, &mut self.rng);
+        self.next_rank = next_rank;
+        self.next_hint = next_hint;
     }
 
     fn take_all_moves(
+        rules: &dyn rules::RuleSet,
         board: &board::Board,
     ) -> crate::EnumMap<board::Direction, Option<board::Board>> {
         let mut next_boards = crate::EnumMap::default();
         for d in &board::ALL_DIRECTIONS {
             let mut new_board = *board;
-            let modified = new_board.shove(*d);
+            let modified = rules.shove(&mut new_board, *d);
             next_boards[*d] = if modified || new_board.is_empty() {
                 Some(new_board)
             } else {
@@ -261,50 +382,111 @@ impl Game {
     }
 
     pub fn update(&mut self, d: board::Direction) -> MoveResult {
-        let modified = self.cur_board.shove(d);
+        let modified = self.rules.shove(&mut self.cur_board, d);
         if !(modified || self.empty) {
             // Either this board was just modified by the shove or it's the first move
             MoveResult::Failed
         } else {
             // We've shifted everything, we can add new elements now
-            let (new_row, new_col) = match d {
-                board::Direction::Down | board::Direction::Up => {
-                    let open_row = if d == board::Direction::Down {
-                        0
-                    } else {
-                        board::WIDTH - 1
-                    };
-                    let elligible_cols = Self::elligible_sections(self.cur_board.cols(), open_row);
-                    if elligible_cols.is_empty() {
-                        panic!("shifted board does not have open row")
-                    }
-                    let selected_idx = self.rng.gen_range(0..elligible_cols.len());
-                    let selected_col = elligible_cols[selected_idx];
-                    (open_row, selected_col)
-                }
-                board::Direction::Left | board::Direction::Right => {
-                    let open_col = if d == board::Direction::Left {
-                        board::WIDTH - 1
-                    } else {
-                        0
-                    };
-                    let elligible_rows = Self::elligible_sections(self.cur_board.rows(), open_col);
-                    if elligible_rows.is_empty() {
-                        panic!("shifted board does not have open col")
-                    }
-                    let selected_idx = self.rng.gen_range(0..elligible_rows.len());
-                    let selected_row = elligible_rows[selected_idx];
-                    (selected_row, open_col)
+            let positions = self.rules.spawn_positions(&self.cur_board, d);
+            if positions.is_empty() {
+                panic!("shifted board does not have an open position")
+            }
+            let (new_row, new_col) = positions[self.rng.gen_range(0..positions.len())];
+            self.cur_board.set_index(new_row, new_col, self.next_rank);
+            self.draw_next_rank();
+            self.finish_move(d)
+        }
+    }
+
+    // Every way moving in direction `d` could turn out, with its probability. Like `fork`, this
+    // only uses what the player knows: a bonus tile could be any value in its range and the deck
+    // could be in any order. Returns nothing if the move isn't allowed.
+    pub fn outcomes(&self, d: board::Direction) -> Vec<Outcome> {
+        let shoved = match self.shifted_boards[d] {
+            Some(shoved) => shoved,
+            None => return Vec::new(),
+        };
+        let positions = self.rules.spawn_positions(&shoved, d);
+        let placed_tiles: Vec<board::RankIndex> = match self.next_hint {
+            spawn::NextHint::Tile(idx) => vec![idx],
+            spawn::NextHint::Bonus { min, max } => (min..=max).collect(),
+        };
+        let p_placement = 1.0 / (positions.len() * placed_tiles.len()) as f64;
+
+        let mut outcomes = Vec::new();
+        for (row, col) in positions {
+            for placed in placed_tiles.iter() {
+                let mut new_board = shoved;
+                new_board.set_index(row, col, *placed);
+                for (next_rank, next_hint, p) in self.spawner.distribution(&new_board) {
+                    outcomes.push(Outcome {
+                        board: new_board,
+                        placed: *placed,
+                        next_rank,
+                        next_hint,
+                        probability: p_placement * p,
+                    });
                 }
-            };
-            let new_val = self.take_next_rank();
-            self.cur_board.set_value(new_row, new_col, new_val);
-            self.num_moves += 1;
-            self.moves.as_mut().map(|moves| moves.push(d));
-            self.empty = false;
-            self.shifted_boards = Self::take_all_moves(&self.cur_board);
-            MoveResult::Moved(self.check_game_over())
+            }
+        }
+        outcomes
+    }
+
+    // Move in direction `d`, with the outcome chosen rather than drawn. Fails unless `outcome` is
+    // one of `outcomes(d)`. The game's seed no longer replays it, so its log is dropped.
+    pub fn apply_outcome(&mut self, d: board::Direction, outcome: &Outcome) -> MoveResult {
+        if !self.is_outcome(d, outcome) {
+            return MoveResult::Failed;
         }
+        self.cur_board = outcome.board;
+        self.spawner
+            .advance(outcome.placed, outcome.next_rank, outcome.next_hint);
+        // The deck may have been refilled in order, so make sure its order stays a secret
+        self.spawner.shuffle(&mut self.rng);
+        self.next_rank = outcome.next_rank;
+        self.next_hint = outcome.next_hint;
+        self.moves = None;
+        self.finish_move(d)
+    }
+
+    // Whether `outcome` is one of `outcomes(d)`, without listing them all
+    fn is_outcome(&self, d: board::Direction, outcome: &Outcome) -> bool {
+        let shoved = match self.shifted_boards[d] {
+            Some(shoved) => shoved,
+            None => return false,
+        };
+        let placeable = match self.next_hint {
+            spawn::NextHint::Tile(idx) => outcome.placed == idx,
+            spawn::NextHint::Bonus { min, max } => (min..=max).contains(&outcome.placed),
+        };
+        let placed_on_spawn =
+            self.rules
+                .spawn_positions(&shoved, d)
+                .into_iter()
+                .any(|(row, col)| {
+                    let mut new_board = shoved;
+                    new_board.set_index(row, col, outcome.placed);
+                    new_board == outcome.board
+                });
+        placeable
+            && placed_on_spawn
+            && self.spawner.distribution(&outcome.board).into_iter().any(
+                |(next_rank, next_hint, _)| {
+                    next_rank == outcome.next_rank && next_hint == outcome.next_hint
+                },
+            )
+    }
+
+    // Bookkeeping once the new tile is on the board and the next one has been chosen
+    fn finish_move(&mut self, d: board::Direction) -> MoveResult {
+        self.num_moves += 1;
+        if let Some(moves) = self.moves.as_mut() {
+            moves.push(d);
+        }
+        self.empty = false;
+        self.shifted_boards = Self::take_all_moves(self.rules.as_ref(), &self.cur_board);
+        MoveResult::Moved(self.check_game_over())
     }
 }
 
@@ -313,6 +495,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::useless_vec, clippy::assertions_on_constants)]
     fn test_full_play() {
         for do_logging in vec![false, true] {
             let mut game = Game::new(None, do_logging);
@@ -357,6 +540,272 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_all_rules_play() {
+        for name in &rules::ALL_NAMES {
+            let rules = rules::by_name(name).unwrap();
+            let mut game = Game::with_rules(Some(3), true, rules);
+            let result = loop {
+                let moves = game.available_moves();
+                assert_ne!(moves.len(), 0);
+                if let MoveResult::Moved(Some(result)) = game.update(moves[moves.len() - 1]) {
+                    break result;
+                }
+            };
+            assert_ne!(result.score, 0);
+            assert_eq!(result.log.unwrap().rules, *name);
+        }
+    }
+
+    #[test]
+    fn test_next_tile() {
+        let mut seen_bonus = false;
+        for seed in 0..20 {
+            let mut game = Game::with_rules(Some(seed), false, rules::by_name("threes").unwrap());
+            // Bonus tiles only come once there's a 48 on the board
+            let mut board = board::Board::new();
+            board.set_index(0, 0, 7);
+            game.set_board(board);
+            loop {
+                match (game.next_tile(), game.bonus_hint()) {
+                    (Some(idx), None) => assert_eq!(idx, game.next_rank),
+                    (None, Some((min, max))) => {
+                        assert!((min..=max).contains(&game.next_rank));
+                        seen_bonus = true;
+                    }
+                    _ => panic!("the next tile is either shown or a bonus"),
+                }
+                let moves = game.available_moves();
+                if let MoveResult::Moved(Some(_)) = game.update(moves[moves.len() - 1]) {
+                    break;
+                }
+            }
+        }
+        assert!(seen_bonus);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        for name in &rules::ALL_NAMES {
+            let mut game = Game::with_rules(Some(4), true, rules::by_name(name).unwrap());
+            let state = game.snapshot();
+            // Even the very first move of a restored game is still allowed
+            let mut restored = Game::restore(&state).unwrap();
+            assert_eq!(restored.available_moves(), game.available_moves());
+            for _ in 0..8 {
+                let d = game.available_moves()[0];
+                game.update(d);
+            }
+
+            let state = game.snapshot();
+            let json = serde_json::to_string(&state).unwrap();
+            let loaded: GameState = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded, state);
+            restored = Game::restore(&loaded).unwrap();
+            assert_eq!(restored.snapshot(), state);
+            assert_eq!(restored.render(), game.render());
+
+            // Both games spawn the same tiles from here on
+            let result = loop {
+                let d = game.available_moves()[0];
+                assert!(matches!(restored.update(d), MoveResult::Moved(_)));
+                if let MoveResult::Moved(Some(result)) = game.update(d) {
+                    break result;
+                }
+                assert_eq!(restored.cur_board, game.cur_board);
+            };
+            assert_eq!(restored.cur_board, result.final_board);
+            assert_eq!(restored.snapshot(), game.snapshot());
+        }
+    }
+
+    #[test]
+    fn test_restore_bad_states() {
+        let mut state = Game::new(Some(1), false).snapshot();
+        state.rules = "checkers".to_string();
+        assert!(Game::restore(&state).is_err());
+
+        // States no game could be in
+        let state = Game::new(Some(1), true).snapshot();
+        let mut bad = state.clone();
+        bad.next_rank = 20;
+        assert!(Game::restore(&bad).is_err());
+        let mut bad = state.clone();
+        bad.next_hint = spawn::NextHint::Tile(3);
+        assert!(Game::restore(&bad).is_err());
+        let mut bad = state.clone();
+        bad.rules = rules::THREES.to_string();
+        assert!(Game::restore(&bad).is_err());
+        let mut bad = state.clone();
+        bad.num_moves = 3;
+        assert!(Game::restore(&bad).is_err());
+        let mut bad = Game::with_rules(Some(1), false, rules::by_name("2048").unwrap()).snapshot();
+        bad.board.set_index(0, 0, 18);
+        assert!(Game::restore(&bad).is_err());
+
+        // Boards with blocks which aren't ranks can't even be loaded
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains("\"blocks\":[0,"));
+        let json = json.replace("\"blocks\":[0,", "\"blocks\":[5,");
+        assert!(serde_json::from_str::<GameState>(&json).is_err());
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut game = Game::with_rules(Some(5), true, rules::by_name("threes").unwrap());
+        for _ in 0..10 {
+            let d = game.available_moves()[0];
+            game.update(d);
+        }
+        let play_out = |mut g: Game| {
+            for _ in 0..10 {
+                let d = g.available_moves()[0];
+                if let MoveResult::Moved(Some(_)) = g.update(d) {
+                    break;
+                }
+            }
+            g.cur_board
+        };
+        let fork = game.fork(1);
+        assert_eq!(fork.cur_board, game.cur_board);
+        assert_eq!(fork.next_hint(), game.next_hint());
+        assert_eq!(fork.cur_score(), game.cur_score());
+        assert_eq!(fork.available_moves(), game.available_moves());
+        // Forks with the same seed are the same game, but different seeds have different futures
+        assert_eq!(play_out(game.fork(1)), play_out(fork));
+        assert!((2..10).any(|seed| play_out(game.fork(seed)) != play_out(game.fork(1))));
+    }
+
+    #[test]
+    fn test_outcomes_match_samples() {
+        let num_samples = 20000;
+        for name in &rules::ALL_NAMES {
+            let mut game = Game::with_rules(Some(2), false, rules::by_name(name).unwrap());
+            for _ in 0..6 {
+                let d = game.available_moves()[0];
+                game.update(d);
+            }
+            for d in game.available_moves() {
+                let outcomes = game.outcomes(d);
+                let total: f64 = outcomes.iter().map(|o| o.probability).sum();
+                assert!((total - 1.0).abs() < 1e-9);
+
+                // Forks draw everything the player doesn't know, so they sample from the outcomes
+                let mut counts = vec![0; outcomes.len()];
+                for seed in 0..num_samples {
+                    let mut fork = game.fork(seed);
+                    fork.update(d);
+                    let i = outcomes
+                        .iter()
+                        .position(|o| {
+                            o.board == fork.cur_board
+                                && o.next_rank == fork.next_rank
+                                && o.next_hint == fork.next_hint
+                        })
+                        .expect("sampled an outcome which wasn't enumerated");
+                    counts[i] += 1;
+                }
+                for (outcome, count) in outcomes.iter().zip(counts) {
+                    let p = outcome.probability;
+                    let stddev = (p * (1.0 - p) / num_samples as f64).sqrt();
+                    let freq = count as f64 / num_samples as f64;
+                    assert!(
+                        (freq - p).abs() < 5.0 * stddev + 1e-3,
+                        "{}: {:?} sampled {} times",
+                        name,
+                        outcome,
+                        count
+                    );
+                }
+            }
+        }
+
+        // Whatever the real game, which knows the order of its deck, goes on to do is one of them
+        for name in &rules::ALL_NAMES {
+            for seed in 0..10 {
+                let mut game = Game::with_rules(Some(seed), false, rules::by_name(name).unwrap());
+                loop {
+                    let moves = game.available_moves();
+                    let d = moves[(seed as usize + game.num_moves() as usize) % moves.len()];
+                    let outcomes = game.outcomes(d);
+                    let move_result = game.update(d);
+                    assert!(
+                        outcomes.iter().any(|o| {
+                            o.board == game.cur_board
+                                && o.next_rank == game.next_rank
+                                && o.next_hint == game.next_hint
+                                && o.probability > 0.0
+                        }),
+                        "{}: game {} moved to an outcome which wasn't enumerated",
+                        name,
+                        seed
+                    );
+                    if let MoveResult::Moved(Some(_)) = move_result {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_outcome() {
+        let mut game = Game::with_rules(Some(4), true, rules::by_name("threes").unwrap());
+        let mut i = 0;
+        let result = loop {
+            let d = game.available_moves()[0];
+            let outcomes = game.outcomes(d);
+            let outcome = outcomes[i % outcomes.len()].clone();
+            i += 1;
+            let move_result = game.apply_outcome(d, &outcome);
+            assert_eq!(game.cur_board, outcome.board);
+            assert_eq!(game.next_hint(), outcome.next_hint);
+            if let MoveResult::Moved(Some(result)) = move_result {
+                break result;
+            }
+        };
+        assert_eq!(result.num_moves as usize, i);
+        // The seed can't replay chosen outcomes, so there's no log
+        assert!(result.log.is_none());
+
+        // Outcomes which the move can't have are refused
+        let mut game = Game::with_rules(Some(4), false, rules::by_name("threes").unwrap());
+        let d = game.available_moves()[0];
+        let outcome = game.outcomes(d)[0].clone();
+        let before = game.cur_board;
+        let bad_outcomes = [
+            Outcome {
+                board: before,
+                ..outcome.clone()
+            },
+            Outcome {
+                placed: outcome.placed + 1,
+                ..outcome.clone()
+            },
+            Outcome {
+                next_rank: board::NUM_RANKS as board::RankIndex - 1,
+                ..outcome.clone()
+            },
+        ];
+        for bad in bad_outcomes.iter() {
+            assert!(matches!(game.apply_outcome(d, bad), MoveResult::Failed));
+            assert_eq!(game.cur_board, before);
+        }
+        let unavailable = board::ALL_DIRECTIONS
+            .iter()
+            .find(|d| !game.available_moves().contains(d));
+        if let Some(d) = unavailable {
+            assert!(matches!(
+                game.apply_outcome(*d, &outcome),
+                MoveResult::Failed
+            ));
+        }
+        assert!(matches!(
+            game.apply_outcome(d, &outcome),
+            MoveResult::Moved(_)
+        ));
+    }
+
     #[test]
     fn test_score() {
         let b_3 =
@@ -372,5 +821,13 @@ mod tests {
         assert_eq!(Game::score(&b_6), 9);
         assert_eq!(Game::score(&b_12), 27);
         assert_eq!(Game::score(&b_33), 6);
+
+        let b_max = board::Board::from_rows(&[
+            [board::MAX_RANK, 0, 0, 0],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+        ]);
+        assert_eq!(Game::score(&b_max), 3_i64.pow(16));
     }
 }
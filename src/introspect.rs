@@ -0,0 +1,208 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::agent_runner::Agent;
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::rules;
+use super::spawn;
+
+// How an agent sees one of the moves it could make
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoveReport {
+    pub direction: Direction,
+    // How good the agent thinks the move is; higher is better
+    pub value: Option<f64>,
+    // How many times a search tried the move
+    pub visits: Option<u64>,
+}
+
+// Why an agent would make a move
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Explanation {
+    pub moves: Vec<MoveReport>,
+    // Numbers about how the agent got its view, such as how many nodes it searched
+    pub details: BTreeMap<String, f64>,
+}
+
+impl Explanation {
+    pub fn from_values(values: Vec<(Direction, f64)>) -> Explanation {
+        Explanation {
+            moves: values
+                .into_iter()
+                .map(|(direction, value)| MoveReport {
+                    direction,
+                    value: Some(value),
+                    visits: None,
+                })
+                .collect(),
+            details: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, name: &str, value: f64) -> Explanation {
+        self.details.insert(name.to_string(), value);
+        self
+    }
+
+    // One line about each move, with `choice` marked
+    pub fn describe(&self, choice: Direction) -> String {
+        let mut parts = self
+            .moves
+            .iter()
+            .map(|report| {
+                let mut part = format!(
+                    "{}{:?}",
+                    if report.direction == choice { "*" } else { "" },
+                    report.direction
+                );
+                if let Some(value) = report.value {
+                    part.push_str(&format!(": {:.1}", value));
+                }
+                if let Some(visits) = report.visits {
+                    part.push_str(&format!(" ({} visits)", visits));
+                }
+                part
+            })
+            .collect::<Vec<String>>();
+        parts.extend(
+            self.details
+                .iter()
+                .map(|(name, value)| format!("{} {}", name, value)),
+        );
+        parts.join("  ")
+    }
+}
+
+// A report of what an agent has done or learned
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Stats {
+    pub agent: String,
+    pub values: BTreeMap<String, f64>,
+    // Anything which isn't a number, such as the last error an external agent had
+    pub notes: BTreeMap<String, String>,
+}
+
+impl Stats {
+    pub fn new(agent: &str) -> Stats {
+        Stats {
+            agent: agent.to_string(),
+            ..Stats::default()
+        }
+    }
+
+    pub fn value<V: Into<f64>>(mut self, name: &str, value: V) -> Stats {
+        self.values.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn note(mut self, name: &str, note: &str) -> Stats {
+        self.notes.insert(name.to_string(), note.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.agent)?;
+        for (name, value) in self.values.iter() {
+            write!(f, "\n  {}: {}", name, value)?;
+        }
+        for (name, note) in self.notes.iter() {
+            write!(f, "\n  {}:\n{}", name, note)?;
+        }
+        Ok(())
+    }
+}
+
+// One move of a game, with why the agent made it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExplainedMove {
+    pub move_index: usize,
+    pub board: board::Board,
+    pub next_hint: spawn::NextHint,
+    pub played: Direction,
+    pub explanation: Option<Explanation>,
+}
+
+// Have `agent` play the game with this seed, explaining each move
+pub fn explain_game<A: Agent + ?Sized>(
+    agent: &mut A,
+    seed: u64,
+    rules: Arc<dyn rules::RuleSet>,
+) -> Vec<ExplainedMove> {
+    let mut game = game::Game::with_rules(Some(seed), false, rules);
+    let mut moves = Vec::new();
+    agent.start_episode(seed, false);
+    loop {
+        let explanation = agent.explain(&game);
+        let played = agent.take_action(&game, false);
+        moves.push(ExplainedMove {
+            move_index: moves.len(),
+            board: game.cur_board,
+            next_hint: game.next_hint(),
+            played,
+            explanation,
+        });
+        match game.update(played) {
+            game::MoveResult::Moved(Some(result)) => {
+                agent.end_episode(&result, false);
+                return moves;
+            }
+            game::MoveResult::Moved(None) => {}
+            game::MoveResult::Failed => panic!("agent chose a move it can't make"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expectimax_agent::{evaluator_by_name, ExpectimaxAgent};
+    use crate::random_agent::RandomAgent;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_describe() {
+        let explanation =
+            Explanation::from_values(vec![(Direction::Up, 1.0), (Direction::Left, 2.26)])
+                .with_detail("nodes", 7.0);
+        assert_eq!(
+            explanation.describe(Direction::Left),
+            "Up: 1.0  *Left: 2.3  nodes 7"
+        );
+    }
+
+    #[test]
+    fn test_explain_game() {
+        let mut agent = ExpectimaxAgent::new(1, evaluator_by_name("score").unwrap());
+        let moves = explain_game(&mut agent, 3, rules::classic());
+        assert!(!moves.is_empty());
+        for explained in moves.iter() {
+            let explanation = explained.explanation.as_ref().unwrap();
+            // Expectimax makes the move it values most
+            let best = explanation
+                .moves
+                .iter()
+                .max_by(|l, r| l.value.partial_cmp(&r.value).unwrap())
+                .unwrap();
+            assert_eq!(
+                best.value,
+                explanation
+                    .moves
+                    .iter()
+                    .find(|m| m.direction == explained.played)
+                    .unwrap()
+                    .value
+            );
+        }
+
+        // Agents which can't explain themselves still play
+        let mut random = RandomAgent::new(Some(&mut StdRng::seed_from_u64(0)));
+        let moves = explain_game(&mut random, 3, rules::classic());
+        assert!(moves.iter().all(|m| m.explanation.is_none()));
+    }
+}
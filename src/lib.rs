@@ -0,0 +1,29 @@
+#[macro_use]
+extern crate enum_map;
+extern crate rand;
+extern crate termion;
+
+pub mod agent_runner;
+pub mod agent_trainer;
+pub mod board;
+pub mod env;
+pub mod eval;
+pub mod expectimax_agent;
+pub mod exploration;
+pub mod game;
+pub mod introspect;
+pub mod mcts_agent;
+pub mod ntuple_agent;
+pub mod protocol;
+pub mod q_agent;
+pub mod random_agent;
+pub mod registry;
+pub mod replay;
+pub mod rules;
+pub mod server;
+pub mod spawn;
+pub mod sweep;
+pub mod utils;
+pub mod watch;
+
+use enum_map::EnumMap;
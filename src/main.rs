@@ -1,42 +1,39 @@
-#[macro_use]
-extern crate enum_map;
 extern crate clap;
 extern crate histogram;
-#[macro_use]
-extern crate itertools;
 extern crate pprof;
 extern crate rand;
 extern crate termion;
 
-mod agent_runner;
-mod agent_trainer;
-mod board;
-mod game;
-mod q_agent;
-mod random_agent;
-mod replay;
-mod utils;
-
 use clap::{App, Arg, SubCommand};
-use enum_map::EnumMap;
 use rand::prelude::*;
 use rayon::prelude::*;
 use std::fs::File;
-use std::io::{stdin, stdout, Write};
-use std::time::Instant;
+use std::io;
+use std::io::{stdin, stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
+use threes::agent_runner::Agent;
+use threes::{
+    agent_runner, agent_trainer, board, eval, exploration, game, introspect, ntuple_agent,
+    protocol, q_agent, registry, replay, rules, server, sweep, utils, watch,
+};
 
-fn play_interactive_game() {
-    let do_logging = true;
-    let mut game = game::Game::new(None, do_logging);
+fn play_interactive_game(game: game::Game, save_file: &Path) {
+    let mut game = game;
+    // Snapshots from before each move, so moves can be undone and then redone
+    let mut undo_stack: Vec<game::GameState> = Vec::new();
+    let mut redo_stack: Vec<game::GameState> = Vec::new();
     let mut stdout = stdout().into_raw_mode().unwrap();
     let stdin = stdin();
     let stdin = stdin.lock();
     write!(
         stdout,
-        "{}{}q to exit. Arrows keys to play.{}\r\n",
+        "{}{}q to exit. Arrows keys to play. u to undo, r to redo, s to save.{}\r\n",
         // Clear the screen.
         termion::clear::All,
         // Goto (1,1).
@@ -67,10 +64,33 @@ fn play_interactive_game() {
             Key::Right => Some(board::Direction::Right),
             Key::Up => Some(board::Direction::Up),
             Key::Down => Some(board::Direction::Down),
+            Key::Char('u') => {
+                if let Some(state) = undo_stack.pop() {
+                    redo_stack.push(game.snapshot());
+                    game = game::Game::restore(&state).unwrap();
+                }
+                None
+            }
+            Key::Char('r') => {
+                if let Some(state) = redo_stack.pop() {
+                    undo_stack.push(game.snapshot());
+                    game = game::Game::restore(&state).unwrap();
+                }
+                None
+            }
+            Key::Char('s') => {
+                match game.snapshot().save(save_file) {
+                    Ok(()) => write!(stdout, "Saved to {}\r\n", save_file.display()),
+                    Err(e) => write!(stdout, "Could not save: {}\r\n", e),
+                }
+                .unwrap();
+                None
+            }
             _ => None,
         };
         let game_over = match direction {
             Some(d) => {
+                let before = game.snapshot();
                 let move_result = game.update(d);
                 match move_result {
                     game::MoveResult::Moved(Some(game_result)) => {
@@ -85,6 +105,8 @@ fn play_interactive_game() {
                     }
                     game::MoveResult::Moved(None) => {
                         // The game is still going
+                        undo_stack.push(before);
+                        redo_stack.clear();
                         false
                     }
                     game::MoveResult::Failed => false,
@@ -101,27 +123,52 @@ fn play_interactive_game() {
     }
 }
 
-fn play_games(num_games: usize, seed: Option<&mut StdRng>) -> Vec<(StdRng, game::GameResult)> {
+// Play games with agents from `factory`, returning the rng each game was seeded from with its result
+fn play_games(
+    num_games: usize,
+    seed: Option<&mut StdRng>,
+    factory: &registry::AgentFactory,
+) -> Vec<(StdRng, game::GameResult)> {
     let mut rng = utils::resolve_rng_from_seed(seed);
     // Play some games
     let game_rngs = (0..num_games)
         .map(|_i| StdRng::from_rng(&mut rng).unwrap())
         .collect::<Vec<StdRng>>();
-    game_rngs
-        .into_par_iter()
-        .map(|seed_rng| {
-            // This must be first; we want to stash the original rng away for later
-            let mut game_rng = seed_rng.clone();
-            let agent = &mut random_agent::RandomAgent::new(Some(&mut game_rng));
-            let result = agent_runner::play_game(Some(&mut game_rng), agent, false);
-            (seed_rng, result)
-        })
-        .collect()
+    let play = |agent: &mut Box<dyn Agent>, seed_rng: StdRng, mut game_rng: StdRng| {
+        let result =
+            agent_runner::play_game_with_rules(Some(&mut game_rng), factory.rules(), agent, false);
+        if let Some(failure) = agent.failure() {
+            exit_with_error::<()>(format!("{} failed: {}", factory.spec(), failure));
+        }
+        (seed_rng, result)
+    };
+    if factory.per_game() {
+        game_rngs
+            .into_par_iter()
+            .map(|seed_rng| {
+                // This must be first; we want to stash the original rng away for later
+                let mut game_rng = seed_rng.clone();
+                let mut agent = factory.build(&mut game_rng).unwrap_or_else(exit_with_error);
+                play(&mut agent, seed_rng, game_rng)
+            })
+            .collect()
+    } else {
+        // Agents loaded from files are only loaded once, and play every game in turn
+        let mut agent = factory.build(&mut rng).unwrap_or_else(exit_with_error);
+        game_rngs
+            .into_iter()
+            .map(|seed_rng| play(&mut agent, seed_rng.clone(), seed_rng))
+            .collect()
+    }
 }
 
-fn play_and_analyze_games(num_games: usize) {
+fn play_and_analyze_games(
+    num_games: usize,
+    seed: Option<&mut StdRng>,
+    factory: &registry::AgentFactory,
+) {
     let start = Instant::now();
-    let results = play_games(num_games, None);
+    let results = play_games(num_games, seed, factory);
     let end = Instant::now();
     let duration = end - start;
     let scores = results
@@ -134,8 +181,9 @@ fn play_and_analyze_games(num_games: usize) {
     }
     let (_best_seed, best_result) = results.into_iter().max_by_key(|r| r.1.score).unwrap();
     println!(
-        "Played {} random games in {}s ({}games/s). Max Score: {}",
+        "Played {} {} games in {}s ({}games/s). Max Score: {}",
         num_games,
+        factory.spec(),
         duration.as_secs_f32(),
         num_games as f32 / duration.as_secs_f32(),
         best_result.score,
@@ -150,22 +198,224 @@ fn play_and_analyze_games(num_games: usize) {
     println!("winning board\n{}", best_board);
 }
 
-fn train_q_agent(
-    num_generations: i32,
-    num_episodes_per_gen: i32,
+// Set by Ctrl-C, asking training to stop once the current generation is done
+static STOP_TRAINING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_TRAINING.store(true, Ordering::SeqCst);
+    // A second Ctrl-C kills the process as usual
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+// Train `agents`, in parallel if there's more than one
+fn train_agent<A, F>(
+    agents: &mut [A],
+    mut progress: agent_trainer::TrainProgress,
     result_file: &str,
-    learning_rate: f64,
-    discount_factor: f64,
-    explore_rate: f64,
-) {
-    let mut agent = q_agent::QAgent::new(None, learning_rate, discount_factor, explore_rate);
-    let train_result =
-        agent_trainer::train_agent_from_scratch(&mut agent, num_generations, num_episodes_per_gen);
+    checkpoint_file: Option<&Path>,
+    checkpoint_every: i32,
+    write_agent: F,
+) where
+    A: Agent + Send,
+    F: Fn(&A, &mut BufWriter<File>) -> io::Result<()>,
+{
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+    let after_generation = |agent: &A, progress: &agent_trainer::TrainProgress| {
+        let stopping = STOP_TRAINING.load(Ordering::SeqCst);
+        if let Some(latest) = progress.outcomes.evaluations.last() {
+            println!(
+                "Generation {}: {}",
+                latest.gen_id, latest.evaluation.mean_score
+            );
+        }
+        if let Some(path) = checkpoint_file {
+            if stopping || progress.is_finished() || progress.generation % checkpoint_every == 0 {
+                agent_trainer::save_checkpoint(path, progress, |w| write_agent(agent, w))
+                    .unwrap_or_else(|e| {
+                        exit_with_error(format!(
+                            "could not save checkpoint to {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    });
+                println!(
+                    "Saved checkpoint after generation {} to {}",
+                    progress.generation,
+                    path.display()
+                );
+            }
+        }
+        if stopping {
+            println!("Stopping early after generation {}", progress.generation);
+        }
+        stopping
+    };
+    if agents.len() == 1 {
+        agent_trainer::continue_training(&mut agents[0], &mut progress, after_generation);
+    } else {
+        agent_trainer::continue_training_in_parallel(agents, &mut progress, after_generation);
+    }
+    let stats = agents[0].stats();
+    println!("{}", stats);
+    progress.outcomes.stats = Some(stats);
 
     let mut file = File::create(result_file).unwrap();
-    let contents = serde_json::to_string(&train_result.outcomes).unwrap();
+    let contents = serde_json::to_string(&progress.outcomes).unwrap();
     file.write_all(contents.as_bytes()).unwrap();
-    println!("Trained agent and saved results to {}", result_file)
+    println!("Trained agent and saved results to {}", result_file);
+}
+
+fn rules_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("rules")
+        .long("rules")
+        .help("which variant of the game to play")
+        .possible_values(&rules::ALL_NAMES)
+        .default_value(rules::CLASSIC)
+}
+
+fn seed_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("seed")
+        .long("seed")
+        .help("the master seed everything random is drawn from; random if not given")
+        .takes_value(true)
+}
+
+// The master seed, chosen at random if none was given so that the run can still be repeated
+fn parse_seed(matches: &clap::ArgMatches) -> u64 {
+    match matches.value_of("seed") {
+        Some(seed) => seed.parse::<u64>().unwrap(),
+        None => rand::thread_rng().next_u64(),
+    }
+}
+
+// Print a report, and save it as JSON if asked to
+fn write_report<R: std::fmt::Display + serde::Serialize>(report: &R, output: Option<&str>) {
+    println!("{}", report);
+    if let Some(path) = output {
+        let mut file = File::create(path).unwrap();
+        let contents = serde_json::to_string(report).unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        println!("Saved report to {}", path);
+    }
+}
+
+fn exit_with_error<T>(message: String) -> T {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+// The options which pick an agent from the registry. With `multiple`, --agent can be given more
+// than once.
+fn agent_args<'a, 'b>(default: Option<&'a str>, multiple: bool) -> Vec<Arg<'a, 'b>> {
+    let mut agent = Arg::with_name("agent")
+        .long("agent")
+        .help("which agent to use; the agents subcommand lists them and their parameters")
+        .possible_values(&registry::ALL_NAMES)
+        .takes_value(true);
+    if let Some(default) = default {
+        agent = agent.default_value(default);
+    }
+    if multiple {
+        agent = agent.multiple(true).number_of_values(1).required(true);
+    }
+    vec![
+        agent,
+        Arg::with_name("agent-param")
+            .long("agent-param")
+            .help("a parameter of the agent, as name=value; it belongs to the --agent before it")
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("agent-model")
+            .long("agent-model")
+            .help("a file to load the agent from, such as a saved q table; it belongs to the --agent before it")
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+// The agents picked by `agent_args`. Each --agent-param and --agent-model belongs to the --agent
+// given before it, or to the only agent if --agent wasn't given.
+fn parse_agent_specs(matches: &clap::ArgMatches) -> Vec<registry::AgentSpec> {
+    let mut specs = match matches.values_of("agent") {
+        Some(names) => names.map(registry::AgentSpec::new).collect::<Vec<_>>(),
+        None => return Vec::new(),
+    };
+    let agent_indices = if matches.occurrences_of("agent") == 0 {
+        vec![0]
+    } else {
+        matches.indices_of("agent").unwrap().collect::<Vec<usize>>()
+    };
+    let owner = |index: usize, arg: &str| {
+        agent_indices
+            .iter()
+            .rposition(|i| *i < index)
+            .unwrap_or_else(|| exit_with_error(format!("--{} must come after an --agent", arg)))
+    };
+    if let (Some(params), Some(indices)) = (
+        matches.values_of("agent-param"),
+        matches.indices_of("agent-param"),
+    ) {
+        for (param, index) in params.zip(indices) {
+            specs[owner(index, "agent-param")]
+                .add_param(param)
+                .unwrap_or_else(exit_with_error);
+        }
+    }
+    if let (Some(models), Some(indices)) = (
+        matches.values_of("agent-model"),
+        matches.indices_of("agent-model"),
+    ) {
+        for (model, index) in models.zip(indices) {
+            specs[owner(index, "agent-model")].model = Some(PathBuf::from(model));
+        }
+    }
+    specs
+}
+
+// The factory for the one agent picked by `agent_args`
+fn agent_factory(
+    matches: &clap::ArgMatches,
+    rules: Arc<dyn rules::RuleSet>,
+) -> registry::AgentFactory {
+    let spec = parse_agent_specs(matches).remove(0);
+    registry::AgentFactory::new(&spec, rules).unwrap_or_else(exit_with_error)
+}
+
+fn print_agents() {
+    for entry in registry::all() {
+        println!("{}: {}", entry.name, entry.about);
+        match entry.model {
+            registry::Model::None => {}
+            registry::Model::Optional(what) => println!("  --agent-model: {} (optional)", what),
+            registry::Model::Required(what) => println!("  --agent-model: {}", what),
+        }
+        for param in entry.params {
+            let default = match (param.default, param.optional) {
+                (Some(default), _) => format!(" [default: {}]", default),
+                (None, true) => " [optional]".to_string(),
+                (None, false) => " [required]".to_string(),
+            };
+            let min = match param.min {
+                Some(min) => format!(" [at least {}]", min),
+                None => String::new(),
+            };
+            println!(
+                "  --agent-param {}=<{}>: {}{}{}",
+                param.name, param.kind, param.help, default, min
+            );
+        }
+    }
+}
+
+fn parse_rules(matches: &clap::ArgMatches) -> Arc<dyn rules::RuleSet> {
+    rules::by_name(matches.value_of("rules").unwrap()).unwrap()
 }
 
 fn main() {
@@ -178,21 +428,221 @@ fn main() {
                 .short("p")
                 .help("generate profiling flamegraph"),
         )
-        .subcommand(SubCommand::with_name("interactive").about("play a game as a human"))
-        .subcommand(SubCommand::with_name("random").about("random agent to play a game"))
+        .subcommand(
+            SubCommand::with_name("interactive")
+                .about("play a game as a human")
+                .arg(rules_arg())
+                .arg(
+                    Arg::with_name("save_file")
+                        .long("save_file")
+                        .help("where pressing s saves the game in progress")
+                        .default_value("game.json"),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .help("carry on a game saved with s, ignoring --rules")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("random")
+                .about("random agent to play a game")
+                .arg(rules_arg())
+                .arg(seed_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("have an agent play many games and report their scores")
+                .arg(rules_arg())
+                .args(&agent_args(Some("expectimax"), false))
+                .arg(
+                    Arg::with_name("num_games")
+                        .long("num_games")
+                        .default_value("100"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stdio")
+                .about("host games for an agent speaking the JSON lines protocol on stdin and stdout")
+                .arg(rules_arg())
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("the seed of the first game; each game after uses the next seed")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("num_games")
+                        .long("num_games")
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("host games for any number of clients over TCP on localhost")
+                .arg(Arg::with_name("port").long("port").default_value("7878"))
+                .arg(
+                    Arg::with_name("log_file")
+                        .long("log_file")
+                        .help("append the log of every finished game to this file")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("idle_minutes")
+                        .long("idle_minutes")
+                        .help("forget games nobody has touched for this many minutes; at least 1")
+                        .default_value("30"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("replay")
-                .about("replay a game from a training log")
+                .about("replay a game from a training log, showing what --agent would have played if given")
                 .arg(
                     Arg::with_name("train_log")
                         .long("train_log")
                         .takes_value(true),
                 )
-                .arg(Arg::with_name("gen_id").long("gen_id").takes_value(true)),
+                .arg(Arg::with_name("gen_id").long("gen_id").takes_value(true))
+                .args(&agent_args(None, false)),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("watch an agent play a game")
+                .arg(rules_arg())
+                .args(&agent_args(Some("expectimax"), false))
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("the seed of the game; random if not given")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("delay_ms")
+                        .long("delay_ms")
+                        .help("how long to wait between moves at first")
+                        .default_value("300"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("play a game with an agent and write why it made each move, as one JSON object per line")
+                .arg(rules_arg())
+                .args(&agent_args(Some("expectimax"), false))
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .help("the seed of the game; random if not given")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("write the moves to this file instead of stdout")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("agents")
+                .about("list the agents which can be picked with --agent, and their parameters"),
+        )
+        .subcommand(
+            SubCommand::with_name("eval")
+                .about("evaluate an agent on a fixed suite of games")
+                .arg(rules_arg())
+                .args(&agent_args(Some("expectimax"), false))
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .help("which games to play; every agent plays the same games of a suite")
+                        .possible_values(&eval::ALL_SUITE_NAMES)
+                        .default_value(eval::STANDARD),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("also save the evaluation to this file as JSON")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("play agents against each other on the same suite of games")
+                .arg(rules_arg())
+                .args(&agent_args(None, true))
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .help("which games every agent plays")
+                        .possible_values(&eval::ALL_SUITE_NAMES)
+                        .default_value(eval::STANDARD),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("also save the comparison to this file as JSON")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sweep")
+                .about("train q agents with many settings at once and rank them")
+                .arg(
+                    Arg::with_name("param")
+                        .long("param")
+                        .help("a setting to sweep over, given once per setting as name=values where values is a list like 0.1,0.5 or a range like 0.1..0.5; settings which aren't swept keep their train defaults")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("search")
+                        .long("search")
+                        .help("try every combination of the listed values, or settings drawn at random")
+                        .possible_values(&["grid", "random"])
+                        .default_value("grid"),
+                )
+                .arg(
+                    Arg::with_name("num_points")
+                        .long("num_points")
+                        .help("how many settings a random search tries")
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::with_name("num_generations")
+                        .long("num_generations")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("num_episodes_per_gen")
+                        .long("num_episodes_per_gen")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("suite")
+                        .long("suite")
+                        .help("which games every trained agent is evaluated on")
+                        .possible_values(&eval::ALL_SUITE_NAMES)
+                        .default_value(eval::SMALL),
+                )
+                .arg(seed_arg())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("where to save the ranked results; as CSV if the file ends in .csv, otherwise as JSON")
+                        .default_value("sweep_results.json"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("train")
-                .about("train a q agent to play")
+                .about("train an agent to play")
+                .arg(
+                    Arg::with_name("agent")
+                        .long("agent")
+                        .help("which kind of agent to train")
+                        .possible_values(&["q", "ntuple"])
+                        .default_value("q"),
+                )
                 .arg(
                     Arg::with_name("num_generations")
                         .long("num_generations")
@@ -221,7 +671,89 @@ fn main() {
                 .arg(
                     Arg::with_name("explore_rate")
                         .long("explore_rate")
+                        .help("how much to explore at first: the chance of a random move, the softmax temperature, or the worth of the bonus for rarely seen boards, depending on --exploration")
                         .default_value("0.1"),
+                )
+                .arg(
+                    Arg::with_name("exploration")
+                        .long("exploration")
+                        .help("how the q agent explores")
+                        .possible_values(&exploration::ALL_STRATEGY_NAMES)
+                        .default_value("epsilon_greedy"),
+                )
+                .arg(
+                    Arg::with_name("explore_schedule")
+                        .long("explore_schedule")
+                        .help("how the explore rate changes from the first generation to the last")
+                        .possible_values(&exploration::ALL_SCHEDULE_NAMES)
+                        .default_value("constant"),
+                )
+                .arg(
+                    Arg::with_name("final_explore_rate")
+                        .long("final_explore_rate")
+                        .help("the explore rate in the last generation of a linear or exponential schedule")
+                        .required_ifs(&[
+                            ("explore_schedule", "linear"),
+                            ("explore_schedule", "exponential"),
+                        ])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("network")
+                        .long("network")
+                        .help("which tuples the ntuple agent looks at")
+                        .possible_values(&ntuple_agent::ALL_NETWORK_NAMES)
+                        .default_value(ntuple_agent::ROWS),
+                )
+                .arg(
+                    Arg::with_name("trace_decay")
+                        .long("trace_decay")
+                        .help("the ntuple agent's TD(lambda) trace decay; 0 is TD(0)")
+                        .default_value("0.5"),
+                )
+                .arg(
+                    Arg::with_name("load_agent")
+                        .long("load_agent")
+                        .help("continue training from a saved q table or ntuple network")
+                        .takes_value(true),
+                )
+                .arg(seed_arg())
+                .arg(
+                    Arg::with_name("num_workers")
+                        .long("num_workers")
+                        .help("how many q agents play training games at once, sharing one q table; runs with more than one can't be reproduced or resumed exactly")
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("checkpoint")
+                        .long("checkpoint")
+                        .help("where to save checkpoints which an interrupted run can be resumed from")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("checkpoint_every")
+                        .long("checkpoint_every")
+                        .help("how many generations to train between checkpoints; at least 1")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .help("carry on the run saved in this checkpoint, with its original settings")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("eval_suite")
+                        .long("eval_suite")
+                        .help("the suite of games the agent is evaluated on after each generation")
+                        .possible_values(&["none", eval::SMALL, eval::STANDARD, eval::LARGE])
+                        .default_value(eval::SMALL),
+                )
+                .arg(
+                    Arg::with_name("save_agent")
+                        .long("save_agent")
+                        .help("where to save the trained agent; q tables in .json files are saved as JSON, anything else in binary")
+                        .takes_value(true),
                 ),
         )
         .get_matches();
@@ -233,10 +765,85 @@ fn main() {
         None
     };
     if matches.is_present("interactive") {
-        play_interactive_game()
+        let interactive_matches = matches.subcommand_matches("interactive").unwrap();
+        let game = match interactive_matches.value_of("resume") {
+            Some(path) => {
+                let state = game::GameState::load(Path::new(path)).unwrap_or_else(|e| {
+                    exit_with_error(format!("could not load game from {}: {}", path, e))
+                });
+                game::Game::restore(&state).unwrap_or_else(|e| {
+                    exit_with_error(format!("could not resume game from {}: {}", path, e))
+                })
+            }
+            None => game::Game::with_rules(None, true, parse_rules(interactive_matches)),
+        };
+        let save_file = interactive_matches.value_of("save_file").unwrap();
+        play_interactive_game(game, Path::new(save_file))
     } else if matches.is_present("random") {
+        let random_matches = matches.subcommand_matches("random").unwrap();
         let num_games = 100_000;
-        play_and_analyze_games(num_games)
+        let seed = parse_seed(random_matches);
+        println!("Seed: {}", seed);
+        let factory = registry::AgentFactory::new(
+            &registry::AgentSpec::new("random"),
+            parse_rules(random_matches),
+        )
+        .unwrap();
+        play_and_analyze_games(num_games, Some(&mut StdRng::seed_from_u64(seed)), &factory)
+    } else if matches.is_present("play") {
+        let play_matches = matches.subcommand_matches("play").unwrap();
+        let factory = agent_factory(play_matches, parse_rules(play_matches));
+        let num_games = play_matches
+            .value_of("num_games")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        play_and_analyze_games(num_games, None, &factory)
+    } else if matches.is_present("stdio") {
+        let stdio_matches = matches.subcommand_matches("stdio").unwrap();
+        let first_seed = stdio_matches
+            .value_of("seed")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let num_games = stdio_matches
+            .value_of("num_games")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        let seeds = (first_seed..first_seed + num_games).collect::<Vec<u64>>();
+        let stdin = stdin();
+        let stdout = stdout();
+        if let Err(e) = protocol::serve_games(
+            stdin.lock(),
+            stdout.lock(),
+            parse_rules(stdio_matches),
+            &seeds,
+        ) {
+            exit_with_error::<()>(format!("stopped hosting games: {}", e));
+        }
+    } else if matches.is_present("serve") {
+        let serve_matches = matches.subcommand_matches("serve").unwrap();
+        let port = serve_matches
+            .value_of("port")
+            .unwrap()
+            .parse::<u16>()
+            .unwrap();
+        let idle_minutes = serve_matches
+            .value_of("idle_minutes")
+            .unwrap()
+            .parse::<u64>()
+            .ok()
+            .filter(|minutes| *minutes >= 1)
+            .unwrap_or_else(|| exit_with_error("--idle_minutes must be at least 1".to_string()));
+        let mut server = server::Server::bind(("127.0.0.1", port))
+            .unwrap()
+            .with_idle_timeout(Duration::from_secs(idle_minutes * 60));
+        if let Some(log_file) = serve_matches.value_of("log_file") {
+            server = server.with_log_file(log_file).unwrap();
+        }
+        println!("Hosting games on {}", server.local_addr().unwrap());
+        server.run();
     } else if matches.is_present("replay") {
         let replay_matches = matches.subcommand_matches("replay").unwrap();
         let train_log = replay_matches.value_of("train_log").unwrap();
@@ -245,7 +852,159 @@ fn main() {
             .unwrap()
             .parse::<i32>()
             .unwrap();
-        replay::replay_game(train_log, gen_id);
+        let log = replay::read_game(train_log, gen_id);
+        let rules = rules::by_name(&log.rules).unwrap_or_else(|| {
+            exit_with_error(format!("game was played with unknown rules {}", log.rules))
+        });
+        // The agent plays by the rules the game was played with
+        let agent = match replay_matches.value_of("agent") {
+            Some(_) => {
+                let factory = agent_factory(replay_matches, rules.clone());
+                Some(
+                    factory
+                        .build(&mut StdRng::seed_from_u64(0))
+                        .unwrap_or_else(exit_with_error),
+                )
+            }
+            None => None,
+        };
+        replay::replay_game(log, rules, agent);
+    } else if matches.is_present("watch") {
+        let watch_matches = matches.subcommand_matches("watch").unwrap();
+        let rules = parse_rules(watch_matches);
+        let factory = agent_factory(watch_matches, rules.clone());
+        let seed = parse_seed(watch_matches);
+        let mut agent = factory
+            .build(&mut StdRng::seed_from_u64(seed))
+            .unwrap_or_else(exit_with_error);
+        let delay = Duration::from_millis(
+            watch_matches
+                .value_of("delay_ms")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap(),
+        );
+        let game = game::Game::with_rules(Some(seed), false, rules);
+        watch::watch_game(game, agent.as_mut(), delay);
+        println!("Seed: {}", seed);
+    } else if matches.is_present("explain") {
+        let explain_matches = matches.subcommand_matches("explain").unwrap();
+        let rules = parse_rules(explain_matches);
+        let factory = agent_factory(explain_matches, rules.clone());
+        let seed = parse_seed(explain_matches);
+        let mut agent = factory
+            .build(&mut StdRng::seed_from_u64(seed))
+            .unwrap_or_else(exit_with_error);
+        let moves = introspect::explain_game(&mut agent, seed, rules);
+        let lines = moves
+            .iter()
+            .map(|m| serde_json::to_string(m).unwrap() + "\n")
+            .collect::<String>();
+        match explain_matches.value_of("output") {
+            Some(path) => {
+                File::create(path)
+                    .and_then(|mut file| file.write_all(lines.as_bytes()))
+                    .unwrap_or_else(|e| {
+                        exit_with_error(format!("could not write {}: {}", path, e))
+                    });
+                println!(
+                    "Explained {} moves of game {} to {}",
+                    moves.len(),
+                    seed,
+                    path
+                );
+            }
+            None => print!("{}", lines),
+        }
+    } else if matches.is_present("agents") {
+        print_agents();
+    } else if matches.is_present("eval") {
+        let eval_matches = matches.subcommand_matches("eval").unwrap();
+        let rules = parse_rules(eval_matches);
+        let suite = eval::SeedSuite::by_name(eval_matches.value_of("suite").unwrap()).unwrap();
+        let output = eval_matches.value_of("output");
+        let evaluation = agent_factory(eval_matches, rules)
+            .evaluate(&suite)
+            .unwrap_or_else(exit_with_error);
+        write_report(&evaluation, output);
+    } else if matches.is_present("compare") {
+        let compare_matches = matches.subcommand_matches("compare").unwrap();
+        let rules = parse_rules(compare_matches);
+        let suite = eval::SeedSuite::by_name(compare_matches.value_of("suite").unwrap()).unwrap();
+        let evaluations = parse_agent_specs(compare_matches)
+            .iter()
+            .map(|spec| {
+                println!("Evaluating {}", spec);
+                let evaluation = registry::AgentFactory::new(spec, rules.clone())
+                    .and_then(|factory| factory.evaluate(&suite))
+                    .unwrap_or_else(exit_with_error);
+                (spec.to_string(), evaluation)
+            })
+            .collect();
+        write_report(
+            &eval::compare(evaluations),
+            compare_matches.value_of("output"),
+        );
+    } else if matches.is_present("sweep") {
+        let sweep_matches = matches.subcommand_matches("sweep").unwrap();
+        let params = sweep_matches
+            .values_of("param")
+            .unwrap()
+            .map(|param| {
+                let (name, spec) = param
+                    .split_once('=')
+                    .ok_or_else(|| format!("{} should look like name=values", param))?;
+                if !sweep::ALL_PARAM_NAMES.contains(&name) {
+                    return Err(format!(
+                        "can't sweep over {}; the settings are {}",
+                        name,
+                        sweep::ALL_PARAM_NAMES.join(", ")
+                    ));
+                }
+                Ok((name.to_string(), spec.parse::<sweep::ParamSpec>()?))
+            })
+            .collect::<Result<Vec<(String, sweep::ParamSpec)>, String>>()
+            .unwrap_or_else(exit_with_error);
+        let seed = parse_seed(sweep_matches);
+        println!("Seed: {}", seed);
+        let points = if sweep_matches.value_of("search").unwrap() == "grid" {
+            sweep::grid(&params).unwrap_or_else(exit_with_error)
+        } else {
+            let num_points = sweep_matches
+                .value_of("num_points")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+            sweep::random_search(&params, num_points, &mut StdRng::seed_from_u64(seed))
+        };
+        let base = agent_trainer::TrainConfig {
+            seed,
+            agent: "q".to_string(),
+            num_generations: sweep_matches
+                .value_of("num_generations")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap(),
+            num_episodes_per_gen: sweep_matches
+                .value_of("num_episodes_per_gen")
+                .unwrap()
+                .parse::<i32>()
+                .unwrap(),
+            ..agent_trainer::TrainConfig::default()
+        };
+        let suite = eval::SeedSuite::by_name(sweep_matches.value_of("suite").unwrap()).unwrap();
+        println!("Training {} agents", points.len());
+        let results = sweep::run(&base, &points, &suite).unwrap_or_else(exit_with_error);
+        print!("{}", results);
+        let output = sweep_matches.value_of("output").unwrap();
+        let mut file = File::create(output).unwrap();
+        if output.ends_with(".csv") {
+            results.write_csv(&mut file).unwrap();
+        } else {
+            let contents = serde_json::to_string(&results).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+        }
+        println!("Saved results to {}", output);
     } else if matches.is_present("train") {
         let train_matches = matches.subcommand_matches("train").unwrap();
         let num_generations = train_matches
@@ -274,14 +1033,232 @@ fn main() {
             .unwrap()
             .parse::<f64>()
             .unwrap();
-        train_q_agent(
-            num_generations,
-            num_episodes_per_gen,
-            result_file,
-            learning_rate,
-            discount_factor,
-            explore_rate,
-        )
+        let load_agent = train_matches.value_of("load_agent");
+        let save_agent = train_matches.value_of("save_agent");
+        let checkpoint_every = train_matches
+            .value_of("checkpoint_every")
+            .unwrap()
+            .parse::<i32>()
+            .ok()
+            .filter(|every| *every >= 1)
+            .unwrap_or_else(|| {
+                exit_with_error("--checkpoint_every must be at least 1".to_string())
+            });
+        // A resumed run carries on with the config it was started with
+        let (progress, mut checkpoint_reader) = match train_matches.value_of("resume") {
+            Some(path) => {
+                let (progress, reader) = agent_trainer::load_checkpoint(Path::new(path))
+                    .unwrap_or_else(|e| {
+                        exit_with_error(format!("could not load checkpoint {}: {}", path, e))
+                    });
+                println!(
+                    "Resuming from generation {} of {}",
+                    progress.generation,
+                    progress.config().num_generations
+                );
+                (progress, Some(reader))
+            }
+            None => {
+                let agent_name = train_matches.value_of("agent").unwrap();
+                let is_ntuple = agent_name == "ntuple";
+                let config = agent_trainer::TrainConfig {
+                    seed: parse_seed(train_matches),
+                    agent: agent_name.to_string(),
+                    num_generations,
+                    num_episodes_per_gen,
+                    learning_rate,
+                    discount_factor,
+                    explore_rate,
+                    trace_decay: if is_ntuple {
+                        Some(
+                            train_matches
+                                .value_of("trace_decay")
+                                .unwrap()
+                                .parse::<f64>()
+                                .unwrap(),
+                        )
+                    } else {
+                        None
+                    },
+                    network: if is_ntuple && load_agent.is_none() {
+                        train_matches.value_of("network").map(|n| n.to_string())
+                    } else {
+                        None
+                    },
+                    load_agent: load_agent.map(|path| path.to_string()),
+                    num_workers: train_matches
+                        .value_of("num_workers")
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap(),
+                    exploration: train_matches
+                        .value_of("exploration")
+                        .unwrap()
+                        .parse::<exploration::Strategy>()
+                        .unwrap(),
+                    explore_schedule: exploration::Schedule::by_name(
+                        train_matches.value_of("explore_schedule").unwrap(),
+                        train_matches
+                            .value_of("final_explore_rate")
+                            .map(|r| r.parse::<f64>().unwrap()),
+                    )
+                    .unwrap(),
+                    eval_suite: match train_matches.value_of("eval_suite").unwrap() {
+                        "none" => None,
+                        suite => Some(suite.to_string()),
+                    },
+                };
+                if is_ntuple && config.num_workers > 1 {
+                    exit_with_error::<()>(
+                        "Only the q agent can be trained with more than one worker".to_string(),
+                    );
+                }
+                // The same limits the registry puts on ntuple agents
+                if is_ntuple && config.explore_rate > 1.0 {
+                    exit_with_error::<()>(format!(
+                        "explore_rate must be at most 1, not {}",
+                        config.explore_rate
+                    ));
+                }
+                if is_ntuple && config.trace_decay.unwrap() >= 1.0 {
+                    exit_with_error::<()>(format!(
+                        "trace_decay must be below 1, not {}",
+                        config.trace_decay.unwrap()
+                    ));
+                }
+                if is_ntuple
+                    && (config.exploration != exploration::Strategy::EpsilonGreedy
+                        || config.explore_schedule != exploration::Schedule::Constant)
+                {
+                    exit_with_error::<()>(
+                        "The ntuple agent only explores with constant epsilon greedy".to_string(),
+                    );
+                }
+                if let exploration::Schedule::Exponential { final_rate } = config.explore_schedule {
+                    if config.explore_rate <= 0.0 || final_rate <= 0.0 {
+                        exit_with_error::<()>(
+                            "An exponential schedule needs explore rates above 0".to_string(),
+                        );
+                    }
+                }
+                (agent_trainer::TrainProgress::new(&config), None)
+            }
+        };
+        let config = progress.config().clone();
+        println!("Seed: {}", config.seed);
+        let checkpoint_file = train_matches
+            .value_of("checkpoint")
+            .or_else(|| train_matches.value_of("resume"))
+            .map(Path::new);
+        if config.num_workers > 1 && checkpoint_file.is_some() {
+            eprintln!(
+                "Warning: training with {} workers isn't reproducible, so a resumed run won't \
+                 match an uninterrupted one",
+                config.num_workers
+            );
+        }
+        if config.agent == "ntuple" {
+            let network = match (checkpoint_reader.as_mut(), &config.load_agent) {
+                (Some(reader), _) => {
+                    ntuple_agent::NTupleNetwork::read(reader).unwrap_or_else(|e| {
+                        exit_with_error(format!("could not load network from checkpoint: {}", e))
+                    })
+                }
+                (None, Some(path)) => ntuple_agent::NTupleNetwork::load(Path::new(path))
+                    .unwrap_or_else(|e| panic!("could not load network from {}: {}", path, e)),
+                (None, None) => {
+                    ntuple_agent::NTupleNetwork::by_name(config.network.as_ref().unwrap()).unwrap()
+                }
+            };
+            let mut agent = ntuple_agent::NTupleAgent::new(
+                None,
+                rules::classic(),
+                network,
+                config.learning_rate,
+                config.trace_decay.unwrap(),
+                config.explore_rate,
+            );
+            train_agent(
+                std::slice::from_mut(&mut agent),
+                progress,
+                result_file,
+                checkpoint_file,
+                checkpoint_every,
+                |agent, w| agent.network().write(w),
+            );
+            if let Some(path) = save_agent {
+                agent
+                    .network()
+                    .save(Path::new(path))
+                    .unwrap_or_else(|e| panic!("could not save network to {}: {}", path, e));
+                println!("Saved network to {}", path);
+            }
+        } else {
+            let q_table = match (checkpoint_reader.as_mut(), &config.load_agent) {
+                (Some(reader), _) => q_agent::QTable::read_binary(reader).unwrap_or_else(|e| {
+                    exit_with_error(format!("could not load q table from checkpoint: {}", e))
+                }),
+                (None, Some(path)) => {
+                    let q_table = q_agent::QTable::load(Path::new(path))
+                        .unwrap_or_else(|e| panic!("could not load q table from {}: {}", path, e));
+                    println!("Loaded {} q table entries from {}", q_table.len(), path);
+                    q_table
+                }
+                (None, None) => q_agent::QTable::new(),
+            };
+            let q_table = if config.num_workers > 1 {
+                // Plenty of shards for the workers to rarely meet
+                let shared = Arc::new(q_agent::SharedQTable::from_table(
+                    q_table,
+                    16 * config.num_workers,
+                ));
+                let mut agents = (0..config.num_workers)
+                    .map(|_| {
+                        q_agent::SharedQAgent::with_table(
+                            None,
+                            shared.clone(),
+                            config.learning_rate,
+                            config.discount_factor,
+                            config.explore_rate,
+                        )
+                        .with_exploration(config.exploration, config.explore_schedule)
+                    })
+                    .collect::<Vec<q_agent::SharedQAgent>>();
+                train_agent(
+                    &mut agents,
+                    progress,
+                    result_file,
+                    checkpoint_file,
+                    checkpoint_every,
+                    |agent, w| agent.q_table().to_table().write_binary(w),
+                );
+                shared.to_table()
+            } else {
+                let mut agent = q_agent::QAgent::new(
+                    None,
+                    config.learning_rate,
+                    config.discount_factor,
+                    config.explore_rate,
+                )
+                .with_exploration(config.exploration, config.explore_schedule);
+                agent.set_q_table(q_table);
+                train_agent(
+                    std::slice::from_mut(&mut agent),
+                    progress,
+                    result_file,
+                    checkpoint_file,
+                    checkpoint_every,
+                    |agent, w| agent.q_table().write_binary(w),
+                );
+                agent.into_q_table()
+            };
+            if let Some(path) = save_agent {
+                q_table
+                    .save(Path::new(path))
+                    .unwrap_or_else(|e| panic!("could not save q table to {}: {}", path, e));
+                println!("Saved {} q table entries to {}", q_table.len(), path);
+            }
+        }
     }
     if let Some(g) = guard {
         if let Ok(report) = g.report().build() {
@@ -0,0 +1,327 @@
+use rand::prelude::*;
+use std::time::{Duration, Instant};
+
+use super::agent_runner::{Agent, Transition};
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::introspect::{Explanation, MoveReport, Stats};
+use super::random_agent::RandomAgent;
+use super::utils;
+
+// How strongly UCT favors less visited moves over ones which have done well
+const EXPLORATION_CONSTANT: f64 = std::f64::consts::SQRT_2;
+
+// When to stop searching. Whichever limit is reached first ends the search.
+#[derive(Clone, Copy, Debug)]
+pub struct MctsBudget {
+    pub iterations: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+impl MctsBudget {
+    pub fn iterations(iterations: usize) -> MctsBudget {
+        MctsBudget {
+            iterations: Some(iterations),
+            time: None,
+        }
+    }
+
+    pub fn time(time: Duration) -> MctsBudget {
+        MctsBudget {
+            iterations: None,
+            time: Some(time),
+        }
+    }
+
+    fn exhausted(&self, iterations: usize, start: Instant) -> bool {
+        self.iterations.is_some_and(|max| iterations >= max)
+            || self.time.is_some_and(|max| start.elapsed() >= max)
+    }
+}
+
+// A node in an open loop tree: it stands for a sequence of moves rather than a board, since the
+// same moves can lead to many different boards depending on where tiles spawn.
+struct Node {
+    visits: u64,
+    total_value: f64,
+    children: crate::EnumMap<Direction, Option<usize>>,
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            visits: 0,
+            total_value: 0.0,
+            children: crate::EnumMap::default(),
+        }
+    }
+}
+
+// Monte Carlo tree search using UCT. Each iteration plays a forked copy of the game with newly
+// drawn spawns, walks the tree by move sequence, expands one move and then plays out the rest of
+// the game with the rollout policy.
+pub struct MctsAgent<P: Agent = RandomAgent> {
+    rng: StdRng,
+    budget: MctsBudget,
+    rollout_policy: P,
+    nodes: Vec<Node>,
+    // Final scores are normalized by the best one seen so they are comparable to UCT's bonus
+    max_value: f64,
+    total_iterations: u64,
+    // The board and move number of a game which `explain` built the tree for, so the move that
+    // follows is picked from the tree that was explained
+    explained: Option<(board::Board, i32)>,
+}
+
+impl MctsAgent<RandomAgent> {
+    pub fn new(seed: Option<&mut StdRng>, budget: MctsBudget) -> MctsAgent<RandomAgent> {
+        let mut rng = utils::resolve_rng_from_seed(seed);
+        let rollout_policy = RandomAgent::new(Some(&mut rng));
+        MctsAgent::with_rollout_policy(rng, budget, rollout_policy)
+    }
+}
+
+impl<P: Agent> MctsAgent<P> {
+    pub fn with_rollout_policy(rng: StdRng, budget: MctsBudget, rollout_policy: P) -> MctsAgent<P> {
+        assert!(budget.iterations.is_some() || budget.time.is_some());
+        MctsAgent {
+            rng,
+            budget,
+            rollout_policy,
+            nodes: Vec::new(),
+            max_value: 1.0,
+            total_iterations: 0,
+            explained: None,
+        }
+    }
+
+    fn select(&self, node: &Node, moves: &[Direction]) -> Direction {
+        let ln_visits = (node.visits.max(1) as f64).ln();
+        let uct = |d: &Direction| {
+            let child = &self.nodes[node.children[*d].unwrap()];
+            child.total_value / (child.visits as f64 * self.max_value)
+                + EXPLORATION_CONSTANT * (ln_visits / child.visits as f64).sqrt()
+        };
+        *moves
+            .iter()
+            .max_by(|l, r| uct(l).partial_cmp(&uct(r)).unwrap())
+            .unwrap()
+    }
+
+    // Build a new tree for `game`, returning how many iterations it took
+    fn search(&mut self, game: &game::Game) -> usize {
+        self.nodes.clear();
+        self.nodes.push(Node::new());
+        let start = Instant::now();
+        let mut iterations = 0;
+        while !self.budget.exhausted(iterations, start) {
+            self.iterate(game);
+            iterations += 1;
+        }
+        iterations
+    }
+
+    fn iterate(&mut self, game: &game::Game) {
+        let seed = self.rng.next_u64();
+        let mut sim = game.fork(seed);
+        let mut path = vec![0];
+        let mut game_over = false;
+
+        // Walk down the tree until we find a move which hasn't been tried yet
+        loop {
+            let node_idx = path[path.len() - 1];
+            let moves = sim.available_moves();
+            let unexpanded = moves
+                .iter()
+                .filter(|d| self.nodes[node_idx].children[**d].is_none())
+                .copied()
+                .collect::<Vec<Direction>>();
+            let d = if unexpanded.is_empty() {
+                self.select(&self.nodes[node_idx], &moves)
+            } else {
+                let d = unexpanded[self.rng.gen_range(0..unexpanded.len())];
+                self.nodes.push(Node::new());
+                self.nodes[node_idx].children[d] = Some(self.nodes.len() - 1);
+                d
+            };
+            path.push(self.nodes[node_idx].children[d].unwrap());
+            if let game::MoveResult::Moved(Some(_)) = sim.update(d) {
+                game_over = true;
+                break;
+            }
+            if !unexpanded.is_empty() {
+                break;
+            }
+        }
+
+        // Play out the rest of the game
+        while !game_over {
+            let d = self.rollout_policy.take_action(&sim, false);
+            game_over = matches!(sim.update(d), game::MoveResult::Moved(Some(_)));
+        }
+
+        let value = sim.cur_score() as f64;
+        self.max_value = self.max_value.max(value);
+        for node_idx in path {
+            self.nodes[node_idx].visits += 1;
+            self.nodes[node_idx].total_value += value;
+        }
+        self.total_iterations += 1;
+    }
+}
+
+impl<P: Agent> Agent for MctsAgent<P> {
+    fn take_action(&mut self, game: &game::Game, _train_mode: bool) -> Direction {
+        let moves = game.available_moves();
+        assert!(!moves.is_empty());
+        let explained = self.explained.take() == Some((game.cur_board, game.num_moves()));
+        if moves.len() == 1 {
+            return moves[0];
+        }
+        if !explained {
+            self.search(game);
+        }
+
+        // Take the move we've been most confident in
+        let root = &self.nodes[0];
+        *moves
+            .iter()
+            .max_by_key(|d| root.children[**d].map_or(0, |c| self.nodes[c].visits))
+            .unwrap()
+    }
+
+    fn update(&mut self, _transition: &Transition) {
+        // We don't learn
+    }
+
+    fn explain(&mut self, game: &game::Game) -> Option<Explanation> {
+        let iterations = self.search(game);
+        self.explained = Some((game.cur_board, game.num_moves()));
+        let root = &self.nodes[0];
+        let moves = game
+            .available_moves()
+            .into_iter()
+            .map(|d| {
+                let child = root.children[d].map(|c| &self.nodes[c]);
+                let visits = child.map_or(0, |c| c.visits);
+                MoveReport {
+                    direction: d,
+                    // The mean final score of the playouts which started with this move
+                    value: child
+                        .filter(|c| c.visits > 0)
+                        .map(|c| c.total_value / c.visits as f64),
+                    visits: Some(visits),
+                }
+            })
+            .collect();
+        Some(
+            Explanation {
+                moves,
+                ..Explanation::default()
+            }
+            .with_detail("iterations", iterations as f64)
+            .with_detail("nodes", self.nodes.len() as f64),
+        )
+    }
+
+    fn stats(&self) -> Stats {
+        Stats::new("mcts")
+            .value("iterations", self.total_iterations as f64)
+            .value("nodes_in_last_tree", self.nodes.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::agent_runner;
+    use super::*;
+
+    #[test]
+    fn test_beats_random() {
+        let num_games = 5;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut mcts_total = 0;
+        let mut random_total = 0;
+        for _ in 0..num_games {
+            let seed = rng.next_u64();
+            let mut agent = MctsAgent::new(Some(&mut rng), MctsBudget::iterations(20));
+            mcts_total +=
+                agent_runner::play_game(Some(&mut StdRng::seed_from_u64(seed)), &mut agent, false)
+                    .score;
+            let mut random = RandomAgent::new(Some(&mut rng));
+            random_total +=
+                agent_runner::play_game(Some(&mut StdRng::seed_from_u64(seed)), &mut random, false)
+                    .score;
+        }
+        assert!(mcts_total > random_total);
+    }
+
+    #[test]
+    fn test_budgets() {
+        let mut game = game::Game::new(Some(0), false);
+        game.update(Direction::Left);
+
+        let mut agent = MctsAgent::new(None, MctsBudget::iterations(50));
+        agent.take_action(&game, false);
+        assert_eq!(agent.total_iterations, 50);
+        assert_eq!(agent.nodes[0].visits, 50);
+
+        let budget = Duration::from_millis(20);
+        let mut agent = MctsAgent::new(None, MctsBudget::time(budget));
+        let start = Instant::now();
+        agent.take_action(&game, false);
+        assert!(start.elapsed() >= budget);
+        assert!(agent.total_iterations > 0);
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut game = game::Game::new(Some(0), false);
+        game.update(Direction::Left);
+        let mut agent = MctsAgent::new(
+            Some(&mut StdRng::seed_from_u64(0)),
+            MctsBudget::iterations(50),
+        );
+        let explanation = agent.explain(&game).unwrap();
+        // Every iteration starts with one of the moves
+        let visits = explanation
+            .moves
+            .iter()
+            .map(|m| m.visits.unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(visits.iter().sum::<u64>(), 50);
+        assert_eq!(explanation.details["iterations"], 50.0);
+        // The move it makes is the one it explained as most visited
+        let most_visited = explanation
+            .moves
+            .iter()
+            .max_by_key(|m| m.visits)
+            .unwrap()
+            .direction;
+        assert_eq!(agent.take_action(&game, false), most_visited);
+        assert_eq!(agent.total_iterations, 50);
+    }
+
+    #[test]
+    fn test_rollout_policy() {
+        // A policy which always takes the first available move
+        struct FirstMove;
+        impl Agent for FirstMove {
+            fn take_action(&mut self, game: &game::Game, _train_mode: bool) -> Direction {
+                game.available_moves()[0]
+            }
+            fn update(&mut self, _: &Transition) {}
+        }
+        // It has nothing to report beyond being an agent
+        assert_eq!(FirstMove.stats().agent, "agent");
+
+        let mut agent = MctsAgent::with_rollout_policy(
+            StdRng::seed_from_u64(0),
+            MctsBudget::iterations(10),
+            FirstMove,
+        );
+        let result = agent_runner::play_game(None, &mut agent, false);
+        assert_ne!(result.score, 0);
+    }
+}
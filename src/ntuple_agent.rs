@@ -0,0 +1,436 @@
+use rand::prelude::*;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::agent_runner::{Agent, Transition};
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::introspect::{Explanation, Stats};
+use super::random_agent::RandomAgent;
+use super::rules::RuleSet;
+use super::utils;
+
+// Saved networks are tagged with this version, which must change whenever the meaning of a saved
+// network does
+const NETWORK_VERSION: u32 = 1;
+// The start of every saved network
+const NETWORK_MAGIC: &[u8; 4] = b"THNT";
+// How many ranks a tuple tells apart. Tiles of 12288 and up are rare enough to share weights,
+// which keeps the rectangles to 16M weights each.
+const NUM_FEATURE_RANKS: usize = 16;
+// The most blocks a tuple can look at, as in the largest built in networks. Each block more needs
+// NUM_FEATURE_RANKS times as many weights.
+const MAX_TUPLE_LEN: usize = 6;
+// TD(λ) updates stop following an afterstate once its trace has decayed below this
+const MIN_TRACE: f64 = 0.01;
+
+// The blocks a tuple looks at
+pub type Tuple = Vec<(usize, usize)>;
+
+pub const ROWS: &str = "rows";
+pub const RECTANGLES: &str = "rectangles";
+pub const ALL_NETWORK_NAMES: [&str; 2] = [ROWS, RECTANGLES];
+
+// Lookup tables holding a weight for every combination of rank indices in each tuple. A board's
+// value is the sum of the weights of every tuple over all 8 symmetries of the board, so each
+// tuple also stands for its rotations and reflections.
+pub struct NTupleNetwork {
+    tuples: Vec<Tuple>,
+    weights: Vec<Vec<f32>>,
+}
+
+impl NTupleNetwork {
+    pub fn new(tuples: Vec<Tuple>) -> NTupleNetwork {
+        let weights = tuples
+            .iter()
+            .map(|tuple| vec![0.0; NUM_FEATURE_RANKS.pow(tuple.len() as u32)])
+            .collect();
+        NTupleNetwork { tuples, weights }
+    }
+
+    // An outer and an inner row, which with symmetry covers every row and column
+    pub fn rows() -> NTupleNetwork {
+        NTupleNetwork::new(vec![
+            (0..board::WIDTH).map(|c| (0, c)).collect(),
+            (0..board::WIDTH).map(|c| (1, c)).collect(),
+        ])
+    }
+
+    // The outer and inner rows plus 2x3 rectangles along the edge and through the middle.
+    // The rectangles have 16M weights each, so this is much slower to train.
+    pub fn rectangles() -> NTupleNetwork {
+        let mut tuples = NTupleNetwork::rows().tuples;
+        for top in 0..2 {
+            tuples.push(
+                (top..top + 2)
+                    .flat_map(|r| (0..3).map(move |c| (r, c)))
+                    .collect(),
+            );
+        }
+        NTupleNetwork::new(tuples)
+    }
+
+    pub fn by_name(name: &str) -> Option<NTupleNetwork> {
+        match name {
+            ROWS => Some(NTupleNetwork::rows()),
+            RECTANGLES => Some(NTupleNetwork::rectangles()),
+            _ => None,
+        }
+    }
+
+    pub fn num_features(&self) -> usize {
+        self.tuples.len() * board::ALL_SYMMETRIES.len()
+    }
+
+    // Which weight of which tuple each feature of the board uses
+    pub fn features(&self, board: &board::Board) -> Vec<(usize, usize)> {
+        let mut features = Vec::with_capacity(self.num_features());
+        for sym in &board::ALL_SYMMETRIES {
+            let image = sym.apply(board);
+            for (t, tuple) in self.tuples.iter().enumerate() {
+                let index = tuple.iter().fold(0, |index, (row, col)| {
+                    let idx = image.get_index(*row, *col) as usize;
+                    index * NUM_FEATURE_RANKS + idx.min(NUM_FEATURE_RANKS - 1)
+                });
+                features.push((t, index));
+            }
+        }
+        features
+    }
+
+    pub fn value(&self, board: &board::Board) -> f64 {
+        self.features(board)
+            .iter()
+            .map(|(t, index)| self.weights[*t][*index] as f64)
+            .sum()
+    }
+
+    fn adjust(&mut self, features: &[(usize, usize)], delta: f64) {
+        for (t, index) in features {
+            self.weights[*t][*index] += delta as f32;
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<NTupleNetwork> {
+        NTupleNetwork::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    // The magic bytes, the version and the number of tuples, followed by each tuple's length,
+    // its blocks as (row, col) byte pairs and its weights. All little endian.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(NETWORK_MAGIC)?;
+        writer.write_all(&NETWORK_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.tuples.len() as u32).to_le_bytes())?;
+        for (tuple, weights) in self.tuples.iter().zip(self.weights.iter()) {
+            writer.write_all(&(tuple.len() as u32).to_le_bytes())?;
+            for (row, col) in tuple {
+                writer.write_all(&[*row as u8, *col as u8])?;
+            }
+            for weight in weights {
+                writer.write_all(&weight.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<NTupleNetwork> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut word = [0; 4];
+        reader.read_exact(&mut word)?;
+        if &word != NETWORK_MAGIC {
+            return Err(invalid("not an n-tuple network".to_string()));
+        }
+        reader.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version != NETWORK_VERSION {
+            return Err(invalid(format!(
+                "network is version {} but only version {} can be read",
+                version, NETWORK_VERSION
+            )));
+        }
+
+        reader.read_exact(&mut word)?;
+        let num_tuples = u32::from_le_bytes(word);
+        let mut tuples = Vec::new();
+        let mut weights = Vec::new();
+        for _ in 0..num_tuples {
+            reader.read_exact(&mut word)?;
+            let len = u32::from_le_bytes(word) as usize;
+            if len > MAX_TUPLE_LEN {
+                return Err(invalid(format!(
+                    "a tuple of {} blocks is longer than the {} allowed",
+                    len, MAX_TUPLE_LEN
+                )));
+            }
+            let mut tuple = Vec::new();
+            for _ in 0..len {
+                let mut block = [0; 2];
+                reader.read_exact(&mut block)?;
+                let (row, col) = (block[0] as usize, block[1] as usize);
+                if row >= board::WIDTH || col >= board::WIDTH {
+                    return Err(invalid(format!("block {},{} is off the board", row, col)));
+                }
+                tuple.push((row, col));
+            }
+            let mut tuple_weights = vec![0.0; NUM_FEATURE_RANKS.pow(len as u32)];
+            for weight in tuple_weights.iter_mut() {
+                reader.read_exact(&mut word)?;
+                *weight = f32::from_le_bytes(word);
+            }
+            tuples.push(tuple);
+            weights.push(tuple_weights);
+        }
+        Ok(NTupleNetwork { tuples, weights })
+    }
+}
+
+// Plays greedily on the value of afterstates, the boards right after a shove and before the new
+// tile is placed, learning those values with TD(λ). An afterstate's value is the score still to
+// be gained from it.
+pub struct NTupleAgent {
+    rng: StdRng,
+    random_agent: RandomAgent,
+    rules: Arc<dyn RuleSet>,
+    network: NTupleNetwork,
+    learning_rate: f64,
+    // λ: how much of each update is passed back to earlier afterstates. 0 is TD(0).
+    trace_decay: f64,
+    exploration_rate: f64,
+    // The features of this game's most recent afterstates, newest first
+    history: VecDeque<Vec<(usize, usize)>>,
+    num_updates: u64,
+}
+
+impl NTupleAgent {
+    pub fn new(
+        seed: Option<&mut StdRng>,
+        rules: Arc<dyn RuleSet>,
+        network: NTupleNetwork,
+        learning_rate: f64,
+        trace_decay: f64,
+        exploration_rate: f64,
+    ) -> NTupleAgent {
+        assert!((0.0..1.0).contains(&trace_decay));
+        let mut rng = utils::resolve_rng_from_seed(seed);
+        let random_agent = RandomAgent::new(Some(&mut rng));
+        NTupleAgent {
+            rng,
+            random_agent,
+            rules,
+            network,
+            learning_rate,
+            trace_decay,
+            exploration_rate,
+            history: VecDeque::new(),
+            num_updates: 0,
+        }
+    }
+
+    pub fn network(&self) -> &NTupleNetwork {
+        &self.network
+    }
+
+    // How many afterstates back an update reaches before its trace is too small to matter
+    fn history_len(&self) -> usize {
+        if self.trace_decay == 0.0 {
+            1
+        } else {
+            1 + (MIN_TRACE.ln() / self.trace_decay.ln()).ceil() as usize
+        }
+    }
+
+    // Each move from `board` with the score it gains plus the value of its afterstate
+    fn afterstate_values(&self, board: &board::Board) -> Vec<(Direction, f64)> {
+        let score = self.rules.score(board);
+        board::ALL_DIRECTIONS
+            .iter()
+            .filter_map(|d| {
+                let mut afterstate = *board;
+                if self.rules.shove(&mut afterstate, *d) || board.is_empty() {
+                    let gained = (self.rules.score(&afterstate) - score) as f64;
+                    Some((*d, gained + self.network.value(&afterstate)))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // The best move from `board` and its value, or None if there are no moves
+    fn best_afterstate(&self, board: &board::Board) -> Option<(Direction, f64)> {
+        self.afterstate_values(board).into_iter().fold(
+            None,
+            |best: Option<(Direction, f64)>, (d, value)| match best {
+                Some((_, best_value)) if best_value >= value => best,
+                _ => Some((d, value)),
+            },
+        )
+    }
+}
+
+impl Agent for NTupleAgent {
+    fn take_action(&mut self, game: &game::Game, train_mode: bool) -> Direction {
+        if train_mode && self.rng.gen_bool(self.exploration_rate) {
+            return self.random_agent.take_action(game, train_mode);
+        }
+        let (d, _) = self.best_afterstate(&game.cur_board).unwrap();
+        if game.available_moves().contains(&d) {
+            d
+        } else {
+            // The agent was built for different rules than the game is played with
+            game.available_moves()[0]
+        }
+    }
+
+    // The reward isn't needed: the score gained is worked out from the afterstates
+    fn update(&mut self, transition: &Transition) {
+        let new_board = &transition.after.board;
+        let mut afterstate = transition.before.board;
+        self.rules.shove(&mut afterstate, transition.action);
+        let features = self.network.features(&afterstate);
+
+        // What the spawned tile scored, plus the best we can do from here on
+        let spawned = (self.rules.score(new_board) - self.rules.score(&afterstate)) as f64;
+        let best_next = if transition.terminal {
+            None
+        } else {
+            self.best_afterstate(new_board)
+        };
+        let target = spawned + best_next.map_or(0.0, |(_, value)| value);
+        let error = target - self.network.value(&afterstate);
+
+        self.history.push_front(features);
+        self.history.truncate(self.history_len());
+        let step = self.learning_rate / self.network.num_features() as f64;
+        let mut trace = 1.0;
+        for features in self.history.iter() {
+            self.network.adjust(features, step * trace * error);
+            trace *= self.trace_decay;
+        }
+        self.num_updates += 1;
+
+        if transition.terminal {
+            // The game is over, so nothing leads on from these afterstates
+            self.history.clear();
+        }
+    }
+
+    fn explain(&mut self, game: &game::Game) -> Option<Explanation> {
+        Some(Explanation::from_values(
+            self.afterstate_values(&game.cur_board),
+        ))
+    }
+
+    fn stats(&self) -> Stats {
+        let num_weights: usize = self.network.weights.iter().map(|w| w.len()).sum();
+        let num_visited = self
+            .network
+            .weights
+            .iter()
+            .flatten()
+            .filter(|w| **w != 0.0)
+            .count();
+        Stats::new("ntuple")
+            .value("tuples", self.network.tuples.len() as f64)
+            .value("weights", num_weights as f64)
+            .value("weights_trained", num_visited as f64)
+            .value("updates", self.num_updates as f64)
+    }
+
+    fn reseed(&mut self, seed: &mut StdRng) {
+        self.rng = utils::resolve_rng_from_seed(Some(seed));
+        self.random_agent.reseed(seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::agent_runner;
+    use super::super::rules;
+    use super::*;
+
+    fn average_score<A: Agent>(agent: &mut A, num_games: usize) -> f64 {
+        let mut rng = StdRng::seed_from_u64(1);
+        let total: i64 = (0..num_games)
+            .map(|_| agent_runner::play_game(Some(&mut rng), agent, false).score)
+            .sum();
+        total as f64 / num_games as f64
+    }
+
+    #[test]
+    fn test_symmetric_value() {
+        let mut network = NTupleNetwork::rows();
+        let board =
+            board::Board::from_rows(&[[1, 2, 3, 6], [0, 3, 0, 0], [0, 0, 12, 0], [0, 0, 0, 1]]);
+        let features = network.features(&board);
+        assert_eq!(features.len(), network.num_features());
+        network.adjust(&features, 1.0);
+        assert!(network.value(&board) >= features.len() as f64);
+        for sym in &board::ALL_SYMMETRIES {
+            assert_eq!(network.value(&sym.apply(&board)), network.value(&board));
+        }
+    }
+
+    #[test]
+    fn test_learns() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut agent = NTupleAgent::new(
+            Some(&mut rng),
+            rules::classic(),
+            NTupleNetwork::rows(),
+            0.3,
+            0.5,
+            0.0,
+        );
+        let untrained = average_score(&mut agent, 20);
+        for _ in 0..500 {
+            agent_runner::play_game(Some(&mut rng), &mut agent, true);
+        }
+        assert!(agent.num_updates > 0);
+        let trained = average_score(&mut agent, 20);
+        assert!(
+            trained > untrained,
+            "trained {} untrained {}",
+            trained,
+            untrained
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut network = NTupleNetwork::rows();
+        let board =
+            board::Board::from_rows(&[[1, 2, 3, 6], [0, 3, 0, 0], [0, 0, 12, 0], [0, 0, 0, 1]]);
+        let features = network.features(&board);
+        network.adjust(&features[..3], 2.5);
+
+        let mut saved = Vec::new();
+        network.write(&mut saved).unwrap();
+        let loaded = NTupleNetwork::read(&mut saved.as_slice()).unwrap();
+        assert_eq!(loaded.tuples, network.tuples);
+        assert_eq!(loaded.value(&board), network.value(&board));
+        assert!(loaded.value(&board) >= 7.5);
+
+        let mut bad_version = saved.clone();
+        bad_version[4] += 1;
+        assert!(NTupleNetwork::read(&mut bad_version.as_slice()).is_err());
+        // Tuples too long to hold the weights of are refused rather than allocated
+        for len in [MAX_TUPLE_LEN as u32 + 1, u32::MAX] {
+            let mut too_long = saved.clone();
+            too_long[12..16].copy_from_slice(&len.to_le_bytes());
+            let err = NTupleNetwork::read(&mut too_long.as_slice()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,577 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::agent_runner::{Agent, Transition};
+use super::board;
+use super::board::Direction;
+use super::game;
+use super::introspect::Stats;
+use super::rules;
+use super::spawn;
+
+// The protocol is one JSON object per line. The engine sends a state whenever it wants a move and
+// the agent replies with a move message such as {"move": "Left"}.
+
+// What the player can see of a game
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateMessage {
+    pub seed: u64,
+    // The number shown on each block, row by row
+    pub board: board::BoardSections,
+    // Every number the next tile could be; more than one if it's a bonus tile
+    pub next_tile: Vec<board::Rank>,
+    pub legal_moves: Vec<Direction>,
+    pub score: game::Score,
+    pub num_moves: i32,
+}
+
+// The number shown on each block of `board` under `rules`, row by row
+fn shown_rows(rules: &dyn rules::RuleSet, board: &board::Board) -> board::BoardSections {
+    let mut rows = [[0; board::WIDTH]; board::WIDTH];
+    for (row, indices) in rows.iter_mut().zip(board.indices().chunks(board::WIDTH)) {
+        for (rank, idx) in row.iter_mut().zip(indices) {
+            *rank = rules.rank(*idx);
+        }
+    }
+    rows
+}
+
+impl StateMessage {
+    pub fn from_game(game: &game::Game) -> StateMessage {
+        let rules = game.rules();
+        let board = shown_rows(rules, &game.cur_board);
+        let next_tile = match game.next_hint() {
+            spawn::NextHint::Tile(idx) => vec![rules.rank(idx)],
+            spawn::NextHint::Bonus { min, max } => (min..=max).map(|i| rules.rank(i)).collect(),
+        };
+        StateMessage {
+            seed: game.seed,
+            board,
+            next_tile,
+            legal_moves: game.available_moves(),
+            score: game.cur_score(),
+            num_moves: game.num_moves(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EngineMessage {
+    // Asks for a move
+    State(StateMessage),
+    // What a move did, sent to agents which are being trained. No reply is expected.
+    Transition {
+        board: board::BoardSections,
+        action: Direction,
+        new_board: board::BoardSections,
+        reward: f64,
+        // The move ended the game
+        terminal: bool,
+    },
+    GameOver {
+        seed: u64,
+        score: game::Score,
+        num_moves: i32,
+    },
+    // The last message from the agent was not understood or not allowed
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MoveMessage {
+    #[serde(rename = "move")]
+    pub direction: Direction,
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    // The other side hung up
+    Closed,
+    Timeout,
+    // A line which isn't the message we wanted
+    BadMessage(String),
+    IllegalMove(Direction),
+    // The other side answered with an error message
+    Rejected(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "i/o error: {}", e),
+            ProtocolError::Closed => write!(f, "connection closed"),
+            ProtocolError::Timeout => write!(f, "timed out waiting for a move"),
+            ProtocolError::BadMessage(line) => write!(f, "could not understand {:?}", line),
+            ProtocolError::IllegalMove(d) => write!(f, "{:?} is not a legal move", d),
+            ProtocolError::Rejected(message) => write!(f, "rejected: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> ProtocolError {
+        ProtocolError::Io(e)
+    }
+}
+
+pub fn write_message<W: Write, M: Serialize>(
+    writer: &mut W,
+    message: &M,
+) -> Result<(), ProtocolError> {
+    let line = serde_json::to_string(message).unwrap();
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn parse_move(line: &str) -> Result<Direction, ProtocolError> {
+    serde_json::from_str::<MoveMessage>(line.trim())
+        .map(|m| m.direction)
+        .map_err(|_| ProtocolError::BadMessage(line.to_string()))
+}
+
+// An agent in another process, or anywhere else which speaks the protocol. If the agent times
+// out or breaks the protocol the error is kept and reported as the agent's failure, and from then
+// on the first legal move is taken so that the game can still finish.
+pub struct ExternalAgent {
+    writer: Box<dyn Write + Send>,
+    // Lines read by a background thread, so that reads can time out
+    lines: mpsc::Receiver<io::Result<String>>,
+    timeout: Duration,
+    child: Option<Child>,
+    error: Option<ProtocolError>,
+    // The seed of the game being played
+    seed: u64,
+    // The rules of the games being played, to show boards in transitions as they look in states
+    rules: Arc<dyn rules::RuleSet>,
+}
+
+impl ExternalAgent {
+    pub fn new<R, W>(
+        reader: R,
+        writer: W,
+        timeout: Duration,
+        rules: Arc<dyn rules::RuleSet>,
+    ) -> ExternalAgent
+    where
+        R: BufRead + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        ExternalAgent {
+            writer: Box::new(writer),
+            lines,
+            timeout,
+            child: None,
+            error: None,
+            seed: 0,
+            rules,
+        }
+    }
+
+    // Run `program`, talking to it over its stdin and stdout
+    pub fn spawn(
+        program: &str,
+        args: &[&str],
+        timeout: Duration,
+        rules: Arc<dyn rules::RuleSet>,
+    ) -> io::Result<ExternalAgent> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut agent = ExternalAgent::new(stdout, stdin, timeout, rules);
+        agent.child = Some(child);
+        Ok(agent)
+    }
+
+    // Messages which don't need a reply. Nothing more is sent once something has gone wrong.
+    fn send(&mut self, message: &EngineMessage) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = write_message(&mut self.writer, message) {
+            self.error = Some(e);
+        }
+    }
+
+    // The first thing which went wrong talking to the agent, if anything has
+    pub fn error(&self) -> Option<&ProtocolError> {
+        self.error.as_ref()
+    }
+
+    fn request_move(&mut self, game: &game::Game) -> Result<Direction, ProtocolError> {
+        write_message(
+            &mut self.writer,
+            &EngineMessage::State(StateMessage::from_game(game)),
+        )?;
+        let line = match self.lines.recv_timeout(self.timeout) {
+            Ok(line) => line?,
+            Err(mpsc::RecvTimeoutError::Timeout) => return Err(ProtocolError::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(ProtocolError::Closed),
+        };
+        let d = parse_move(&line)?;
+        if game.available_moves().contains(&d) {
+            Ok(d)
+        } else {
+            Err(ProtocolError::IllegalMove(d))
+        }
+    }
+}
+
+impl Drop for ExternalAgent {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Agent for ExternalAgent {
+    fn take_action(&mut self, game: &game::Game, _train_mode: bool) -> Direction {
+        if self.error.is_none() {
+            match self.request_move(game) {
+                Ok(d) => return d,
+                Err(e) => {
+                    eprintln!(
+                        "external agent failed, taking the first legal move from now on: {}",
+                        e
+                    );
+                    self.error = Some(e);
+                }
+            }
+        }
+        game.available_moves()[0]
+    }
+
+    fn update(&mut self, transition: &Transition) {
+        self.send(&EngineMessage::Transition {
+            board: shown_rows(self.rules.as_ref(), &transition.before.board),
+            action: transition.action,
+            new_board: shown_rows(self.rules.as_ref(), &transition.after.board),
+            reward: transition.reward,
+            terminal: transition.terminal,
+        });
+    }
+
+    fn start_episode(&mut self, seed: u64, _train_mode: bool) {
+        self.seed = seed;
+    }
+
+    // Tell the agent how the game went, as games served to agents do
+    fn end_episode(&mut self, result: &game::GameResult, _train_mode: bool) {
+        self.send(&EngineMessage::GameOver {
+            seed: self.seed,
+            score: result.score,
+            num_moves: result.num_moves,
+        });
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.error.as_ref().map(|e| e.to_string())
+    }
+
+    fn stats(&self) -> Stats {
+        let stats = Stats::new("external");
+        match &self.error {
+            Some(e) => stats.note("error", &e.to_string()),
+            None => stats,
+        }
+    }
+}
+
+// How many moves in a row may be refused before serve_games gives up on the agent
+const MAX_REFUSED_MOVES: usize = 10;
+
+// Host games for an agent on the other end of `reader` and `writer`, one game per seed. Moves
+// which can't be understood or aren't legal are answered with an error message, and the state is
+// sent again, until too many have been refused in a row.
+pub fn serve_games<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    rules: Arc<dyn rules::RuleSet>,
+    seeds: &[u64],
+) -> Result<Vec<game::GameResult>, ProtocolError> {
+    let mut lines = reader.lines();
+    let mut results = Vec::new();
+    for seed in seeds {
+        let mut game = game::Game::with_rules(Some(*seed), true, rules.clone());
+        let mut num_refused = 0;
+        let result = loop {
+            write_message(
+                &mut writer,
+                &EngineMessage::State(StateMessage::from_game(&game)),
+            )?;
+            let line = lines.next().ok_or(ProtocolError::Closed)??;
+            let d = match parse_move(&line) {
+                Ok(d) if !game.available_moves().contains(&d) => Err(ProtocolError::IllegalMove(d)),
+                parsed => parsed,
+            };
+            let d = match d {
+                Ok(d) => d,
+                Err(e) => {
+                    num_refused += 1;
+                    if num_refused == MAX_REFUSED_MOVES {
+                        return Err(e);
+                    }
+                    report_error(&mut writer, e)?;
+                    continue;
+                }
+            };
+            num_refused = 0;
+            if let game::MoveResult::Moved(Some(result)) = game.update(d) {
+                break result;
+            }
+        };
+        write_message(
+            &mut writer,
+            &EngineMessage::GameOver {
+                seed: *seed,
+                score: result.score,
+                num_moves: result.num_moves,
+            },
+        )?;
+        results.push(result);
+    }
+    Ok(results)
+}
+
+fn report_error<W: Write>(writer: &mut W, e: ProtocolError) -> Result<(), ProtocolError> {
+    write_message(
+        writer,
+        &EngineMessage::Error {
+            message: e.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{agent_runner, eval};
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    // Connect an ExternalAgent to a thread which answers each state with `respond`
+    fn connect<F>(respond: F, timeout: Duration) -> ExternalAgent
+    where
+        F: Fn(&StateMessage) -> Option<String> + Send + 'static,
+    {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        thread::spawn(move || {
+            let mut writer = theirs.try_clone().unwrap();
+            for line in BufReader::new(theirs).lines() {
+                let message: EngineMessage = serde_json::from_str(&line.unwrap()).unwrap();
+                if let EngineMessage::State(state) = message {
+                    if let Some(reply) = respond(&state) {
+                        writeln!(writer, "{}", reply).unwrap();
+                    }
+                }
+            }
+        });
+        ExternalAgent::new(
+            BufReader::new(ours.try_clone().unwrap()),
+            ours,
+            timeout,
+            rules::classic(),
+        )
+    }
+
+    fn first_legal_move(state: &StateMessage) -> Option<String> {
+        let reply = MoveMessage {
+            direction: state.legal_moves[0],
+        };
+        Some(serde_json::to_string(&reply).unwrap())
+    }
+
+    #[test]
+    fn test_messages() {
+        let game = game::Game::new(Some(0), false);
+        let state = StateMessage::from_game(&game);
+        assert_eq!(state.board, [[0; 4]; 4]);
+        assert_eq!(state.legal_moves.len(), 4);
+        let line = serde_json::to_string(&EngineMessage::State(state.clone())).unwrap();
+        assert!(line.starts_with(r#"{"type":"state","seed":0,"board":"#));
+        assert_eq!(
+            serde_json::from_str::<EngineMessage>(&line).unwrap(),
+            EngineMessage::State(state)
+        );
+        assert_eq!(parse_move(r#"{"move": "Up"}"#).unwrap(), Direction::Up);
+        assert!(parse_move(r#"{"move": "Sideways"}"#).is_err());
+    }
+
+    #[test]
+    fn test_external_agent_plays() {
+        let mut agent = connect(first_legal_move, Duration::from_secs(10));
+        let result = agent_runner::play_game(None, &mut agent, true);
+        assert!(agent.error().is_none());
+        assert_ne!(result.score, 0);
+    }
+
+    #[test]
+    fn test_transition_ranks() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let rules = rules::by_name(rules::TWENTY_FORTY_EIGHT).unwrap();
+        let mut agent = ExternalAgent::new(
+            BufReader::new(ours.try_clone().unwrap()),
+            ours,
+            Duration::from_secs(10),
+            rules.clone(),
+        );
+        let mut before = board::Board::new();
+        before.set_index(0, 0, 1);
+        before.set_index(0, 1, 1);
+        let mut after = board::Board::new();
+        after.set_index(0, 0, 2);
+        let observe = |board| agent_runner::Observation {
+            board,
+            next_hint: spawn::NextHint::Tile(1),
+        };
+        agent.update(&Transition {
+            before: observe(before),
+            action: Direction::Left,
+            after: observe(after),
+            reward: 4.0,
+            terminal: false,
+        });
+
+        // Boards are shown with the numbers of the rules being played, as states are
+        let mut lines = BufReader::new(theirs).lines();
+        match serde_json::from_str::<EngineMessage>(&lines.next().unwrap().unwrap()).unwrap() {
+            EngineMessage::Transition {
+                board, new_board, ..
+            } => {
+                assert_eq!(board[0], [2, 2, 0, 0]);
+                assert_eq!(new_board[0], [4, 0, 0, 0]);
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    #[test]
+    fn test_external_agent_errors() {
+        let game = game::Game::new(Some(0), false);
+
+        let mut agent = connect(|_| None, Duration::from_millis(20));
+        assert!(game
+            .available_moves()
+            .contains(&agent.take_action(&game, false)));
+        assert!(matches!(agent.error(), Some(ProtocolError::Timeout)));
+
+        let mut agent = connect(|_| Some("left please".to_string()), Duration::from_secs(10));
+        agent.take_action(&game, false);
+        assert!(matches!(agent.error(), Some(ProtocolError::BadMessage(_))));
+
+        // Once something has gone wrong the agent stops asking and still finishes the game
+        let result = agent_runner::play_game(None, &mut agent, false);
+        assert_ne!(result.num_moves, 0);
+        assert!(agent.failure().is_some());
+
+        // but evaluating the agent fails rather than scoring the fallback moves
+        let mut agent = connect(|_| None, Duration::from_millis(20));
+        let suite = eval::SeedSuite::by_name(eval::SMALL).unwrap();
+        assert!(eval::evaluate(&mut agent, rules::classic(), &suite).is_err());
+    }
+
+    #[test]
+    fn test_serve_games_gives_up() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let writer = theirs.try_clone().unwrap();
+            serve_games(BufReader::new(theirs), writer, rules::classic(), &[4])
+        });
+        // Drain the server's messages so that it never blocks writing them
+        let reader = ours.try_clone().unwrap();
+        thread::spawn(move || BufReader::new(reader).lines().count());
+
+        let mut writer = ours;
+        for _ in 0..MAX_REFUSED_MOVES {
+            writeln!(writer, "not json").unwrap();
+        }
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(ProtocolError::BadMessage(_))
+        ));
+
+        // and stops when the agent goes away
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(ours);
+        let writer = theirs.try_clone().unwrap();
+        assert!(serve_games(BufReader::new(theirs), writer, rules::classic(), &[4]).is_err());
+    }
+
+    #[test]
+    fn test_serve_games() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let writer = theirs.try_clone().unwrap();
+            serve_games(BufReader::new(theirs), writer, rules::classic(), &[4, 5])
+        });
+
+        let mut writer = ours.try_clone().unwrap();
+        let mut lines = BufReader::new(ours).lines();
+        let mut next_message =
+            || serde_json::from_str::<EngineMessage>(&lines.next().unwrap().unwrap()).unwrap();
+        let mut num_errors = 0;
+        let mut scores = Vec::new();
+        while scores.len() < 2 {
+            match next_message() {
+                EngineMessage::State(state) => {
+                    if state.num_moves == 1 && num_errors == 0 {
+                        // Mistakes are reported and the state is sent again
+                        writeln!(writer, "not json").unwrap();
+                        assert!(matches!(next_message(), EngineMessage::Error { .. }));
+                        assert_eq!(next_message(), EngineMessage::State(state.clone()));
+                        let illegal = board::ALL_DIRECTIONS
+                            .iter()
+                            .find(|d| !state.legal_moves.contains(d));
+                        if let Some(d) = illegal {
+                            writeln!(writer, r#"{{"move": "{:?}"}}"#, d).unwrap();
+                            assert!(matches!(next_message(), EngineMessage::Error { .. }));
+                            assert_eq!(next_message(), EngineMessage::State(state.clone()));
+                        }
+                        num_errors += 1;
+                    }
+                    writeln!(writer, "{}", first_legal_move(&state).unwrap()).unwrap();
+                }
+                EngineMessage::GameOver { score, .. } => scores.push(score),
+                message => panic!("unexpected {:?}", message),
+            }
+        }
+        drop(writer);
+
+        let results = server.join().unwrap().unwrap();
+        assert_eq!(num_errors, 1);
+        assert_eq!(
+            results
+                .iter()
+                .map(|r| r.score)
+                .collect::<Vec<game::Score>>(),
+            scores
+        );
+        assert_eq!(results[1].log.as_ref().unwrap().seed, 5);
+    }
+}
@@ -1,36 +1,119 @@
-use super::agent_runner::Agent;
+use super::agent_runner::{Agent, Observation, Transition};
 use super::board;
 use super::board::Direction;
+use super::exploration::{Exploration, Schedule, Strategy};
 use super::game;
+use super::introspect::{Explanation, Stats};
 use super::random_agent::RandomAgent;
+use super::spawn::NextHint;
 use super::utils;
 
 use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 type StandardFormBoard = board::Board;
 
+// What Q values are learned for: the canonical form of a board, along with what's shown of the
+// next tile, since the best move often depends on where that tile could go
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct StateKey {
+    board: StandardFormBoard,
+    next_hint: NextHint,
+}
+
+impl StateKey {
+    fn of(observation: &Observation) -> (StateKey, board::Symmetry) {
+        let (board, sym) = observation.board.canonical();
+        let key = StateKey {
+            board,
+            next_hint: observation.next_hint,
+        };
+        (key, sym)
+    }
+}
+
+// Saved Q tables are tagged with this version, which must change whenever the meaning of a saved
+// table does
+const Q_TABLE_VERSION: u32 = 4;
+// Version 3 is the same, except that binary tables packed each board's blocks into 4 bits of a
+// u64, before tiles past 12288 could be stored
+const PACKED_U64_VERSION: u32 = 3;
+// The start of every binary Q table file
+const BINARY_MAGIC: &[u8; 4] = b"THQT";
+
 type ActionRewards = crate::EnumMap<Direction, f64>;
 
+#[derive(Clone)]
 pub struct RewardTable {
     rewards: ActionRewards,
     read_count: i64,
 }
 
 pub struct QTable {
-    action_rewards: HashMap<StandardFormBoard, RewardTable>,
+    action_rewards: HashMap<StateKey, RewardTable>,
+}
+
+// How a Q table is saved as JSON. Binary files hold the same fields.
+#[derive(Serialize, Deserialize)]
+struct SavedQTable {
+    version: u32,
+    entries: Vec<SavedRewardTable>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRewardTable {
+    board: board::Board,
+    next_hint: NextHint,
+    rewards: Vec<(Direction, f64)>,
+    read_count: i64,
+}
+
+// A Q table which many threads can learn into at once. Boards are spread over shards, each behind
+// its own lock, so threads rarely have to wait for each other.
+pub struct SharedQTable {
+    shards: Vec<Mutex<QTable>>,
+}
+
+// Where a QAgent keeps what it learns: a QTable of its own, or a SharedQTable which other agents
+// learn into at the same time
+pub trait QStore {
+    // The best action in `state`, and its value
+    fn max_action(&mut self, state: &Observation) -> (Direction, f64);
+    fn max_q_from_directions(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Direction;
+    fn action_values(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Vec<(Direction, f64)>;
+    fn read_count(&self, state: &Observation) -> i64;
+    // Move the value of `direction` in `state` towards `target`
+    fn learn(&mut self, state: &Observation, direction: Direction, target: f64, learning_rate: f64);
+    fn stats(&self, agent: &str) -> Stats;
 }
 
-pub struct QAgent {
+pub struct QAgent<T: QStore = QTable> {
     rng: StdRng,
     random_agent: RandomAgent,
-    q_table: QTable,
+    q_table: T,
     learning_rate: f64,
     discount_factor: f64,
-    exploration_rate: f64,
+    exploration: Exploration,
 }
 
+// Plays and learns like a QAgent, but into a table it shares with other agents
+pub type SharedQAgent = QAgent<Arc<SharedQTable>>;
+
 impl RewardTable {
     pub fn _new() -> RewardTable {
         RewardTable {
@@ -47,6 +130,12 @@ impl RewardTable {
     }
 }
 
+impl Default for QTable {
+    fn default() -> QTable {
+        QTable::new()
+    }
+}
+
 impl QTable {
     pub fn new() -> QTable {
         QTable {
@@ -54,6 +143,134 @@ impl QTable {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.action_rewards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.action_rewards.is_empty()
+    }
+
+    // Save to `path`, as JSON if it ends in .json and in the compact binary format otherwise
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if is_json_path(path) {
+            self.write_json(&mut writer)?;
+        } else {
+            self.write_binary(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    // Load a table written by `save`
+    pub fn load(path: &Path) -> io::Result<QTable> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if is_json_path(path) {
+            QTable::read_json(&mut reader)
+        } else {
+            QTable::read_binary(&mut reader)
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let saved = SavedQTable {
+            version: Q_TABLE_VERSION,
+            entries: self
+                .action_rewards
+                .iter()
+                .map(|(key, table)| SavedRewardTable {
+                    board: key.board,
+                    next_hint: key.next_hint,
+                    rewards: table.rewards.iter().map(|(d, q)| (d, *q)).collect(),
+                    read_count: table.read_count,
+                })
+                .collect(),
+        };
+        serde_json::to_writer(writer, &saved).map_err(io::Error::from)
+    }
+
+    pub fn read_json<R: Read>(reader: &mut R) -> io::Result<QTable> {
+        let saved: SavedQTable = serde_json::from_reader(reader).map_err(io::Error::from)?;
+        check_version(saved.version)?;
+        let mut q_table = QTable::new();
+        for entry in saved.entries {
+            let mut rewards = ActionRewards::default();
+            for (d, q) in entry.rewards {
+                rewards[d] = q;
+            }
+            q_table.action_rewards.insert(
+                StateKey {
+                    board: entry.board,
+                    next_hint: entry.next_hint,
+                },
+                RewardTable {
+                    rewards,
+                    read_count: entry.read_count,
+                },
+            );
+        }
+        Ok(q_table)
+    }
+
+    // The magic bytes, the version and the number of entries, followed by each entry's packed
+    // 16 byte board, its next hint, its rewards in the order of ALL_DIRECTIONS and its read count. All
+    // little endian. A hint is three bytes: 0 and the tile twice, or 1 and a bonus tile's range.
+    pub fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&Q_TABLE_VERSION.to_le_bytes())?;
+        writer.write_all(&(self.action_rewards.len() as u64).to_le_bytes())?;
+        for (key, table) in self.action_rewards.iter() {
+            writer.write_all(&key.board.to_bits().to_le_bytes())?;
+            writer.write_all(&hint_bytes(key.next_hint))?;
+            for d in &board::ALL_DIRECTIONS {
+                writer.write_all(&table.rewards[*d].to_le_bytes())?;
+            }
+            writer.write_all(&table.read_count.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_binary<R: Read>(reader: &mut R) -> io::Result<QTable> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != BINARY_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a binary Q table",
+            ));
+        }
+        let mut word = [0; 4];
+        reader.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        check_version(version)?;
+
+        let mut long = [0; 8];
+        reader.read_exact(&mut long)?;
+        let num_entries = u64::from_le_bytes(long);
+        let mut q_table = QTable::new();
+        for _ in 0..num_entries {
+            let board = read_packed_board(reader, version)?;
+            let mut hint = [0; 3];
+            reader.read_exact(&mut hint)?;
+            let next_hint = hint_from_bytes(hint)?;
+            let mut rewards = ActionRewards::default();
+            for d in &board::ALL_DIRECTIONS {
+                reader.read_exact(&mut long)?;
+                rewards[*d] = f64::from_le_bytes(long);
+            }
+            reader.read_exact(&mut long)?;
+            let read_count = i64::from_le_bytes(long);
+            q_table.action_rewards.insert(
+                StateKey { board, next_hint },
+                RewardTable {
+                    rewards,
+                    read_count,
+                },
+            );
+        }
+        Ok(q_table)
+    }
+
     pub fn fold_cmp_directions(
         l_move: (Direction, f64),
         r_move: (Direction, f64),
@@ -65,33 +282,81 @@ impl QTable {
         }
     }
 
-    pub fn max_action(&mut self, board: &board::Board) -> (Direction, f64) {
-        self.get_reward_table(board)
+    // The best action in `state`, and its value
+    pub fn max_action(&mut self, state: &Observation) -> (Direction, f64) {
+        let (key, sym) = StateKey::of(state);
+        let (d, q) = self.canonical_max_action(&key);
+        (sym.inverse().map_direction(d), q)
+    }
+
+    fn canonical_max_action(&mut self, canonical: &StateKey) -> (Direction, f64) {
+        self.get_reward_table(canonical)
             .rewards
             .iter()
             .map(|(d, q)| (d, *q))
             .fold((Direction::Down, -1.0), Self::fold_cmp_directions)
     }
 
-    pub fn q_value(&mut self, board: &board::Board, direction: Direction) -> f64 {
-        self.get_reward_table(board).rewards[direction]
+    pub fn q_value(&mut self, state: &Observation, direction: Direction) -> f64 {
+        let (key, sym) = StateKey::of(state);
+        self.get_reward_table(&key).rewards[sym.map_direction(direction)]
+    }
+
+    pub fn set_q_value(&mut self, state: &Observation, direction: Direction, q: f64) {
+        let (key, sym) = StateKey::of(state);
+        self.get_reward_table(&key).rewards[sym.map_direction(direction)] = q;
     }
 
     pub fn max_q_from_directions(
         &mut self,
-        board: &board::Board,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Direction {
+        let (key, sym) = StateKey::of(state);
+        self.canonical_max_q_from_directions(&key, sym, available_directions)
+    }
+
+    // The values of each of `available_directions` in `state`
+    pub fn action_values(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Vec<(Direction, f64)> {
+        let (key, sym) = StateKey::of(state);
+        let action_rewards = self.get_reward_table(&key);
+        available_directions
+            .iter()
+            .map(|d| (*d, action_rewards.rewards[sym.map_direction(*d)]))
+            .collect()
+    }
+
+    // How many times `state` has been read, without counting this one
+    pub fn read_count(&self, state: &Observation) -> i64 {
+        let (key, _) = StateKey::of(state);
+        self.action_rewards
+            .get(&key)
+            .map_or(0, |table| table.read_count)
+    }
+
+    fn canonical_max_q_from_directions(
+        &mut self,
+        canonical: &StateKey,
+        sym: board::Symmetry,
         available_directions: &[Direction],
     ) -> Direction {
-        let action_rewards = self.get_reward_table(board);
+        let action_rewards = self.get_reward_table(canonical);
         available_directions
             .iter()
-            .map(|d| (*d, action_rewards.rewards[*d]))
+            .map(|d| (*d, action_rewards.rewards[sym.map_direction(*d)]))
             .fold((available_directions[0], -1.0), Self::fold_cmp_directions)
             .0
     }
 
-    pub fn get_reward_table(&mut self, board: &board::Board) -> &mut RewardTable {
-        let mut reward_table = self.action_rewards.entry(*board).or_insert_with(|| {
+    // Tables are only kept for the canonical form of each board, so that what's learned about
+    // one board applies to all of its rotations and reflections. Directions in the table are
+    // directions on the canonical board.
+    fn get_reward_table(&mut self, key: &StateKey) -> &mut RewardTable {
+        let reward_table = self.action_rewards.entry(*key).or_insert_with(|| {
             RewardTable::from_map({
                 enum_map! {
                     Direction::Left => 80.0,
@@ -106,6 +371,257 @@ impl QTable {
     }
 }
 
+impl SharedQTable {
+    pub fn new(num_shards: usize) -> SharedQTable {
+        SharedQTable::from_table(QTable::new(), num_shards)
+    }
+
+    // Share out the entries of `q_table`, e.g. one which was saved earlier
+    pub fn from_table(q_table: QTable, num_shards: usize) -> SharedQTable {
+        assert!(num_shards > 0);
+        let mut shards = (0..num_shards)
+            .map(|_| QTable::new())
+            .collect::<Vec<QTable>>();
+        for (key, table) in q_table.action_rewards {
+            shards[Self::shard_index(&key, num_shards)]
+                .action_rewards
+                .insert(key, table);
+        }
+        SharedQTable {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    // A copy of everything learned so far, as one table
+    pub fn to_table(&self) -> QTable {
+        let mut q_table = QTable::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            q_table.action_rewards.extend(
+                shard
+                    .action_rewards
+                    .iter()
+                    .map(|(key, table)| (*key, table.clone())),
+            );
+        }
+        q_table
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard_index(canonical: &StateKey, num_shards: usize) -> usize {
+        let [kind, min, max] = hint_bytes(canonical.next_hint);
+        let hint = u64::from_le_bytes([kind, min, max, 0, 0, 0, 0, 0]);
+        // Spread the bits around first; nearby boards differ in only a few of them
+        let cells = canonical.board.to_bits();
+        let bits = cells as u64 ^ (cells >> 64) as u64 ^ hint.rotate_right(24);
+        (bits.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize % num_shards
+    }
+
+    fn shard(&self, canonical: &StateKey) -> MutexGuard<'_, QTable> {
+        self.shards[Self::shard_index(canonical, self.shards.len())]
+            .lock()
+            .unwrap()
+    }
+
+    pub fn max_action(&self, state: &Observation) -> (Direction, f64) {
+        let (key, sym) = StateKey::of(state);
+        let (d, q) = self.shard(&key).canonical_max_action(&key);
+        (sym.inverse().map_direction(d), q)
+    }
+
+    pub fn q_value(&self, state: &Observation, direction: Direction) -> f64 {
+        let (key, sym) = StateKey::of(state);
+        self.shard(&key).get_reward_table(&key).rewards[sym.map_direction(direction)]
+    }
+
+    pub fn max_q_from_directions(
+        &self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Direction {
+        let (key, sym) = StateKey::of(state);
+        self.shard(&key)
+            .canonical_max_q_from_directions(&key, sym, available_directions)
+    }
+
+    pub fn action_values(
+        &self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Vec<(Direction, f64)> {
+        let (key, _) = StateKey::of(state);
+        self.shard(&key).action_values(state, available_directions)
+    }
+
+    pub fn read_count(&self, state: &Observation) -> i64 {
+        let (key, _) = StateKey::of(state);
+        self.shard(&key).read_count(state)
+    }
+
+    // Move the value of `direction` in `state` towards `target`. Other threads can't update the
+    // same value in between reading and writing it.
+    pub fn learn(
+        &self,
+        state: &Observation,
+        direction: Direction,
+        target: f64,
+        learning_rate: f64,
+    ) {
+        let (key, sym) = StateKey::of(state);
+        let mut shard = self.shard(&key);
+        let q = &mut shard.get_reward_table(&key).rewards[sym.map_direction(direction)];
+        *q = *q * (1.0 - learning_rate) + learning_rate * target;
+    }
+}
+
+impl QStore for QTable {
+    fn max_action(&mut self, state: &Observation) -> (Direction, f64) {
+        QTable::max_action(self, state)
+    }
+
+    fn max_q_from_directions(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Direction {
+        QTable::max_q_from_directions(self, state, available_directions)
+    }
+
+    fn action_values(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Vec<(Direction, f64)> {
+        QTable::action_values(self, state, available_directions)
+    }
+
+    fn read_count(&self, state: &Observation) -> i64 {
+        QTable::read_count(self, state)
+    }
+
+    fn learn(
+        &mut self,
+        state: &Observation,
+        direction: Direction,
+        target: f64,
+        learning_rate: f64,
+    ) {
+        let new_q = self.q_value(state, direction) * (1.0 - learning_rate) + learning_rate * target;
+        self.set_q_value(state, direction, new_q);
+    }
+
+    fn stats(&self, agent: &str) -> Stats {
+        QTable::stats(self, agent)
+    }
+}
+
+impl QStore for Arc<SharedQTable> {
+    fn max_action(&mut self, state: &Observation) -> (Direction, f64) {
+        SharedQTable::max_action(self, state)
+    }
+
+    fn max_q_from_directions(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Direction {
+        SharedQTable::max_q_from_directions(self, state, available_directions)
+    }
+
+    fn action_values(
+        &mut self,
+        state: &Observation,
+        available_directions: &[Direction],
+    ) -> Vec<(Direction, f64)> {
+        SharedQTable::action_values(self, state, available_directions)
+    }
+
+    fn read_count(&self, state: &Observation) -> i64 {
+        SharedQTable::read_count(self, state)
+    }
+
+    fn learn(
+        &mut self,
+        state: &Observation,
+        direction: Direction,
+        target: f64,
+        learning_rate: f64,
+    ) {
+        SharedQTable::learn(self, state, direction, target, learning_rate)
+    }
+
+    fn stats(&self, agent: &str) -> Stats {
+        self.to_table().stats(agent)
+    }
+}
+
+fn is_json_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+fn hint_bytes(hint: NextHint) -> [u8; 3] {
+    match hint {
+        NextHint::Tile(idx) => [0, idx, idx],
+        NextHint::Bonus { min, max } => [1, min, max],
+    }
+}
+
+fn hint_from_bytes(bytes: [u8; 3]) -> io::Result<NextHint> {
+    match bytes {
+        [0, idx, _] => Ok(NextHint::Tile(idx)),
+        [1, min, max] => Ok(NextHint::Bonus { min, max }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad next hint {:?} in Q table", bytes),
+        )),
+    }
+}
+
+fn check_version(version: u32) -> io::Result<()> {
+    if (PACKED_U64_VERSION..=Q_TABLE_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Q table is version {} but only versions {} to {} can be read",
+                version, PACKED_U64_VERSION, Q_TABLE_VERSION
+            ),
+        ))
+    }
+}
+
+fn read_packed_board<R: Read>(reader: &mut R, version: u32) -> io::Result<board::Board> {
+    let board = if version == PACKED_U64_VERSION {
+        let mut long = [0; 8];
+        reader.read_exact(&mut long)?;
+        let cells = u64::from_le_bytes(long);
+        let mut board = board::Board::new();
+        for i in 0..board::WIDTH * board::WIDTH {
+            let idx = ((cells >> (4 * i)) & 0xF) as board::RankIndex;
+            board.set_index(i / board::WIDTH, i % board::WIDTH, idx);
+        }
+        Some(board)
+    } else {
+        let mut bytes = [0; 16];
+        reader.read_exact(&mut bytes)?;
+        board::Board::from_bits(u128::from_le_bytes(bytes))
+    };
+    board.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Q table has a board with a block which isn't a rank",
+        )
+    })
+}
+
 impl QAgent {
     pub fn new(
         seed: Option<&mut StdRng>,
@@ -113,140 +629,193 @@ impl QAgent {
         discount_factor: f64,
         exploration_rate: f64,
     ) -> QAgent {
+        QAgent::with_table(
+            seed,
+            QTable::new(),
+            learning_rate,
+            discount_factor,
+            exploration_rate,
+        )
+    }
+}
+
+impl<T: QStore> QAgent<T> {
+    // An agent which learns into `q_table`, e.g. a SharedQTable
+    pub fn with_table(
+        seed: Option<&mut StdRng>,
+        q_table: T,
+        learning_rate: f64,
+        discount_factor: f64,
+        exploration_rate: f64,
+    ) -> QAgent<T> {
         let mut rng = utils::resolve_rng_from_seed(seed);
         let random_agent = RandomAgent::new(Some(&mut rng));
         QAgent {
             rng,
             random_agent,
-            q_table: QTable::new(),
+            q_table,
             learning_rate,
             discount_factor,
-            exploration_rate,
+            exploration: Exploration::new(
+                Strategy::EpsilonGreedy,
+                Schedule::Constant,
+                exploration_rate,
+            ),
         }
     }
-}
 
-impl Agent for QAgent {
-    fn take_action(&mut self, game: &game::Game, train_mode: bool) -> Direction {
-        if train_mode && self.rng.gen_bool(self.exploration_rate) {
-            // let's explore
-            self.random_agent.take_action(game, train_mode)
-        } else {
-            // take the best option
-            self.q_table
-                .max_q_from_directions(&game.cur_board, &game.available_moves())
-        }
+    // Explore some other way than making random moves at a constant rate
+    pub fn with_exploration(mut self, strategy: Strategy, schedule: Schedule) -> QAgent<T> {
+        self.exploration = Exploration::new(strategy, schedule, self.exploration.initial_rate);
+        self
     }
 
-    fn update(
-        &mut self,
-        board: &board::Board,
-        action: Direction,
-        new_board: &board::Board,
-        reward: f64,
-    ) {
-        let new_q = self.q_table.q_value(board, action) * (1.0 - self.learning_rate)
-            + self.learning_rate
-                * (reward + self.discount_factor * self.q_table.max_action(new_board).1);
-        self.q_table.get_reward_table(board).rewards[action] = new_q;
+    pub fn exploration(&self) -> &Exploration {
+        &self.exploration
     }
 
-    fn print(&self) {
-        println!("qtable {} entries", self.q_table.action_rewards.len());
-        let nonzero_q_value_counts = self
-            .q_table
-            .action_rewards
-            .iter()
-            // Count the number of non-zero q table entries for this board
-            .map(|(b, acts)| {
-                (
-                    b,
-                    acts,
-                    acts.rewards
-                        .iter()
-                        .filter(|(_d, q)| q.abs() > 0.001)
-                        .count(),
-                )
-            })
-            .collect::<Vec<(&board::Board, &RewardTable, usize)>>();
-        let q_counts = (0..5)
-            .map(|fullness| {
-                (
-                    fullness,
-                    nonzero_q_value_counts
-                        .iter()
-                        .filter(|(_b, _acts, c)| *c == fullness as usize)
-                        .count(),
-                )
-            })
-            .collect::<HashMap<i32, usize>>();
-        println!("Q table fullness:\n{:?}", q_counts);
-        let full_tables = nonzero_q_value_counts
-            .iter()
-            .filter(|(_b, _acts, c)| *c == 4)
-            .collect::<Vec<&(&board::Board, &RewardTable, usize)>>();
+    pub fn q_table(&self) -> &T {
+        &self.q_table
+    }
 
-        //for (board, actions) in self.q_table.action_rewards.iter().take(3) {
-        for (board, actions, _) in full_tables.iter().take(3) {
-            println!("{}", board.simple_render());
-            for d in &board::ALL_DIRECTIONS {
-                println!("entry[{:?}] {}", d, actions.rewards[*d]);
+    pub fn into_q_table(self) -> T {
+        self.q_table
+    }
+
+    // Replace what the agent has learned, e.g. with a table which was saved earlier
+    pub fn set_q_table(&mut self, q_table: T) {
+        self.q_table = q_table;
+    }
+}
+
+impl<T: QStore> Agent for QAgent<T> {
+    fn take_action(&mut self, game: &game::Game, train_mode: bool) -> Direction {
+        let available = game.available_moves();
+        let state = Observation::of(game);
+        if train_mode {
+            // let's explore
+            let q_table = &mut self.q_table;
+            let explored =
+                self.exploration
+                    .explore(&mut self.rng, &mut self.random_agent, game, || {
+                        q_table.action_values(&state, &available)
+                    });
+            if let Some(direction) = explored {
+                return direction;
             }
         }
+        // take the best option
+        self.q_table.max_q_from_directions(&state, &available)
+    }
 
-        println!("===========================\n\n\n");
+    fn update(&mut self, transition: &Transition) {
+        let (state, action, new_state) = (&transition.before, transition.action, &transition.after);
+        // Nothing follows the end of a game
+        let next_q = if transition.terminal {
+            0.0
+        } else {
+            self.q_table.max_action(new_state).1
+        };
+        let reward = transition.reward + self.exploration.bonus(self.q_table.read_count(new_state));
+        let target = reward + self.discount_factor * next_q;
+        self.q_table
+            .learn(state, action, target, self.learning_rate);
+    }
 
-        // All of the above might not be useful since "fullness" real means we have some priors.
-        // I've changed my thinking here and basic priors seems like a good idea for now.
+    fn stats(&self) -> Stats {
+        self.q_table.stats("q")
+    }
 
-        // Instead, let's look at 'hot' tables. We track read count for each reward table. Let's
-        // just look at the top few
-        let num_top_tables = 10;
+    fn reseed(&mut self, seed: &mut StdRng) {
+        self.rng = utils::resolve_rng_from_seed(Some(seed));
+        self.random_agent.reseed(seed);
+    }
 
-        let mut reward_tables: Vec<(&board::Board, &RewardTable)> =
-            self.q_table.action_rewards.iter().collect();
+    fn start_generation(&mut self, generation: i32, num_generations: i32) {
+        self.exploration
+            .start_generation(generation, num_generations);
+    }
 
-        reward_tables
-            .sort_unstable_by(|(_, acts_x), (_, acts_y)| acts_x.read_count.cmp(&acts_y.read_count));
+    fn explain(&mut self, game: &game::Game) -> Option<Explanation> {
+        let state = Observation::of(game);
+        let read_count = self.q_table.read_count(&state);
+        let values = self.q_table.action_values(&state, &game.available_moves());
+        Some(Explanation::from_values(values).with_detail("read_count", read_count as f64))
+    }
+}
 
-        let mut histogram = histogram::Histogram::new();
-        for table in reward_tables.iter() {
-            histogram.increment(table.1.read_count as u64).unwrap();
+impl QTable {
+    // A summary of what's in the table, for a feel of what was learned
+    pub fn stats(&self, agent: &str) -> Stats {
+        let mut stats = Stats::new(agent).value("entries", self.action_rewards.len() as f64);
+        // How many boards have each number of moves with a learned value, away from zero
+        let mut fullness = [0; 5];
+        for table in self.action_rewards.values() {
+            let learned = table.rewards.values().filter(|q| q.abs() > 0.001).count();
+            fullness[learned] += 1;
         }
-        println!("distribution of read counts:");
-        println!("percentile, read count");
-        for p in &[1.0, 10.0, 50.0, 90.0, 99.0, 99.9, 99.99] {
-            println!(" p{:5}: {}", p, histogram.percentile(*p).unwrap());
+        for (learned, count) in fullness.iter().enumerate() {
+            stats = stats.value(&format!("boards_with_{}_values", learned), *count as f64);
         }
-        println!("------------------------\n");
 
-        for (ii, table) in reward_tables.iter().rev().take(num_top_tables).enumerate() {
-            println!("#{} table [{} reads]", ii, table.1.read_count);
-            println!("{}", table.0.simple_render());
-            for d in &board::ALL_DIRECTIONS {
-                println!("entry[{:?}] {}", d, table.1.rewards[*d]);
+        let mut histogram = histogram::Histogram::new();
+        for table in self.action_rewards.values() {
+            histogram.increment(table.read_count as u64).unwrap();
+        }
+        for p in &[1.0, 10.0, 50.0, 90.0, 99.0, 99.9, 99.99] {
+            if let Ok(reads) = histogram.percentile(*p) {
+                stats = stats.value(&format!("read_count_p{}", p), reads as f64);
             }
-            println!("------------------------\n");
         }
 
-        let top_table = self
-            .q_table
+        if let Some((key, table)) = self
             .action_rewards
             .iter()
-            .max_by(|(_, acts_x), (_, acts_y)| acts_x.read_count.cmp(&acts_y.read_count))
-            .unwrap();
-        println!("Top table [{} reads]", top_table.1.read_count);
-        println!("{}", top_table.0.simple_render());
-        for d in &board::ALL_DIRECTIONS {
-            println!("entry[{:?}] {}", d, top_table.1.rewards[*d]);
+            .max_by_key(|(_, table)| table.read_count)
+        {
+            let mut note = format!("{}\nnext {:?}", key.board.simple_render(), key.next_hint);
+            for d in &board::ALL_DIRECTIONS {
+                note.push_str(&format!("\nentry[{:?}] {}", d, table.rewards[*d]));
+            }
+            stats = stats
+                .value("top_read_count", table.read_count as f64)
+                .note("top_board", &note);
         }
+        stats
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::agent_runner;
+    use super::super::agent_trainer;
     use super::*;
+    use crate::agent_runner::Observation;
+    use crate::spawn::NextHint;
+
+    // What's seen of `board` with a 1 coming next
+    fn observe(board: &board::Board) -> Observation {
+        Observation {
+            board: *board,
+            next_hint: NextHint::Tile(1),
+        }
+    }
+
+    // A move which doesn't end the game
+    fn transition(
+        board: &board::Board,
+        action: Direction,
+        new_board: &board::Board,
+        reward: f64,
+    ) -> Transition {
+        Transition {
+            before: observe(board),
+            action,
+            after: observe(new_board),
+            reward,
+            terminal: false,
+        }
+    }
 
     #[test]
     fn test_basic_qtable() {
@@ -255,11 +824,38 @@ mod tests {
         let mut test_actions = crate::EnumMap::default();
         test_actions[Direction::Down] = 42.0;
         let reward_table = RewardTable::from_map(test_actions);
-        table.action_rewards.insert(empty_board, reward_table);
-        assert_eq!(table.max_action(&empty_board), (Direction::Down, 42.0));
+        let (key, _) = StateKey::of(&observe(&empty_board));
+        table.action_rewards.insert(key, reward_table);
+        assert_eq!(
+            table.max_action(&observe(&empty_board)),
+            (Direction::Down, 42.0)
+        );
         // table.action_rewards[&empty_board] = test_actions;
     }
 
+    #[test]
+    fn test_learns_each_next_tile_apart() {
+        let mut agent = QAgent::new(None, 1.0, 0.0, 0.1);
+        let board0 =
+            board::Board::from_rows(&[[1, 2, 0, 0], [0, 3, 0, 0], [0, 0, 0, 0], [6, 0, 0, 0]]);
+        let mut board1 = board0;
+        board1.shove(Direction::Left);
+        agent.update(&transition(&board0, Direction::Left, &board1, 1000.0));
+        assert_eq!(
+            agent.q_table.q_value(&observe(&board0), Direction::Left),
+            1000.0
+        );
+        // The same board with something else coming next hasn't been learned about
+        for next_hint in [NextHint::Tile(2), NextHint::Bonus { min: 4, max: 6 }] {
+            let state = Observation {
+                board: board0,
+                next_hint,
+            };
+            assert_eq!(agent.q_table.read_count(&state), 0);
+            assert_ne!(agent.q_table.q_value(&state, Direction::Left), 1000.0);
+        }
+    }
+
     #[test]
     fn test_basic_qagent() {
         let mut agent = QAgent::new(None, 0.5, 0.9, 0.1);
@@ -268,13 +864,330 @@ mod tests {
         board1.set_value(0, 0, 1);
         let reward = 100.0;
         let action = Direction::Up;
-        let old_reward = agent.q_table.get_reward_table(&board0).rewards[action];
-        agent.update(&board0, action, &board1, reward);
+        let old_reward = agent.q_table.q_value(&observe(&board0), action);
+        agent.update(&transition(&board0, action, &board1, reward));
         // Our updated reward in the q table is about 60
-        let new_reward = agent.q_table.get_reward_table(&board0).rewards[action];
+        let new_reward = agent.q_table.q_value(&observe(&board0), action);
         assert_ne!(old_reward, new_reward);
     }
 
+    #[test]
+    fn test_terminal_update() {
+        let mut agent = QAgent::new(None, 1.0, 0.9, 0.1);
+        let board0 = board::Board::new();
+        let mut board1 = board0;
+        board1.set_value(0, 0, 1);
+        // Nothing is worth anything after the game ends, whatever the table says
+        let mut last = transition(&board0, Direction::Up, &board1, 5.0);
+        last.terminal = true;
+        agent.update(&last);
+        assert_eq!(agent.q_table.q_value(&observe(&board0), Direction::Up), 5.0);
+        agent.update(&transition(&board0, Direction::Up, &board1, 5.0));
+        assert!(agent.q_table.q_value(&observe(&board0), Direction::Up) > 5.0);
+    }
+
+    #[test]
+    fn test_explain_and_stats() {
+        let mut agent = QAgent::new(None, 0.5, 0.9, 0.1);
+        let mut game = game::Game::new(Some(0), false);
+        game.update(Direction::Left);
+        let available = game.available_moves();
+        agent.update(&transition(
+            &game.cur_board,
+            available[0],
+            &game.cur_board,
+            1000.0,
+        ));
+
+        // The explanation holds the table's values, and how often the board had been read
+        let explanation = agent.explain(&game).unwrap();
+        assert_eq!(explanation.moves.len(), available.len());
+        for report in explanation.moves.iter() {
+            let value = agent
+                .q_table
+                .q_value(&observe(&game.cur_board), report.direction);
+            assert_eq!(report.value, Some(value));
+        }
+        assert!(explanation.details["read_count"] > 0.0);
+        assert_eq!(
+            agent.take_action(&game, false),
+            explanation
+                .moves
+                .iter()
+                .max_by(|l, r| l.value.partial_cmp(&r.value).unwrap())
+                .unwrap()
+                .direction
+        );
+
+        let stats = agent.stats();
+        assert_eq!(stats.values["entries"], agent.q_table.len() as f64);
+        assert!(stats.notes.contains_key("top_board"));
+        assert!(QTable::new().stats("q").notes.is_empty());
+    }
+
+    #[test]
+    fn test_learning_transfers_across_symmetries() {
+        let mut agent = QAgent::new(None, 0.5, 0.9, 0.1);
+        let board0 =
+            board::Board::from_rows(&[[1, 2, 0, 0], [0, 3, 0, 0], [0, 0, 0, 0], [6, 0, 0, 0]]);
+        let mut board1 = board0;
+        board1.shove(Direction::Left);
+        agent.update(&transition(&board0, Direction::Left, &board1, 1000.0));
+        let learned = agent.q_table.q_value(&observe(&board0), Direction::Left);
+        let num_entries = agent.q_table.len();
+
+        let mut rotated = board0;
+        let mut d = Direction::Left;
+        for _ in 0..3 {
+            rotated = rotated.rotate_clockwise();
+            d = d.rotate_clockwise();
+            assert_eq!(agent.q_table.q_value(&observe(&rotated), d), learned);
+            assert_eq!(agent.q_table.max_action(&observe(&rotated)), (d, learned));
+        }
+        let mirrored = board0.reflect_horizontal();
+        assert_eq!(
+            agent.q_table.q_value(&observe(&mirrored), Direction::Right),
+            learned
+        );
+        // None of the symmetric boards needed their own entries
+        assert_eq!(agent.q_table.len(), num_entries);
+    }
+
+    fn assert_same_tables(l: &QTable, r: &QTable) {
+        assert_eq!(l.len(), r.len());
+        for (board, table) in l.action_rewards.iter() {
+            let other = &r.action_rewards[board];
+            assert_eq!(table.rewards, other.rewards);
+            assert_eq!(table.read_count, other.read_count);
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut agent = QAgent::new(Some(&mut StdRng::seed_from_u64(0)), 0.5, 0.9, 0.1);
+        agent_runner::play_game(None, &mut agent, true);
+        // Classic games only show single tiles, so make sure bonus hints are saved too
+        let bonus = Observation {
+            board: board::Board::new(),
+            next_hint: NextHint::Bonus { min: 4, max: 6 },
+        };
+        agent.q_table.set_q_value(&bonus, Direction::Up, 7.0);
+        let table = agent.q_table();
+        assert!(!table.is_empty());
+
+        let mut json = Vec::new();
+        table.write_json(&mut json).unwrap();
+        assert_same_tables(table, &QTable::read_json(&mut json.as_slice()).unwrap());
+
+        let mut binary = Vec::new();
+        table.write_binary(&mut binary).unwrap();
+        assert_same_tables(table, &QTable::read_binary(&mut binary.as_slice()).unwrap());
+        // The binary format is much smaller than JSON
+        assert!(binary.len() * 2 < json.len());
+
+        // Files are written in whichever format their name asks for
+        let dir = std::env::temp_dir();
+        for name in &["threes_test_q_table.json", "threes_test_q_table.bin"] {
+            let path = dir.join(name);
+            table.save(&path).unwrap();
+            assert_same_tables(table, &QTable::load(&path).unwrap());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_rejects_bad_files() {
+        let table = QTable::new();
+        let mut binary = Vec::new();
+        table.write_binary(&mut binary).unwrap();
+        binary[4] += 1;
+        let err = QTable::read_binary(&mut binary.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(QTable::read_binary(&mut &b"not a table"[..]).is_err());
+        assert!(QTable::read_json(&mut &br#"{"version": 0, "entries": []}"#[..]).is_err());
+
+        // A block which isn't a rank
+        let mut table = QTable::new();
+        let state = Observation {
+            board: board::Board::new(),
+            next_hint: NextHint::Tile(1),
+        };
+        table.set_q_value(&state, Direction::Up, 1.0);
+        let mut binary = Vec::new();
+        table.write_binary(&mut binary).unwrap();
+        binary[16] = 0x1F;
+        let err = QTable::read_binary(&mut binary.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut json = Vec::new();
+        table.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap().replace("[0,", "[5,");
+        let err = QTable::read_json(&mut json.as_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_load_version_3() {
+        // One entry, with a 3 in the top left and a 48 in the bottom right of a u64 board
+        let mut binary = Vec::new();
+        binary.extend_from_slice(BINARY_MAGIC);
+        binary.extend_from_slice(&3_u32.to_le_bytes());
+        binary.extend_from_slice(&1_u64.to_le_bytes());
+        binary.extend_from_slice(&(3_u64 | 7 << 60).to_le_bytes());
+        binary.extend_from_slice(&hint_bytes(NextHint::Tile(2)));
+        for q in &[1.0_f64, 2.0, 3.0, 4.0] {
+            binary.extend_from_slice(&q.to_le_bytes());
+        }
+        binary.extend_from_slice(&5_i64.to_le_bytes());
+
+        let table = QTable::read_binary(&mut binary.as_slice()).unwrap();
+        let (key, rewards) = table.action_rewards.iter().next().unwrap();
+        assert_eq!(
+            key.board,
+            board::Board::from_rows(&[[3, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 48]])
+        );
+        assert_eq!(key.next_hint, NextHint::Tile(2));
+        assert_eq!(rewards.rewards[Direction::Up], 2.0);
+        assert_eq!(rewards.read_count, 5);
+    }
+
+    #[test]
+    fn test_shared_table_concurrent_updates() {
+        let table = Arc::new(SharedQTable::new(8));
+        let board0 =
+            board::Board::from_rows(&[[1, 2, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        let threads = (0..4)
+            .map(|i| {
+                let table = table.clone();
+                std::thread::spawn(move || {
+                    // The table itself, rather than the Arc which agents use as their QStore
+                    let table = &*table;
+                    for j in 0..500 {
+                        // Everyone learns about one board, and each thread about boards of its own
+                        table.learn(
+                            &observe(&board0.rotate_clockwise()),
+                            Direction::Up,
+                            100.0,
+                            0.1,
+                        );
+                        let mut own = board::Board::new();
+                        own.set_index(0, 0, 1 + i as u8);
+                        own.set_index(3, 3, 5 + (j % 10) as u8);
+                        table.learn(&observe(&own), Direction::Left, j as f64, 0.5);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let table = &*table;
+        // No update was lost, so the value ends up at the target
+        assert!((table.q_value(&observe(&board0), Direction::Left) - 100.0).abs() < 1e-6);
+        assert_eq!(
+            table.max_action(&observe(&board0.reflect_vertical())),
+            (
+                Direction::Left,
+                table.q_value(&observe(&board0), Direction::Left)
+            )
+        );
+
+        // Sharing a table out and gathering it back up loses nothing
+        let gathered = table.to_table();
+        assert_eq!(gathered.len(), 1 + 4 * 10);
+        assert_eq!(gathered.len(), table.len());
+        assert_same_tables(
+            &gathered,
+            &SharedQTable::from_table(table.to_table(), 3).to_table(),
+        );
+    }
+
+    #[test]
+    fn test_exploration() {
+        let mut config = agent_trainer::TrainConfig {
+            seed: 2,
+            num_generations: 3,
+            num_episodes_per_gen: 5,
+            learning_rate: 0.1,
+            explore_rate: 0.5,
+            explore_schedule: Schedule::Linear { final_rate: 0.0 },
+            ..Default::default()
+        };
+        let mut agent = QAgent::new(None, 0.1, 0.9, 0.5)
+            .with_exploration(config.exploration, config.explore_schedule);
+        agent_trainer::train_agent_from_scratch(&mut agent, &config);
+        // The last generation was trained without exploring
+        assert_eq!(agent.exploration().rate, 0.0);
+
+        config.exploration = Strategy::Boltzmann;
+        config.explore_rate = 20.0;
+        let mut agent = QAgent::new(None, 0.1, 0.9, 20.0)
+            .with_exploration(config.exploration, config.explore_schedule);
+        agent_trainer::train_agent_from_scratch(&mut agent, &config);
+        assert!(!agent.q_table().is_empty());
+
+        // Rarely seen boards are worth more than their rewards
+        let mut agent = QAgent::new(None, 1.0, 0.0, 10.0)
+            .with_exploration(Strategy::CountBonus, Schedule::Constant);
+        let board0 = board::Board::new();
+        let mut board1 = board0;
+        board1.set_value(0, 0, 1);
+        agent.update(&transition(&board0, Direction::Up, &board1, 5.0));
+        assert_eq!(
+            agent.q_table.q_value(&observe(&board0), Direction::Up),
+            15.0
+        );
+    }
+
+    #[test]
+    fn test_parallel_training_matches_serial() {
+        let config = agent_trainer::TrainConfig {
+            seed: 1,
+            num_generations: 10,
+            num_episodes_per_gen: 200,
+            learning_rate: 0.1,
+            num_workers: 4,
+            ..Default::default()
+        };
+        let shared = Arc::new(SharedQTable::new(64));
+        let mut agents = (0..config.num_workers)
+            .map(|_| QAgent::with_table(None, shared.clone(), 0.1, 0.9, 0.1))
+            .collect::<Vec<SharedQAgent>>();
+        let mut progress = agent_trainer::TrainProgress::new(&config);
+        agent_trainer::continue_training_in_parallel(&mut agents, &mut progress, |_, _| false);
+        assert!(progress.is_finished());
+        let mut parallel = shared.to_table();
+
+        let mut agent = QAgent::new(None, 0.1, 0.9, 0.1);
+        agent_trainer::train_agent_from_scratch(&mut agent, &config);
+        let serial = &mut agent.q_table;
+
+        // Both saw about as many boards, and learned about the same values for the common ones
+        let ratio = parallel.len() as f64 / serial.len() as f64;
+        assert!((0.9..1.1).contains(&ratio), "{}", ratio);
+        let mut common = serial
+            .action_rewards
+            .iter()
+            .map(|(key, table)| {
+                let state = Observation {
+                    board: key.board,
+                    next_hint: key.next_hint,
+                };
+                (state, table.read_count)
+            })
+            .collect::<Vec<(Observation, i64)>>();
+        common.sort_by_key(|(_, read_count)| -read_count);
+        for (state, _) in common.iter().take(50) {
+            let (_, learned) = serial.max_action(state);
+            let (_, parallel_learned) = parallel.max_action(state);
+            assert!(
+                (learned - parallel_learned).abs() < 0.05 * learned.abs(),
+                "{} {}",
+                learned,
+                parallel_learned
+            );
+        }
+    }
+
     #[test]
     fn test_agent_play() {
         let mut agent = QAgent::new(None, 0.5, 0.9, 0.1);
@@ -1,8 +1,9 @@
 use rand::prelude::*;
 
-use super::agent_runner::Agent;
+use super::agent_runner::{Agent, Transition};
 use super::board;
 use super::game;
+use super::introspect::Stats;
 use super::utils;
 
 pub struct RandomAgent {
@@ -26,15 +27,15 @@ impl Agent for RandomAgent {
         options[op_idx]
     }
 
-    fn update(
-        &mut self,
-        _board: &board::Board,
-        _action: board::Direction,
-        _new_board: &board::Board,
-        _reward: f64,
-    ) {
+    fn update(&mut self, _transition: &Transition) {
         // We don't learn
     }
 
-    fn print(&self) {}
+    fn stats(&self) -> Stats {
+        Stats::new("random")
+    }
+
+    fn reseed(&mut self, seed: &mut StdRng) {
+        self.rng = utils::resolve_rng_from_seed(Some(seed));
+    }
 }
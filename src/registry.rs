@@ -0,0 +1,606 @@
+use rand::prelude::*;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::agent_runner::Agent;
+use super::eval;
+use super::expectimax_agent;
+use super::mcts_agent;
+use super::ntuple_agent;
+use super::protocol;
+use super::q_agent;
+use super::random_agent;
+use super::rules;
+
+// Every kind of agent which can be picked by name
+pub const ALL_NAMES: [&str; 6] = ["random", "expectimax", "mcts", "external", "q", "ntuple"];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParamKind {
+    Int,
+    Float,
+    Text,
+    Choice(&'static [&'static str]),
+}
+
+impl fmt::Display for ParamKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamKind::Int => write!(f, "integer"),
+            ParamKind::Float => write!(f, "number"),
+            ParamKind::Text => write!(f, "text"),
+            ParamKind::Choice(choices) => write!(f, "{}", choices.join("|")),
+        }
+    }
+}
+
+// A setting an agent is made with
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub help: &'static str,
+    // Parameters without a default must be given, unless they are optional
+    pub default: Option<&'static str>,
+    pub optional: bool,
+    // The smallest value allowed, for numbers
+    pub min: Option<f64>,
+}
+
+impl Param {
+    fn check(&self, value: &str) -> Result<(), String> {
+        let number = match self.kind {
+            ParamKind::Int => value.parse::<u64>().ok().map(|n| n as f64),
+            ParamKind::Float => value.parse::<f64>().ok(),
+            ParamKind::Text => None,
+            ParamKind::Choice(_) => None,
+        };
+        let valid = match self.kind {
+            ParamKind::Int | ParamKind::Float => number.is_some(),
+            ParamKind::Text => true,
+            ParamKind::Choice(choices) => choices.contains(&value),
+        };
+        if !valid {
+            return Err(format!(
+                "{} is not a valid {} ({})",
+                value, self.name, self.kind
+            ));
+        }
+        match (number, self.min) {
+            (Some(number), Some(min)) if number < min => Err(format!(
+                "{} must be at least {}, not {}",
+                self.name, min, value
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// What a file an agent can be loaded from holds
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Model {
+    // The agent can't be loaded from a file
+    None,
+    // The agent starts from scratch if it isn't given one
+    Optional(&'static str),
+    Required(&'static str),
+}
+
+type MakeAgent = fn(
+    &Settings,
+    Option<&Path>,
+    Arc<dyn rules::RuleSet>,
+    &mut StdRng,
+) -> Result<Box<dyn Agent>, String>;
+
+pub struct AgentEntry {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub params: &'static [Param],
+    pub model: Model,
+    // Whether it's cheap to make a new agent for every game, so that games can be played at
+    // once. Agents loaded from files are made once and play their games one after another.
+    pub per_game: bool,
+    make: MakeAgent,
+}
+
+const fn param(
+    name: &'static str,
+    kind: ParamKind,
+    help: &'static str,
+    default: Option<&'static str>,
+) -> Param {
+    Param {
+        name,
+        kind,
+        help,
+        default,
+        optional: false,
+        min: None,
+    }
+}
+
+const fn at_least(min: f64, param: Param) -> Param {
+    Param {
+        min: Some(min),
+        ..param
+    }
+}
+
+const LEARNING_PARAMS: [Param; 3] = [
+    at_least(
+        0.0,
+        param(
+            "learning_rate",
+            ParamKind::Float,
+            "how far each update moves what was learned",
+            Some("0.5"),
+        ),
+    ),
+    at_least(
+        0.0,
+        param(
+            "discount_factor",
+            ParamKind::Float,
+            "how much later rewards are worth",
+            Some("0.9"),
+        ),
+    ),
+    at_least(
+        0.0,
+        param(
+            "explore_rate",
+            ParamKind::Float,
+            "the chance of a random move while training",
+            Some("0.1"),
+        ),
+    ),
+];
+
+const ENTRIES: [AgentEntry; 6] = [
+    AgentEntry {
+        name: "random",
+        about: "makes random moves",
+        params: &[],
+        model: Model::None,
+        per_game: true,
+        make: |_, _, _, rng| Ok(Box::new(random_agent::RandomAgent::new(Some(rng)))),
+    },
+    AgentEntry {
+        name: "expectimax",
+        about: "searches every move and tile a few moves ahead",
+        params: &[
+            at_least(
+                1.0,
+                param(
+                    "depth",
+                    ParamKind::Int,
+                    "how many moves to look ahead",
+                    Some("2"),
+                ),
+            ),
+            param(
+                "eval",
+                ParamKind::Choice(&["heuristic", "score"]),
+                "how the boards looked ahead to are scored",
+                Some("heuristic"),
+            ),
+        ],
+        model: Model::None,
+        per_game: true,
+        make: |settings, _, _, _| {
+            Ok(Box::new(expectimax_agent::ExpectimaxAgent::new(
+                settings.get("depth")?,
+                expectimax_agent::evaluator_by_name(&settings.get::<String>("eval")?).unwrap(),
+            )))
+        },
+    },
+    AgentEntry {
+        name: "mcts",
+        about: "simulates random games from each move",
+        params: &[
+            at_least(
+                1.0,
+                param(
+                    "iterations",
+                    ParamKind::Int,
+                    "how many games to simulate per move",
+                    Some("200"),
+                ),
+            ),
+            Param {
+                optional: true,
+                ..at_least(
+                    1.0,
+                    param(
+                        "time_ms",
+                        ParamKind::Int,
+                        "how long to search per move",
+                        None,
+                    ),
+                )
+            },
+        ],
+        model: Model::None,
+        per_game: true,
+        make: |settings, _, _, rng| {
+            let budget = mcts_agent::MctsBudget {
+                iterations: Some(settings.get("iterations")?),
+                time: settings.get_opt("time_ms")?.map(Duration::from_millis),
+            };
+            Ok(Box::new(mcts_agent::MctsAgent::new(Some(rng), budget)))
+        },
+    },
+    AgentEntry {
+        name: "external",
+        about: "a program speaking the JSON lines protocol, started for each game",
+        params: &[
+            param(
+                "command",
+                ParamKind::Text,
+                "the program to run, with its arguments separated by spaces",
+                None,
+            ),
+            at_least(
+                1.0,
+                param(
+                    "timeout_ms",
+                    ParamKind::Int,
+                    "how long the program may take to choose a move",
+                    Some("5000"),
+                ),
+            ),
+        ],
+        model: Model::None,
+        per_game: true,
+        make: |settings, _, rules, _| {
+            let command = settings.get::<String>("command")?;
+            let timeout = Duration::from_millis(settings.get("timeout_ms")?);
+            let mut words = command.split_whitespace();
+            let program = words
+                .next()
+                .ok_or_else(|| "the external agent command is empty".to_string())?;
+            let args = words.collect::<Vec<&str>>();
+            match protocol::ExternalAgent::spawn(program, &args, timeout, rules) {
+                Ok(agent) => Ok(Box::new(agent)),
+                Err(e) => Err(format!("could not start external agent {}: {}", command, e)),
+            }
+        },
+    },
+    AgentEntry {
+        name: "q",
+        about: "learns the value of each move on each board it sees",
+        params: &LEARNING_PARAMS,
+        model: Model::Optional("a saved q table"),
+        per_game: false,
+        make: |settings, model, _, rng| {
+            let mut agent = q_agent::QAgent::new(
+                Some(rng),
+                settings.get("learning_rate")?,
+                settings.get("discount_factor")?,
+                settings.get("explore_rate")?,
+            );
+            if let Some(path) = model {
+                agent.set_q_table(q_agent::QTable::load(path).map_err(|e| {
+                    format!("could not load q table from {}: {}", path.display(), e)
+                })?);
+            }
+            Ok(Box::new(agent))
+        },
+    },
+    AgentEntry {
+        name: "ntuple",
+        about: "learns the value of boards from patterns of tiles",
+        params: &[
+            param(
+                "network",
+                ParamKind::Choice(&ntuple_agent::ALL_NETWORK_NAMES),
+                "which tuples to look at, unless loaded from a file",
+                Some(ntuple_agent::ROWS),
+            ),
+            LEARNING_PARAMS[0],
+            at_least(
+                0.0,
+                param(
+                    "trace_decay",
+                    ParamKind::Float,
+                    "the TD(lambda) trace decay, below 1; 0 is TD(0)",
+                    Some("0.5"),
+                ),
+            ),
+            LEARNING_PARAMS[2],
+        ],
+        model: Model::Optional("a saved ntuple network"),
+        per_game: false,
+        make: |settings, model, rules, rng| {
+            let trace_decay = settings.get::<f64>("trace_decay")?;
+            if trace_decay >= 1.0 {
+                return Err(format!("trace_decay must be below 1, not {}", trace_decay));
+            }
+            let explore_rate = settings.get::<f64>("explore_rate")?;
+            if explore_rate > 1.0 {
+                return Err(format!(
+                    "explore_rate must be at most 1, not {}",
+                    explore_rate
+                ));
+            }
+            let network = match model {
+                Some(path) => ntuple_agent::NTupleNetwork::load(path).map_err(|e| {
+                    format!("could not load network from {}: {}", path.display(), e)
+                })?,
+                None => ntuple_agent::NTupleNetwork::by_name(&settings.get::<String>("network")?)
+                    .unwrap(),
+            };
+            Ok(Box::new(ntuple_agent::NTupleAgent::new(
+                Some(rng),
+                rules,
+                network,
+                settings.get("learning_rate")?,
+                trace_decay,
+                explore_rate,
+            )))
+        },
+    },
+];
+
+pub fn all() -> &'static [AgentEntry] {
+    &ENTRIES
+}
+
+pub fn by_name(name: &str) -> Option<&'static AgentEntry> {
+    ENTRIES.iter().find(|entry| entry.name == name)
+}
+
+// An agent as picked on the command line
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct AgentSpec {
+    pub name: String,
+    // (name, value) pairs, which override the parameters' defaults
+    pub params: Vec<(String, String)>,
+    pub model: Option<PathBuf>,
+}
+
+impl AgentSpec {
+    pub fn new(name: &str) -> AgentSpec {
+        AgentSpec {
+            name: name.to_string(),
+            ..AgentSpec::default()
+        }
+    }
+
+    // Add a parameter written as name=value
+    pub fn add_param(&mut self, param: &str) -> Result<(), String> {
+        let (name, value) = param
+            .split_once('=')
+            .ok_or_else(|| format!("agent parameter {} should look like name=value", param))?;
+        self.params.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+impl fmt::Display for AgentSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (name, value) in self.params.iter() {
+            write!(f, " {}={}", name, value)?;
+        }
+        if let Some(model) = self.model.as_ref() {
+            write!(f, " from {}", model.display())?;
+        }
+        Ok(())
+    }
+}
+
+// The parameters an agent is made with, falling back on their defaults
+pub struct Settings<'a> {
+    entry: &'a AgentEntry,
+    given: &'a [(String, String)],
+}
+
+impl Settings<'_> {
+    fn value(&self, name: &str) -> Option<&str> {
+        match self.given.iter().rev().find(|(n, _)| n == name) {
+            Some((_, value)) => Some(value),
+            None => self
+                .entry
+                .params
+                .iter()
+                .find(|p| p.name == name)
+                .and_then(|p| p.default),
+        }
+    }
+
+    pub fn get_opt<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name) {
+            Some(value) => match value.parse::<T>() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(format!("{} is not a valid {}", value, name)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn get<T: std::str::FromStr>(&self, name: &str) -> Result<T, String> {
+        self.get_opt(name)?
+            .ok_or_else(|| format!("agent {} needs {}", self.entry.name, name))
+    }
+}
+
+// Makes agents of one kind with the same settings, checked up front
+pub struct AgentFactory {
+    entry: &'static AgentEntry,
+    spec: AgentSpec,
+    rules: Arc<dyn rules::RuleSet>,
+}
+
+impl AgentFactory {
+    pub fn new(spec: &AgentSpec, rules: Arc<dyn rules::RuleSet>) -> Result<AgentFactory, String> {
+        let entry = by_name(&spec.name).ok_or_else(|| {
+            format!(
+                "unknown agent {}; the agents are {}",
+                spec.name,
+                ALL_NAMES.join(", ")
+            )
+        })?;
+        for (name, value) in spec.params.iter() {
+            match entry.params.iter().find(|p| p.name == name) {
+                Some(param) => param.check(value)?,
+                None => return Err(format!("agent {} has no parameter {}", entry.name, name)),
+            }
+        }
+        for param in entry.params.iter() {
+            if param.default.is_none()
+                && !param.optional
+                && !spec.params.iter().any(|(n, _)| n == param.name)
+            {
+                return Err(format!("agent {} needs {}", entry.name, param.name));
+            }
+        }
+        match (entry.model, spec.model.is_some()) {
+            (Model::None, true) => {
+                return Err(format!("agent {} can't be loaded from a file", entry.name))
+            }
+            (Model::Required(what), false) => {
+                return Err(format!("agent {} needs {}", entry.name, what))
+            }
+            _ => {}
+        }
+        Ok(AgentFactory {
+            entry,
+            spec: spec.clone(),
+            rules,
+        })
+    }
+
+    pub fn spec(&self) -> &AgentSpec {
+        &self.spec
+    }
+
+    pub fn rules(&self) -> Arc<dyn rules::RuleSet> {
+        self.rules.clone()
+    }
+
+    pub fn per_game(&self) -> bool {
+        self.entry.per_game
+    }
+
+    pub fn build(&self, rng: &mut StdRng) -> Result<Box<dyn Agent>, String> {
+        let settings = Settings {
+            entry: self.entry,
+            given: &self.spec.params,
+        };
+        (self.entry.make)(
+            &settings,
+            self.spec.model.as_deref(),
+            self.rules.clone(),
+            rng,
+        )
+    }
+
+    // Evaluate agents from this factory on `suite`, at once if they can be made for each game
+    pub fn evaluate(&self, suite: &eval::SeedSuite) -> Result<eval::Evaluation, String> {
+        if self.per_game() {
+            // Check the agent can be made before making one for every game
+            self.build(&mut StdRng::seed_from_u64(0))?;
+            eval::evaluate_in_parallel(
+                |rng| self.build(rng).unwrap_or_else(|e| panic!("{}", e)),
+                self.rules.clone(),
+                suite,
+            )
+        } else {
+            let mut agent = self.build(&mut StdRng::seed_from_u64(0))?;
+            eval::evaluate(&mut agent, self.rules.clone(), suite)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, params: &[&str]) -> AgentSpec {
+        let mut spec = AgentSpec::new(name);
+        for param in params {
+            spec.add_param(param).unwrap();
+        }
+        spec
+    }
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(
+            all().iter().map(|e| e.name).collect::<Vec<&str>>(),
+            ALL_NAMES.to_vec()
+        );
+        let mut rng = StdRng::seed_from_u64(0);
+        // Everything but the external agent can be made with its defaults
+        for name in ALL_NAMES.iter().filter(|n| **n != "external") {
+            let factory = AgentFactory::new(&AgentSpec::new(name), rules::classic()).unwrap();
+            assert!(factory.build(&mut rng).is_ok(), "could not make {}", name);
+        }
+    }
+
+    #[test]
+    fn test_bad_specs() {
+        let rules = rules::classic();
+        let check = |spec: &AgentSpec| AgentFactory::new(spec, rules.clone()).err();
+        assert!(check(&spec("expectimax", &["depth=3", "eval=score"])).is_none());
+        assert!(check(&spec("mcts", &["time_ms=10"])).is_none());
+        assert!(check(&spec("minimax", &[])).is_some());
+        assert!(check(&spec("expectimax", &["depth=deep"])).is_some());
+        assert!(check(&spec("expectimax", &["eval=vibes"])).is_some());
+        assert!(check(&spec("expectimax", &["width=3"])).is_some());
+        assert!(check(&spec("external", &[])).is_some());
+        // Numbers the agents can't work with are caught before any are made
+        assert!(check(&spec("expectimax", &["depth=0"])).is_some());
+        assert!(check(&spec("mcts", &["iterations=0"])).is_some());
+        assert!(check(&spec("mcts", &["time_ms=0"])).is_some());
+        assert!(check(&spec("q", &["learning_rate=-0.5"])).is_some());
+        let too_much = AgentFactory::new(&spec("ntuple", &["trace_decay=1"]), rules.clone());
+        assert!(too_much
+            .unwrap()
+            .build(&mut StdRng::seed_from_u64(0))
+            .is_err());
+        let mut loaded = AgentSpec::new("random");
+        loaded.model = Some(PathBuf::from("table.bin"));
+        assert!(check(&loaded).is_some());
+        assert!(AgentSpec::new("random").add_param("depth").is_err());
+    }
+
+    #[test]
+    fn test_settings() {
+        let factory = AgentFactory::new(
+            &spec("expectimax", &["depth=1", "depth=3"]),
+            rules::classic(),
+        )
+        .unwrap();
+        let settings = Settings {
+            entry: factory.entry,
+            given: &factory.spec.params,
+        };
+        // The last value given wins, and the rest are defaults
+        assert_eq!(settings.get::<usize>("depth"), Ok(3));
+        assert_eq!(settings.get::<String>("eval"), Ok("heuristic".to_string()));
+        assert_eq!(settings.get_opt::<u64>("time_ms"), Ok(None));
+        assert_eq!(factory.spec().to_string(), "expectimax depth=1 depth=3");
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let suite = eval::SeedSuite::by_name(eval::SMALL).unwrap();
+        let random = AgentFactory::new(&AgentSpec::new("random"), rules::classic()).unwrap();
+        let evaluation = random.evaluate(&suite).unwrap();
+        // The same as evaluating random agents directly
+        let direct = eval::evaluate_in_parallel(
+            |rng| random_agent::RandomAgent::new(Some(rng)),
+            rules::classic(),
+            &suite,
+        )
+        .unwrap();
+        assert_eq!(evaluation, direct);
+        let q = AgentFactory::new(&AgentSpec::new("q"), rules::classic()).unwrap();
+        assert_eq!(q.evaluate(&suite).unwrap().num_games, 20);
+    }
+}
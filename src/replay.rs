@@ -1,14 +1,17 @@
 use std::fs;
+use std::sync::Arc;
 
+use super::agent_runner::Agent;
 use super::agent_trainer::TrainingOutcomes;
 use super::game::{Game, GameLog, MoveResult};
+use super::rules;
 
 use std::io::{stdin, stdout, Write};
 use termion::event::Key;
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
-fn read_game(result_file: &str, gen_id: i32) -> GameLog {
+pub fn read_game(result_file: &str, gen_id: i32) -> GameLog {
     let contents = fs::read_to_string(result_file).unwrap();
     let outcomes: TrainingOutcomes = serde_json::from_str(&contents).unwrap();
     outcomes
@@ -20,14 +23,20 @@ fn read_game(result_file: &str, gen_id: i32) -> GameLog {
         .unwrap()
 }
 
-pub fn replay_game(result_file: &str, gen_id: i32) {
-    let log = read_game(result_file, gen_id);
-    interactive_step_game(log);
+// Step through a logged game, played with `rules`. If `agent` is given, each move is shown next to
+// the move the agent would have made instead, and how the agent rated the move that was played if
+// it can say.
+pub fn replay_game(log: GameLog, rules: Arc<dyn rules::RuleSet>, agent: Option<Box<dyn Agent>>) {
+    interactive_step_game(log, rules, agent);
 }
 
-fn interactive_step_game(log: GameLog) {
+fn interactive_step_game(
+    log: GameLog,
+    rules: Arc<dyn rules::RuleSet>,
+    mut agent: Option<Box<dyn Agent>>,
+) {
     let do_logging = false;
-    let mut game = Game::new(Some(log.seed), do_logging);
+    let mut game = Game::with_rules(Some(log.seed), do_logging, rules);
     let mut stdout = stdout().into_raw_mode().unwrap();
     let stdin = stdin();
     let stdin = stdin.lock();
@@ -67,6 +76,24 @@ fn interactive_step_game(log: GameLog) {
 
         let game_over = if advance {
             let direction = log.moves[move_index];
+            if let Some(agent) = agent.as_mut() {
+                let explanation = agent.explain(&game);
+                let choice = agent.take_action(&game, false);
+                let verdict = if choice == direction {
+                    "agrees"
+                } else {
+                    "disagrees"
+                };
+                write!(
+                    stdout,
+                    "played {:?}, agent {} and would play {:?}\r\n",
+                    direction, verdict, choice
+                )
+                .unwrap();
+                if let Some(explanation) = explanation {
+                    write!(stdout, "{}\r\n", explanation.describe(direction)).unwrap();
+                }
+            }
             move_index += 1;
             let move_result = game.update(direction);
             match move_result {
@@ -0,0 +1,329 @@
+use std::sync::{Arc, OnceLock};
+
+use super::board;
+use super::game::Score;
+use super::spawn::SpawnModel;
+
+// Everything which differs between variants of the game. Boards always hold rank indices; it's
+// up to the rule set to decide what each index means.
+pub trait RuleSet: Send + Sync {
+    // A unique name for these rules, recorded in game logs so games can be replayed
+    fn name(&self) -> &str;
+
+    // The index two blocks merge into, if they merge at all
+    fn combine(&self, in1: board::RankIndex, in2: board::RankIndex) -> Option<board::RankIndex>;
+
+    // Push the board in a certain direction, merging blocks according to combine.
+    // Returns true if the board was modified
+    fn shove(&self, board: &mut board::Board, d: board::Direction) -> bool;
+
+    // How the next tile is chosen
+    fn spawn_model(&self) -> SpawnModel;
+
+    // Where the new tile may be placed after `board` was shoved in direction `d`.
+    // One of these is chosen uniformly.
+    fn spawn_positions(&self, board: &board::Board, d: board::Direction) -> Vec<(usize, usize)>;
+
+    fn score_tile(&self, idx: board::RankIndex) -> Score;
+
+    fn score(&self, board: &board::Board) -> Score {
+        board.indices().iter().map(|i| self.score_tile(*i)).sum()
+    }
+
+    // The number shown on a tile
+    fn rank(&self, idx: board::RankIndex) -> board::Rank;
+
+    // The index of the largest tile these rules have
+    fn max_index(&self) -> board::RankIndex {
+        (board::NUM_RANKS - 1) as board::RankIndex
+    }
+}
+
+pub const CLASSIC: &str = "classic";
+pub const THREES: &str = "threes";
+pub const TWENTY_FORTY_EIGHT: &str = "2048";
+pub const ALL_NAMES: [&str; 3] = [CLASSIC, THREES, TWENTY_FORTY_EIGHT];
+
+// Games which were logged before there were rule sets used the classic rules
+pub fn default_name() -> String {
+    CLASSIC.to_string()
+}
+
+pub fn by_name(name: &str) -> Option<Arc<dyn RuleSet>> {
+    match name {
+        CLASSIC => Some(Arc::new(ThreesRules::classic())),
+        THREES => Some(Arc::new(ThreesRules::authentic())),
+        TWENTY_FORTY_EIGHT => Some(Arc::new(TwentyFortyEight::new())),
+        _ => None,
+    }
+}
+
+pub fn classic() -> Arc<dyn RuleSet> {
+    by_name(CLASSIC).unwrap()
+}
+
+// The index of every block on the edge opposite `d` which is free for a new tile
+fn open_edge_positions(board: &board::Board, d: board::Direction) -> Vec<(usize, usize)> {
+    let last = board::WIDTH - 1;
+    (0..board::WIDTH)
+        .map(|i| match d {
+            board::Direction::Down => (0, i),
+            board::Direction::Up => (last, i),
+            board::Direction::Left => (i, last),
+            board::Direction::Right => (i, 0),
+        })
+        .filter(|(row, col)| board.get_index(*row, *col) == 0)
+        .collect()
+}
+
+// Threes merging, movement and scoring. The classic rules are how this engine has always
+// played: only 1s and 2s are spawned. The authentic rules use the real Threes deck.
+pub struct ThreesRules {
+    spawn_model: SpawnModel,
+}
+
+impl ThreesRules {
+    pub fn classic() -> ThreesRules {
+        ThreesRules {
+            spawn_model: SpawnModel::Parity,
+        }
+    }
+
+    pub fn authentic() -> ThreesRules {
+        ThreesRules {
+            spawn_model: SpawnModel::Deck,
+        }
+    }
+}
+
+impl RuleSet for ThreesRules {
+    fn name(&self) -> &str {
+        match self.spawn_model {
+            SpawnModel::Deck => THREES,
+            _ => CLASSIC,
+        }
+    }
+
+    fn combine(&self, in1: board::RankIndex, in2: board::RankIndex) -> Option<board::RankIndex> {
+        board::combine(board::index_to_rank(in1), board::index_to_rank(in2))
+            .map(board::rank_to_index)
+    }
+
+    fn shove(&self, board: &mut board::Board, d: board::Direction) -> bool {
+        board.shove(d)
+    }
+
+    fn spawn_model(&self) -> SpawnModel {
+        self.spawn_model
+    }
+
+    fn spawn_positions(&self, board: &board::Board, d: board::Direction) -> Vec<(usize, usize)> {
+        open_edge_positions(board, d)
+    }
+
+    // 1s and 2s are worth nothing, 3 is worth 3 and every doubling after that triples the score
+    fn score_tile(&self, idx: board::RankIndex) -> Score {
+        if idx < 3 {
+            0
+        } else {
+            3_i64.pow(idx as u32 - 2)
+        }
+    }
+
+    fn rank(&self, idx: board::RankIndex) -> board::Rank {
+        board::index_to_rank(idx)
+    }
+}
+
+// The index of 32768, the largest 2048 tile, which doesn't combine any further
+const MAX_TWENTY_FORTY_EIGHT_INDEX: board::RankIndex = 15;
+
+// Powers of two which slide as far as they can, spawning 2s and 4s anywhere
+pub struct TwentyFortyEight {
+    tables: OnceLock<board::RowTables>,
+}
+
+impl TwentyFortyEight {
+    pub fn new() -> TwentyFortyEight {
+        TwentyFortyEight {
+            tables: OnceLock::new(),
+        }
+    }
+
+    // Slide every block towards index 0, merging each pair of equal blocks at most once
+    fn shift_down(&self, in_sec: &board::IndexSection) -> board::IndexSection {
+        let mut out_sec = [0; board::WIDTH];
+        let mut out_x = 0;
+        let mut can_merge = false;
+        for idx in in_sec.iter().filter(|idx| **idx != 0) {
+            if can_merge {
+                if let Some(new_idx) = self.combine(out_sec[out_x - 1], *idx) {
+                    out_sec[out_x - 1] = new_idx;
+                    can_merge = false;
+                    continue;
+                }
+            }
+            out_sec[out_x] = *idx;
+            out_x += 1;
+            can_merge = true;
+        }
+        out_sec
+    }
+}
+
+impl Default for TwentyFortyEight {
+    fn default() -> TwentyFortyEight {
+        TwentyFortyEight::new()
+    }
+}
+
+impl RuleSet for TwentyFortyEight {
+    fn name(&self) -> &str {
+        TWENTY_FORTY_EIGHT
+    }
+
+    fn combine(&self, in1: board::RankIndex, in2: board::RankIndex) -> Option<board::RankIndex> {
+        if in1 == 0 || in1 != in2 || in1 == self.max_index() {
+            None
+        } else {
+            Some(in1 + 1)
+        }
+    }
+
+    fn shove(&self, board: &mut board::Board, d: board::Direction) -> bool {
+        let tables = self
+            .tables
+            .get_or_init(|| board::RowTables::from_shift_down(|sec| self.shift_down(sec)));
+        board.shove_with(tables, d)
+    }
+
+    fn spawn_model(&self) -> SpawnModel {
+        SpawnModel::TwoFour
+    }
+
+    fn spawn_positions(&self, board: &board::Board, _d: board::Direction) -> Vec<(usize, usize)> {
+        board
+            .indices()
+            .iter()
+            .enumerate()
+            .filter(|(_, idx)| **idx == 0)
+            .map(|(i, _)| (i / board::WIDTH, i % board::WIDTH))
+            .collect()
+    }
+
+    // Roughly the points earned from all of the merges needed to build the tile
+    fn score_tile(&self, idx: board::RankIndex) -> Score {
+        if idx < 2 {
+            0
+        } else {
+            (idx as i64 - 1) << idx
+        }
+    }
+
+    fn max_index(&self) -> board::RankIndex {
+        MAX_TWENTY_FORTY_EIGHT_INDEX
+    }
+
+    fn rank(&self, idx: board::RankIndex) -> board::Rank {
+        if idx == 0 {
+            0
+        } else {
+            1 << idx
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_indices(rows: &[board::IndexSection; board::WIDTH]) -> board::Board {
+        let mut board = board::Board::new();
+        for (r, row) in rows.iter().enumerate() {
+            for (c, idx) in row.iter().enumerate() {
+                board.set_index(r, c, *idx);
+            }
+        }
+        board
+    }
+
+    #[test]
+    fn test_names() {
+        for name in &ALL_NAMES {
+            assert_eq!(by_name(name).unwrap().name(), *name);
+        }
+        assert!(by_name("checkers").is_none());
+    }
+
+    #[test]
+    fn test_threes_combine() {
+        let rules = ThreesRules::classic();
+        assert_eq!(rules.combine(1, 2), Some(3));
+        assert_eq!(rules.combine(2, 2), None);
+        assert_eq!(rules.combine(3, 3), Some(4));
+        assert_eq!(rules.combine(3, 4), None);
+        assert_eq!(rules.combine(0, 3), None);
+    }
+
+    #[test]
+    fn test_threes_spawn_positions() {
+        let rules = ThreesRules::classic();
+        let board =
+            board::Board::from_rows(&[[1, 0, 0, 3], [0, 0, 0, 0], [0, 0, 0, 2], [0, 6, 0, 0]]);
+        assert_eq!(
+            rules.spawn_positions(&board, board::Direction::Down),
+            vec![(0, 1), (0, 2)]
+        );
+        assert_eq!(
+            rules.spawn_positions(&board, board::Direction::Up),
+            vec![(3, 0), (3, 2), (3, 3)]
+        );
+        assert_eq!(
+            rules.spawn_positions(&board, board::Direction::Left),
+            vec![(1, 3), (3, 3)]
+        );
+        assert_eq!(
+            rules.spawn_positions(&board, board::Direction::Right),
+            vec![(1, 0), (2, 0), (3, 0)]
+        );
+    }
+
+    #[test]
+    fn test_2048_shove() {
+        let rules = TwentyFortyEight::new();
+        assert_eq!(rules.shift_down(&[1, 1, 1, 1]), [2, 2, 0, 0]);
+        assert_eq!(rules.shift_down(&[0, 1, 0, 1]), [2, 0, 0, 0]);
+        assert_eq!(rules.shift_down(&[2, 1, 1, 0]), [2, 2, 0, 0]);
+        assert_eq!(rules.shift_down(&[1, 2, 3, 4]), [1, 2, 3, 4]);
+        assert_eq!(rules.shift_down(&[0, 0, 0, 3]), [3, 0, 0, 0]);
+
+        let mut board = from_indices(&[[1, 1, 0, 2], [0, 0, 0, 0], [0, 0, 0, 3], [0, 0, 0, 3]]);
+        assert!(rules.shove(&mut board, board::Direction::Right));
+        assert_eq!(
+            board,
+            from_indices(&[[0, 0, 2, 2], [0, 0, 0, 0], [0, 0, 0, 3], [0, 0, 0, 3]])
+        );
+        assert!(rules.shove(&mut board, board::Direction::Down));
+        assert_eq!(
+            board,
+            from_indices(&[[0, 0, 0, 0], [0, 0, 0, 0], [0, 0, 0, 2], [0, 0, 2, 4]])
+        );
+        assert!(!rules.shove(&mut board, board::Direction::Down));
+    }
+
+    #[test]
+    fn test_2048_scoring() {
+        let rules = TwentyFortyEight::new();
+        assert_eq!(rules.rank(1), 2);
+        assert_eq!(rules.rank(11), 2048);
+        assert_eq!(rules.score_tile(1), 0);
+        assert_eq!(rules.score_tile(2), 4);
+        assert_eq!(rules.score_tile(3), 16);
+        assert_eq!(
+            rules
+                .spawn_positions(&board::Board::new(), board::Direction::Up)
+                .len(),
+            board::WIDTH * board::WIDTH
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::board::Direction;
+use super::game;
+use super::protocol::{write_message, ProtocolError, StateMessage};
+use super::rules;
+
+// The wire protocol is one JSON object per line, like the stdio protocol. Each request gets
+// exactly one response. Games are shared by every connection, so a game can be created by one
+// client and played by another. Games nobody has touched for a while are forgotten, and only the
+// most recent logs are kept in memory; use a log file to keep every one.
+
+// How many finished games' logs are kept in memory
+const MAX_KEPT_LOGS: usize = 1000;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    // Start a game. The rules default to the classic ones.
+    NewGame { seed: u64, rules: Option<String> },
+    Move { game_id: u64, direction: Direction },
+    State { game_id: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    State {
+        game_id: u64,
+        state: StateMessage,
+    },
+    // The game is finished and has been forgotten; its log has been recorded
+    GameOver {
+        game_id: u64,
+        result: game::GameResult,
+    },
+    Error {
+        message: String,
+    },
+}
+
+struct Session {
+    game: game::Game,
+    last_used: Instant,
+}
+
+struct Sessions {
+    next_id: u64,
+    games: HashMap<u64, Session>,
+    // Games idle for longer than this are dropped
+    idle_timeout: Duration,
+    logs: VecDeque<game::GameLog>,
+    // Every finished game's log is also appended here as a line of JSON
+    log_file: Option<File>,
+}
+
+impl Sessions {
+    fn new() -> Sessions {
+        Sessions {
+            next_id: 0,
+            games: HashMap::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            logs: VecDeque::new(),
+            log_file: None,
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        self.games
+            .retain(|_, session| now.duration_since(session.last_used) < idle_timeout);
+        match request {
+            Request::NewGame { seed, rules } => {
+                let rules_name = rules.unwrap_or_else(rules::default_name);
+                let rules = match rules::by_name(&rules_name) {
+                    Some(rules) => rules,
+                    None => return error(format!("unknown rules {}", rules_name)),
+                };
+                let game_id = self.next_id;
+                self.next_id += 1;
+                let game = game::Game::with_rules(Some(seed), true, rules);
+                let state = StateMessage::from_game(&game);
+                self.games.insert(
+                    game_id,
+                    Session {
+                        game,
+                        last_used: now,
+                    },
+                );
+                Response::State { game_id, state }
+            }
+            Request::Move { game_id, direction } => {
+                let session = match self.games.get_mut(&game_id) {
+                    Some(session) => session,
+                    None => return error(format!("no game {}", game_id)),
+                };
+                session.last_used = now;
+                let game = &mut session.game;
+                match game.update(direction) {
+                    game::MoveResult::Failed => {
+                        error(ProtocolError::IllegalMove(direction).to_string())
+                    }
+                    game::MoveResult::Moved(None) => Response::State {
+                        game_id,
+                        state: StateMessage::from_game(game),
+                    },
+                    game::MoveResult::Moved(Some(result)) => {
+                        self.games.remove(&game_id);
+                        if let Some(log) = result.log.clone() {
+                            self.record(log);
+                        }
+                        Response::GameOver { game_id, result }
+                    }
+                }
+            }
+            Request::State { game_id } => match self.games.get_mut(&game_id) {
+                Some(session) => {
+                    session.last_used = now;
+                    Response::State {
+                        game_id,
+                        state: StateMessage::from_game(&session.game),
+                    }
+                }
+                None => error(format!("no game {}", game_id)),
+            },
+        }
+    }
+
+    fn record(&mut self, log: game::GameLog) {
+        if let Some(file) = self.log_file.as_mut() {
+            let line = serde_json::to_string(&log).unwrap();
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("could not record game log: {}", e);
+            }
+        }
+        if self.logs.len() == MAX_KEPT_LOGS {
+            self.logs.pop_front();
+        }
+        self.logs.push_back(log);
+    }
+}
+
+fn error(message: String) -> Response {
+    Response::Error { message }
+}
+
+// Hosts games for any number of clients over TCP
+pub struct Server {
+    listener: TcpListener,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            sessions: Arc::new(Mutex::new(Sessions::new())),
+        })
+    }
+
+    // Also append the log of every finished game to the file at `path`
+    pub fn with_log_file(self, path: &str) -> io::Result<Server> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.sessions.lock().unwrap().log_file = Some(file);
+        Ok(self)
+    }
+
+    // Forget games nobody has moved or looked at for `timeout`, instead of after 30 minutes
+    pub fn with_idle_timeout(self, timeout: Duration) -> Server {
+        self.sessions.lock().unwrap().idle_timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The logs of the most recently finished games, oldest first
+    pub fn logs(&self) -> Vec<game::GameLog> {
+        self.sessions.lock().unwrap().logs.iter().cloned().collect()
+    }
+
+    // Accept connections forever, serving each on its own thread
+    pub fn run(&self) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sessions = self.sessions.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve_connection(stream, sessions) {
+                            eprintln!("connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("could not accept connection: {}", e),
+            }
+        }
+    }
+}
+
+fn serve_connection(stream: TcpStream, sessions: Arc<Mutex<Sessions>>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let response = match serde_json::from_str::<Request>(line.trim()) {
+            Ok(request) => sessions.lock().unwrap().handle(request),
+            Err(_) => error(ProtocolError::BadMessage(line).to_string()),
+        };
+        if let Err(ProtocolError::Io(e)) = write_message(&mut writer, &response) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// What a move led to
+#[derive(Debug)]
+pub enum MoveOutcome {
+    Playing(StateMessage),
+    GameOver(game::GameResult),
+}
+
+// Plays games on a Server
+pub struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Client> {
+        let writer = TcpStream::connect(addr)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Client { writer, reader })
+    }
+
+    // Start a game, getting back its id and first state
+    pub fn new_game(
+        &mut self,
+        seed: u64,
+        rules: Option<&str>,
+    ) -> Result<(u64, StateMessage), ProtocolError> {
+        let request = Request::NewGame {
+            seed,
+            rules: rules.map(|r| r.to_string()),
+        };
+        match self.request(&request)? {
+            Response::State { game_id, state } => Ok((game_id, state)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn make_move(
+        &mut self,
+        game_id: u64,
+        direction: Direction,
+    ) -> Result<MoveOutcome, ProtocolError> {
+        match self.request(&Request::Move { game_id, direction })? {
+            Response::State { state, .. } => Ok(MoveOutcome::Playing(state)),
+            Response::GameOver { result, .. } => Ok(MoveOutcome::GameOver(result)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn state(&mut self, game_id: u64) -> Result<StateMessage, ProtocolError> {
+        match self.request(&Request::State { game_id })? {
+            Response::State { state, .. } => Ok(state),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response, ProtocolError> {
+        write_message(&mut self.writer, request)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(ProtocolError::Closed);
+        }
+        match serde_json::from_str::<Response>(line.trim()) {
+            Ok(Response::Error { message }) => Err(ProtocolError::Rejected(message)),
+            Ok(response) => Ok(response),
+            Err(_) => Err(ProtocolError::BadMessage(line)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> ProtocolError {
+    ProtocolError::BadMessage(format!("{:?}", response))
+}
@@ -0,0 +1,489 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::board;
+use super::game::RngType;
+
+// Tiles are all rank indices. For Threes, indices 1, 2 and 3 are the ranks 1, 2 and 3.
+
+// The cards in a fresh Threes deck
+const DECK: [board::RankIndex; 12] = [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3];
+// Bonus tiles only show up once the highest tile on the board has reached 48
+const BONUS_THRESHOLD: board::RankIndex = 7;
+// Once bonus tiles are unlocked, 1 in this many tiles is a bonus tile
+const BONUS_ODDS: u32 = 21;
+// Bonus tiles go from 6 up to 1/8th of the highest tile
+const MIN_BONUS_INDEX: board::RankIndex = 4;
+const BONUS_OFFSET: board::RankIndex = 3;
+// In 2048, 1 in this many tiles is a 4 rather than a 2
+const FOUR_ODDS: u32 = 10;
+// Far further from balanced than any game's 1s and 2s get
+const MAX_PARITY: i32 = 1_000_000;
+
+// How new tiles are chosen
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+pub enum SpawnModel {
+    // Only 1s and 2s, biased towards keeping their counts balanced. Games logged before there
+    // was a choice of spawn model used this.
+    #[default]
+    Parity,
+    // The real Threes deck of four 1s, 2s and 3s, with bonus tiles late in the game
+    Deck,
+    // 2048's 2s (index 1) and occasional 4s (index 2)
+    TwoFour,
+}
+
+impl std::str::FromStr for SpawnModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SpawnModel, String> {
+        match s {
+            "parity" => Ok(SpawnModel::Parity),
+            "deck" => Ok(SpawnModel::Deck),
+            "two_four" => Ok(SpawnModel::TwoFour),
+            _ => Err(format!("unknown spawn model {}", s)),
+        }
+    }
+}
+
+// What the player is shown about the next tile
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NextHint {
+    Tile(board::RankIndex),
+    // A bonus tile is one of the rank indices in this (inclusive) range
+    Bonus {
+        min: board::RankIndex,
+        max: board::RankIndex,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Spawner {
+    model: SpawnModel,
+    // (count of 2s - count of 1s) used to bias the RNG towards balanced 1s and 2s
+    parity: i32,
+    // The cards left in the deck; the top of the deck is the end
+    deck: Vec<board::RankIndex>,
+}
+
+impl Spawner {
+    pub fn new(model: SpawnModel) -> Spawner {
+        Spawner {
+            model,
+            parity: 0,
+            deck: Vec::new(),
+        }
+    }
+
+    pub fn model(&self) -> SpawnModel {
+        self.model
+    }
+
+    // Make sure the spawner is in a state it could have reached, and could have chosen
+    // `next_rank` shown as `next_hint`, so that restored games can't draw tiles which don't exist
+    pub fn check(&self, next_rank: board::RankIndex, next_hint: NextHint) -> Result<(), String> {
+        if self.parity.abs() > MAX_PARITY || (self.model != SpawnModel::Parity && self.parity != 0)
+        {
+            return Err(format!("impossible parity {}", self.parity));
+        }
+        let mut counts = [0; 3];
+        for card in self.deck.iter() {
+            match card {
+                1..=3 if self.model == SpawnModel::Deck => counts[*card as usize - 1] += 1,
+                _ => return Err(format!("impossible card {} in the deck", card)),
+            }
+        }
+        if counts.iter().any(|count| *count > 4) {
+            return Err("the deck has too many of a card".to_string());
+        }
+        let possible = match (self.model, next_hint) {
+            (SpawnModel::Deck, NextHint::Tile(idx)) => (1..=3).contains(&idx) && idx == next_rank,
+            (SpawnModel::Deck, NextHint::Bonus { min, max }) => {
+                min == MIN_BONUS_INDEX
+                    && (min..=max).contains(&next_rank)
+                    && ((max + BONUS_OFFSET) as usize) < board::NUM_RANKS
+            }
+            (_, NextHint::Tile(idx)) => (1..=2).contains(&idx) && idx == next_rank,
+            (_, NextHint::Bonus { .. }) => false,
+        };
+        if possible {
+            Ok(())
+        } else {
+            Err(format!(
+                "the next tile {} shown as {:?} can't be drawn",
+                next_rank, next_hint
+            ))
+        }
+    }
+
+    // Choose the very first tile of the game
+    pub fn first(&mut self, rng: &mut RngType) -> (board::RankIndex, NextHint) {
+        match self.model {
+            SpawnModel::Parity => {
+                // Drawn as a Rank, as games always have been, so that seeds replay the same games
+                let rank: board::Rank = rng.gen_range(1..=2);
+                let idx = rank as board::RankIndex;
+                (idx, NextHint::Tile(idx))
+            }
+            SpawnModel::Deck => self.draw_card(&board::Board::new(), rng),
+            SpawnModel::TwoFour => Self::draw_two_four(rng),
+        }
+    }
+
+    // Choose the tile after `placed`, which has just been put onto `board`
+    pub fn next(
+        &mut self,
+        placed: board::RankIndex,
+        board: &board::Board,
+        rng: &mut RngType,
+    ) -> (board::RankIndex, NextHint) {
+        match self.model {
+            SpawnModel::Parity => {
+                let range = 100;
+                let multiplier = 3;
+                let threshold = 50 + multiplier * self.parity;
+                let rank = if rng.gen_range(0..range) > threshold {
+                    2
+                } else {
+                    1
+                };
+                if placed == 2 {
+                    self.parity += 1;
+                } else if placed == 1 {
+                    self.parity -= 1;
+                }
+                (rank, NextHint::Tile(rank))
+            }
+            SpawnModel::Deck => self.draw_card(board, rng),
+            SpawnModel::TwoFour => Self::draw_two_four(rng),
+        }
+    }
+
+    // Every tile which `next` could choose, with its probability
+    pub fn distribution(&self, board: &board::Board) -> Vec<(board::RankIndex, NextHint, f64)> {
+        match self.model {
+            SpawnModel::Parity => {
+                let threshold = 50 + 3 * self.parity;
+                // How many of the 100 possible rolls are above the threshold
+                let num_twos = (99 - threshold).clamp(0, 100);
+                let p_two = num_twos as f64 / 100.0;
+                vec![
+                    (1, NextHint::Tile(1), 1.0 - p_two),
+                    (2, NextHint::Tile(2), p_two),
+                ]
+                .into_iter()
+                .filter(|(_, _, p)| *p > 0.0)
+                .collect()
+            }
+            SpawnModel::Deck => {
+                let highest = *board.indices().iter().max().unwrap();
+                let mut outcomes = Vec::new();
+                let mut p_card = 1.0;
+                if highest >= BONUS_THRESHOLD {
+                    let p_bonus = 1.0 / BONUS_ODDS as f64;
+                    p_card -= p_bonus;
+                    let max = highest - BONUS_OFFSET;
+                    let hint = NextHint::Bonus {
+                        min: MIN_BONUS_INDEX,
+                        max,
+                    };
+                    let num_bonus = (max - MIN_BONUS_INDEX + 1) as f64;
+                    for rank in MIN_BONUS_INDEX..=max {
+                        outcomes.push((rank, hint, p_bonus / num_bonus));
+                    }
+                }
+                let deck: &[board::RankIndex] = if self.deck.is_empty() {
+                    &DECK
+                } else {
+                    &self.deck
+                };
+                for rank in 1..=3 {
+                    let count = deck.iter().filter(|r| **r == rank).count();
+                    if count > 0 {
+                        let p = p_card * count as f64 / deck.len() as f64;
+                        outcomes.push((rank, NextHint::Tile(rank), p));
+                    }
+                }
+                outcomes
+            }
+            SpawnModel::TwoFour => {
+                let p_four = 1.0 / FOUR_ODDS as f64;
+                vec![
+                    (1, NextHint::Tile(1), 1.0 - p_four),
+                    (2, NextHint::Tile(2), p_four),
+                ]
+            }
+        }
+    }
+
+    // Everything about the spawner which `distribution` depends on, besides the board: the
+    // parity, or how many of each card are left in the deck. Spawners with the same state give
+    // the same chances for every tile after.
+    pub fn chance_state(&self) -> (i32, [u8; 3]) {
+        let mut counts = [0; 3];
+        if self.model == SpawnModel::Deck {
+            // An empty deck is about to be refilled
+            let deck: &[board::RankIndex] = if self.deck.is_empty() {
+                &DECK
+            } else {
+                &self.deck
+            };
+            for card in deck {
+                counts[*card as usize - 1] += 1;
+            }
+        }
+        (self.parity, counts)
+    }
+
+    // Forget the order of the cards left in the deck, which the player can't know
+    pub fn shuffle(&mut self, rng: &mut RngType) {
+        self.deck.shuffle(rng);
+    }
+
+    // Update the spawner as if `next` had chosen `drawn` after `placed`. This is for exploring
+    // hypothetical games; the order of the remaining deck is not kept.
+    pub fn advance(&mut self, placed: board::RankIndex, drawn: board::RankIndex, hint: NextHint) {
+        match self.model {
+            SpawnModel::Parity => {
+                if placed == 2 {
+                    self.parity += 1;
+                } else if placed == 1 {
+                    self.parity -= 1;
+                }
+            }
+            SpawnModel::Deck => {
+                if let NextHint::Tile(_) = hint {
+                    if self.deck.is_empty() {
+                        self.deck.extend_from_slice(&DECK);
+                    }
+                    let pos = self.deck.iter().position(|r| *r == drawn).unwrap();
+                    self.deck.remove(pos);
+                }
+            }
+            SpawnModel::TwoFour => {}
+        }
+    }
+
+    fn draw_card(
+        &mut self,
+        board: &board::Board,
+        rng: &mut RngType,
+    ) -> (board::RankIndex, NextHint) {
+        let highest = *board.indices().iter().max().unwrap();
+        if highest >= BONUS_THRESHOLD && rng.gen_range(0..BONUS_ODDS) == 0 {
+            let max = highest - BONUS_OFFSET;
+            let rank = rng.gen_range(MIN_BONUS_INDEX..=max);
+            return (
+                rank,
+                NextHint::Bonus {
+                    min: MIN_BONUS_INDEX,
+                    max,
+                },
+            );
+        }
+        if self.deck.is_empty() {
+            self.deck.extend_from_slice(&DECK);
+            self.deck.shuffle(rng);
+        }
+        let rank = self.deck.pop().unwrap();
+        (rank, NextHint::Tile(rank))
+    }
+
+    fn draw_two_four(rng: &mut RngType) -> (board::RankIndex, NextHint) {
+        let rank = if rng.gen_range(0..FOUR_ODDS) == 0 {
+            2
+        } else {
+            1
+        };
+        (rank, NextHint::Tile(rank))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_xoshiro::rand_core::SeedableRng;
+
+    #[test]
+    fn test_parity_matches_original() {
+        let mut rng = RngType::seed_from_u64(7);
+        let mut original_rng = rng.clone();
+        let board = board::Board::new();
+        let mut spawner = Spawner::new(SpawnModel::Parity);
+
+        let (mut rank, _) = spawner.first(&mut rng);
+        // Drawing a RankIndex instead would use the random number generator differently
+        let mut original_rank: board::Rank = original_rng.gen_range(1..=2);
+        let mut original_parity = 0;
+        for _ in 0..1000 {
+            assert_eq!(rank as board::Rank, original_rank);
+            let placed = rank;
+            rank = spawner.next(placed, &board, &mut rng).0;
+
+            let threshold = 50 + 3 * original_parity;
+            let original_placed = original_rank;
+            original_rank = if original_rng.gen_range(0..100) > threshold {
+                2
+            } else {
+                1
+            };
+            if original_placed == 2 {
+                original_parity += 1;
+            } else if original_placed == 1 {
+                original_parity -= 1;
+            }
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mut rng = RngType::seed_from_u64(5);
+        let board = board::Board::from_rows(&[[48, 0, 0, 0], [0; 4], [0; 4], [0; 4]]);
+        for model in &[SpawnModel::Parity, SpawnModel::Deck, SpawnModel::TwoFour] {
+            let mut spawner = Spawner::new(*model);
+            let (mut rank, mut hint) = spawner.first(&mut rng);
+            for _ in 0..100 {
+                assert_eq!(spawner.check(rank, hint), Ok(()));
+                let next = spawner.next(rank, &board, &mut rng);
+                rank = next.0;
+                hint = next.1;
+            }
+        }
+
+        let spawner = Spawner::new(SpawnModel::Parity);
+        assert!(spawner.check(3, NextHint::Tile(3)).is_err());
+        assert!(spawner.check(1, NextHint::Tile(2)).is_err());
+        let spawner = Spawner {
+            model: SpawnModel::Deck,
+            parity: 0,
+            deck: vec![1, 1, 1, 1, 1],
+        };
+        assert!(spawner.check(1, NextHint::Tile(1)).is_err());
+        let spawner = Spawner {
+            model: SpawnModel::Deck,
+            parity: 0,
+            deck: vec![9],
+        };
+        assert!(spawner.check(1, NextHint::Tile(1)).is_err());
+        let spawner = Spawner::new(SpawnModel::Deck);
+        assert!(spawner
+            .check(5, NextHint::Bonus { min: 4, max: 20 })
+            .is_err());
+    }
+
+    #[test]
+    fn test_chance_state() {
+        let mut rng = RngType::seed_from_u64(3);
+        let board = board::Board::new();
+        let mut spawner = Spawner::new(SpawnModel::Deck);
+        assert_eq!(spawner.chance_state(), (0, [4, 4, 4]));
+        let (first, _) = spawner.first(&mut rng);
+        let mut expected = [4, 4, 4];
+        expected[first as usize - 1] -= 1;
+        assert_eq!(spawner.chance_state(), (0, expected));
+        // Drawing two different cards changes the chances, whatever the next tile is
+        let (second, _) = spawner.next(first, &board, &mut rng);
+        let mut other = Spawner::new(SpawnModel::Deck);
+        other.advance(0, second, NextHint::Tile(second));
+        assert_ne!(spawner.chance_state(), other.chance_state());
+
+        let mut parity = Spawner::new(SpawnModel::Parity);
+        parity.advance(2, 1, NextHint::Tile(1));
+        assert_eq!(parity.chance_state(), (1, [0, 0, 0]));
+    }
+
+    #[test]
+    fn test_deck() {
+        let mut rng = RngType::seed_from_u64(7);
+        let board = board::Board::new();
+        let mut spawner = Spawner::new(SpawnModel::Deck);
+        let (first, _) = spawner.first(&mut rng);
+        let mut drawn = vec![first];
+        for _ in 0..(DECK.len() * 10 - 1) {
+            let (rank, hint) = spawner.next(drawn[drawn.len() - 1], &board, &mut rng);
+            assert_eq!(hint, NextHint::Tile(rank));
+            drawn.push(rank);
+        }
+        // Every run through the deck draws every card exactly once
+        for deck in drawn.chunks(DECK.len()) {
+            let mut deck = deck.to_vec();
+            deck.sort_unstable();
+            assert_eq!(deck, DECK.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_two_four() {
+        let mut rng = RngType::seed_from_u64(7);
+        let board = board::Board::new();
+        let mut spawner = Spawner::new(SpawnModel::TwoFour);
+        let draws = (0..1000)
+            .map(|_| spawner.next(1, &board, &mut rng).0)
+            .collect::<Vec<board::RankIndex>>();
+        assert!(draws.iter().all(|r| *r == 1 || *r == 2));
+        let num_fours = draws.iter().filter(|r| **r == 2).count();
+        assert!(num_fours > 50 && num_fours < 150);
+    }
+
+    #[test]
+    fn test_distribution() {
+        let mut board = board::Board::new();
+        board.set_value(0, 0, 48);
+        for model in &[SpawnModel::Parity, SpawnModel::Deck, SpawnModel::TwoFour] {
+            let mut spawner = Spawner::new(*model);
+            let mut placed = 1;
+            for _ in 0..30 {
+                let outcomes = spawner.distribution(&board);
+                let total: f64 = outcomes.iter().map(|(_, _, p)| p).sum();
+                assert!((total - 1.0).abs() < 1e-9);
+                let (drawn, hint, _) = outcomes[outcomes.len() - 1];
+                spawner.advance(placed, drawn, hint);
+                placed = drawn;
+            }
+        }
+
+        // Parity is biased towards whichever of 1 and 2 there have been fewer of
+        let mut spawner = Spawner::new(SpawnModel::Parity);
+        spawner.advance(2, 1, NextHint::Tile(1));
+        let outcomes = spawner.distribution(&board);
+        assert_eq!(outcomes[0], (1, NextHint::Tile(1), 0.54));
+
+        // Cards which have been drawn are gone from the deck
+        let mut spawner = Spawner::new(SpawnModel::Deck);
+        let empty = board::Board::new();
+        for _ in 0..4 {
+            spawner.advance(1, 3, NextHint::Tile(3));
+        }
+        let outcomes = spawner.distribution(&empty);
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|(rank, _, p)| *rank != 3 && *p == 0.5));
+    }
+
+    #[test]
+    fn test_bonus_tiles() {
+        let mut rng = RngType::seed_from_u64(7);
+        let mut board = board::Board::new();
+        board.set_value(0, 0, 192);
+        let mut spawner = Spawner::new(SpawnModel::Deck);
+        let mut num_bonus = 0;
+        for _ in 0..1000 {
+            let (rank, hint) = spawner.next(1, &board, &mut rng);
+            if rank > 3 {
+                num_bonus += 1;
+                // 6, 12 or 24
+                assert!([4, 5, 6].contains(&rank));
+                assert_eq!(hint, NextHint::Bonus { min: 4, max: 6 });
+            } else {
+                assert_eq!(hint, NextHint::Tile(rank));
+            }
+        }
+        assert!(num_bonus > 0);
+
+        // No bonus tiles before reaching 48
+        board.set_value(0, 0, 24);
+        for _ in 0..1000 {
+            assert!(spawner.next(1, &board, &mut rng).0 <= 3);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use rand::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use super::agent_trainer;
+use super::agent_trainer::TrainConfig;
+use super::eval;
+use super::q_agent::QAgent;
+use super::rules;
+
+// The training parameters which can be swept over
+pub const ALL_PARAM_NAMES: [&str; 3] = ["learning_rate", "discount_factor", "explore_rate"];
+
+// The values a parameter is tried with
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamSpec {
+    // Written as a comma separated list, like 0.1,0.5,0.9
+    Values(Vec<f64>),
+    // Anywhere between two values, like 0.1..0.9. Only random searches can sample a range.
+    Range { low: f64, high: f64 },
+}
+
+impl FromStr for ParamSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ParamSpec, String> {
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<f64>()
+                .map_err(|_| format!("{} is not a number", v))
+        };
+        match s.split_once("..") {
+            Some((low, high)) => {
+                let (low, high) = (parse(low)?, parse(high)?);
+                if low > high {
+                    return Err(format!("the range {} is empty", s));
+                }
+                Ok(ParamSpec::Range { low, high })
+            }
+            None => Ok(ParamSpec::Values(
+                s.split(',')
+                    .map(parse)
+                    .collect::<Result<Vec<f64>, String>>()?,
+            )),
+        }
+    }
+}
+
+// A value for each swept parameter
+pub type Point = BTreeMap<String, f64>;
+
+// Every combination of the parameters' values
+pub fn grid(params: &[(String, ParamSpec)]) -> Result<Vec<Point>, String> {
+    let mut points = vec![Point::new()];
+    for (name, spec) in params.iter() {
+        let values = match spec {
+            ParamSpec::Values(values) => values,
+            ParamSpec::Range { .. } => {
+                return Err(format!("{} is a range, which a grid can't cover", name))
+            }
+        };
+        points = points
+            .iter()
+            .flat_map(|point| {
+                values.iter().map(move |value| {
+                    let mut point = point.clone();
+                    point.insert(name.clone(), *value);
+                    point
+                })
+            })
+            .collect();
+    }
+    Ok(points)
+}
+
+// `num_points` points, each parameter drawn uniformly from its range or its list of values
+pub fn random_search(
+    params: &[(String, ParamSpec)],
+    num_points: usize,
+    rng: &mut StdRng,
+) -> Vec<Point> {
+    (0..num_points)
+        .map(|_| {
+            params
+                .iter()
+                .map(|(name, spec)| {
+                    let value = match spec {
+                        ParamSpec::Values(values) => *values.choose(rng).unwrap(),
+                        ParamSpec::Range { low, high } if low == high => *low,
+                        ParamSpec::Range { low, high } => rng.gen_range(*low..*high),
+                    };
+                    (name.clone(), value)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// `config` with one of its parameters changed
+fn set_param(config: &mut TrainConfig, name: &str, value: f64) -> Result<(), String> {
+    match name {
+        "learning_rate" => config.learning_rate = value,
+        "discount_factor" => config.discount_factor = value,
+        "explore_rate" => config.explore_rate = value,
+        _ => return Err(format!("unknown parameter {}", name)),
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Trial {
+    // 1 for the best configuration
+    pub rank: usize,
+    pub params: Point,
+    pub config: TrainConfig,
+    pub evaluation: eval::Evaluation,
+}
+
+// The trials of a sweep, best first
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SweepResults {
+    pub trials: Vec<Trial>,
+}
+
+impl SweepResults {
+    // One row per trial, with a column for each swept parameter
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let names = match self.trials.first() {
+            Some(trial) => trial.params.keys().cloned().collect::<Vec<String>>(),
+            None => Vec::new(),
+        };
+        let mut header = vec!["rank".to_string()];
+        header.extend(names.iter().cloned());
+        header.extend(
+            [
+                "mean_score",
+                "mean_low",
+                "mean_high",
+                "median_score",
+                "mean_moves",
+            ]
+            .iter()
+            .map(|c| c.to_string()),
+        );
+        writeln!(writer, "{}", header.join(","))?;
+        for trial in self.trials.iter() {
+            let mut row = vec![trial.rank.to_string()];
+            row.extend(names.iter().map(|name| trial.params[name].to_string()));
+            let evaluation = &trial.evaluation;
+            row.extend(
+                [
+                    evaluation.mean_score.value,
+                    evaluation.mean_score.low,
+                    evaluation.mean_score.high,
+                    evaluation.median_score.value,
+                    evaluation.mean_moves,
+                ]
+                .iter()
+                .map(|v| v.to_string()),
+            );
+            writeln!(writer, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SweepResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trial in self.trials.iter() {
+            let params = trial
+                .params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>();
+            writeln!(
+                f,
+                "{:3}. {}: mean score {}",
+                trial.rank,
+                params.join(" "),
+                trial.evaluation.mean_score
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Train a q agent with `base` changed by each point, all at once, and rank them by their mean
+// score on `suite`. Every training run uses the base seed, so that configurations are compared on
+// the same games.
+pub fn run(
+    base: &TrainConfig,
+    points: &[Point],
+    suite: &eval::SeedSuite,
+) -> Result<SweepResults, String> {
+    let configs = points
+        .iter()
+        .map(|point| {
+            let mut config = base.clone();
+            // Trials are only evaluated once they are trained
+            config.eval_suite = None;
+            for (name, value) in point.iter() {
+                set_param(&mut config, name, *value)?;
+            }
+            Ok(config)
+        })
+        .collect::<Result<Vec<TrainConfig>, String>>()?;
+    let mut trials = points
+        .par_iter()
+        .zip(configs.into_par_iter())
+        .map(|(point, config)| {
+            let mut agent = QAgent::new(
+                None,
+                config.learning_rate,
+                config.discount_factor,
+                config.explore_rate,
+            )
+            .with_exploration(config.exploration, config.explore_schedule);
+            agent_trainer::train_agent_from_scratch(&mut agent, &config);
+            Ok(Trial {
+                rank: 0,
+                params: point.clone(),
+                evaluation: eval::evaluate(&mut agent, rules::classic(), suite)?,
+                config,
+            })
+        })
+        .collect::<Result<Vec<Trial>, String>>()?;
+    trials.sort_by(|l, r| {
+        r.evaluation
+            .mean_score
+            .value
+            .partial_cmp(&l.evaluation.mean_score.value)
+            .unwrap()
+    });
+    for (i, trial) in trials.iter_mut().enumerate() {
+        trial.rank = i + 1;
+    }
+    Ok(SweepResults { trials })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(specs: &[(&str, &str)]) -> Vec<(String, ParamSpec)> {
+        specs
+            .iter()
+            .map(|(name, spec)| (name.to_string(), spec.parse::<ParamSpec>().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_param_spec() {
+        assert_eq!(
+            "0.1, 0.5".parse::<ParamSpec>(),
+            Ok(ParamSpec::Values(vec![0.1, 0.5]))
+        );
+        assert_eq!(
+            "0.1..0.9".parse::<ParamSpec>(),
+            Ok(ParamSpec::Range {
+                low: 0.1,
+                high: 0.9
+            })
+        );
+        assert!("0.9..0.1".parse::<ParamSpec>().is_err());
+        assert!("fast".parse::<ParamSpec>().is_err());
+    }
+
+    #[test]
+    fn test_search_spaces() {
+        let points = grid(&params(&[
+            ("learning_rate", "0.1,0.5"),
+            ("explore_rate", "0.0,0.1,0.2"),
+        ]))
+        .unwrap();
+        assert_eq!(points.len(), 6);
+        assert!(points.contains(
+            &[
+                ("learning_rate".to_string(), 0.5),
+                ("explore_rate".to_string(), 0.2)
+            ]
+            .iter()
+            .cloned()
+            .collect()
+        ));
+        assert!(grid(&params(&[("learning_rate", "0.1..0.5")])).is_err());
+
+        let space = params(&[("learning_rate", "0.1..0.5"), ("explore_rate", "0.0,0.1")]);
+        let points = random_search(&space, 10, &mut StdRng::seed_from_u64(1));
+        assert_eq!(points.len(), 10);
+        assert!(points
+            .iter()
+            .all(|p| (0.1..0.5).contains(&p["learning_rate"])
+                && (p["explore_rate"] == 0.0 || p["explore_rate"] == 0.1)));
+    }
+
+    #[test]
+    fn test_run() {
+        let base = TrainConfig {
+            seed: 3,
+            num_generations: 2,
+            num_episodes_per_gen: 5,
+            ..TrainConfig::default()
+        };
+        let points = grid(&params(&[("learning_rate", "0.1,0.9")])).unwrap();
+        let suite = eval::SeedSuite::by_name(eval::SMALL).unwrap();
+        let results = run(&base, &points, &suite).unwrap();
+        assert_eq!(results.trials.len(), 2);
+        assert_eq!(results.trials[0].rank, 1);
+        assert!(
+            results.trials[0].evaluation.mean_score.value
+                >= results.trials[1].evaluation.mean_score.value
+        );
+        let trial = &results.trials[0];
+        assert_eq!(trial.config.learning_rate, trial.params["learning_rate"]);
+
+        let mut csv = Vec::new();
+        results.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines = csv.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("rank,learning_rate,mean_score"));
+
+        let bad = [[("gamma".to_string(), 0.5)].iter().cloned().collect()];
+        assert!(run(&base, &bad, &suite).is_err());
+    }
+}
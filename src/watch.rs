@@ -0,0 +1,119 @@
+use std::io::{stdout, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+use super::agent_runner::Agent;
+use super::board::Direction;
+use super::game::{Game, MoveResult};
+use super::introspect::Explanation;
+
+// Playback can't get faster or slower than this
+const MIN_DELAY: Duration = Duration::from_millis(10);
+const MAX_DELAY: Duration = Duration::from_secs(10);
+// How often keys are checked for between moves
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// What the agent chose and, if it says, why, with the chosen move marked
+fn describe_choice(choice: Direction, explanation: Option<&Explanation>) -> String {
+    let mut description = format!("agent plays {:?}", choice);
+    if let Some(explanation) = explanation {
+        description.push_str(&format!(" ({})", explanation.describe(choice)));
+    }
+    description
+}
+
+// Play `game` with `agent`, drawing every move. Moves are made every `delay` unless paused.
+pub fn watch_game(mut game: Game, agent: &mut dyn Agent, delay: Duration) {
+    let mut stdout = stdout().into_raw_mode().unwrap();
+    let mut keys = termion::async_stdin().keys();
+    let mut delay = delay;
+    let mut paused = false;
+    let mut last_move: Option<String> = None;
+    let mut game_over: Option<String> = None;
+    let mut last_move_time = Instant::now();
+    let mut redraw = true;
+    agent.start_episode(game.seed, false);
+    loop {
+        let mut step = false;
+        while let Some(Ok(key)) = keys.next() {
+            match key {
+                Key::Char('q') => {
+                    write!(stdout, "{}", termion::cursor::Show).unwrap();
+                    return;
+                }
+                Key::Char(' ') => paused = !paused,
+                Key::Char('n') | Key::Right => step = true,
+                Key::Char('+') => delay = (delay / 2).max(MIN_DELAY),
+                Key::Char('-') => delay = (delay * 2).min(MAX_DELAY),
+                _ => {}
+            }
+            redraw = true;
+        }
+
+        let due = !paused && last_move_time.elapsed() >= delay;
+        if game_over.is_none() && (step || due) {
+            let explanation = agent.explain(&game);
+            let direction = agent.take_action(&game, false);
+            last_move = Some(describe_choice(direction, explanation.as_ref()));
+            match game.update(direction) {
+                MoveResult::Moved(Some(result)) => {
+                    game_over = Some(format!("Game over with {} points", result.score));
+                    agent.end_episode(&result, false);
+                }
+                MoveResult::Moved(None) => {}
+                MoveResult::Failed => panic!("agent chose a move it can't make"),
+            }
+            last_move_time = Instant::now();
+            redraw = true;
+        }
+
+        if redraw {
+            write!(
+                stdout,
+                "{}{}{}q to exit, space to pause, n to step, + and - to speed up and slow down\r\n",
+                termion::clear::All,
+                termion::cursor::Goto(1, 1),
+                termion::cursor::Hide
+            )
+            .unwrap();
+            write!(
+                stdout,
+                "move {}, {} points, a move every {}ms{}\r\n",
+                game.num_moves(),
+                game.cur_score(),
+                delay.as_millis(),
+                if paused { " (paused)" } else { "" }
+            )
+            .unwrap();
+            if let Some(description) = last_move.as_ref() {
+                write!(stdout, "{}\r\n", description).unwrap();
+            }
+            write!(stdout, "\r\n{}\r\n", game.render()).unwrap();
+            if let Some(message) = game_over.as_ref() {
+                write!(stdout, "\r\n{}\r\n", message).unwrap();
+            }
+            stdout.flush().unwrap();
+            redraw = false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_choice() {
+        assert_eq!(describe_choice(Direction::Up, None), "agent plays Up");
+        let explanation =
+            Explanation::from_values(vec![(Direction::Up, 1.0), (Direction::Left, 2.26)]);
+        assert_eq!(
+            describe_choice(Direction::Left, Some(&explanation)),
+            "agent plays Left (Up: 1.0  *Left: 2.3)"
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use threes::board::Direction;
+use threes::game::{Game, MoveResult};
+use threes::protocol::ProtocolError;
+use threes::server::{Client, MoveOutcome, Server};
+
+// Play a game to the end on the server, always taking the last legal move
+fn play(addr: std::net::SocketAddr, seed: u64) -> (Vec<Direction>, threes::game::GameResult) {
+    let mut client = Client::connect(addr).unwrap();
+    let (game_id, mut state) = client.new_game(seed, Some("threes")).unwrap();
+    let mut moves = Vec::new();
+    loop {
+        let d = state.legal_moves[state.legal_moves.len() - 1];
+        moves.push(d);
+        match client.make_move(game_id, d).unwrap() {
+            MoveOutcome::Playing(next) => state = next,
+            MoveOutcome::GameOver(result) => return (moves, result),
+        }
+    }
+}
+
+#[test]
+fn test_concurrent_games() {
+    let server = Arc::new(Server::bind("127.0.0.1:0").unwrap());
+    let addr = server.local_addr().unwrap();
+    let runner = server.clone();
+    thread::spawn(move || runner.run());
+
+    let players = (0..4)
+        .map(|seed| thread::spawn(move || (seed, play(addr, seed))))
+        .collect::<Vec<_>>();
+    for player in players {
+        let (seed, (moves, result)) = player.join().unwrap();
+        // The server plays exactly the same game as a local one
+        let mut game =
+            Game::with_rules(Some(seed), false, threes::rules::by_name("threes").unwrap());
+        for d in &moves {
+            assert!(!matches!(game.update(*d), MoveResult::Failed));
+        }
+        assert_eq!(game.cur_score(), result.score);
+        let log = result.log.unwrap();
+        assert_eq!(log.seed, seed);
+        assert_eq!(log.moves, moves);
+        assert_eq!(log.rules, "threes");
+    }
+
+    let mut logs = server.logs();
+    logs.sort_by_key(|log| log.seed);
+    assert_eq!(
+        logs.iter().map(|log| log.seed).collect::<Vec<u64>>(),
+        vec![0, 1, 2, 3]
+    );
+}
+
+#[test]
+fn test_errors() {
+    let server = Arc::new(Server::bind("127.0.0.1:0").unwrap());
+    let addr = server.local_addr().unwrap();
+    let runner = server.clone();
+    thread::spawn(move || runner.run());
+
+    let mut client = Client::connect(addr).unwrap();
+    assert!(matches!(
+        client.new_game(0, Some("checkers")),
+        Err(ProtocolError::Rejected(_))
+    ));
+    assert!(matches!(
+        client.make_move(42, Direction::Up),
+        Err(ProtocolError::Rejected(_))
+    ));
+
+    let (game_id, state) = client.new_game(7, None).unwrap();
+    let d = state.legal_moves[0];
+    let state = match client.make_move(game_id, d).unwrap() {
+        MoveOutcome::Playing(state) => state,
+        MoveOutcome::GameOver(_) => panic!("the game ended after one move"),
+    };
+    // Games can be looked at from any connection, and a refused move changes nothing
+    let mut other = Client::connect(addr).unwrap();
+    assert_eq!(other.state(game_id).unwrap(), state);
+    let illegal = [
+        Direction::Down,
+        Direction::Up,
+        Direction::Left,
+        Direction::Right,
+    ]
+    .iter()
+    .find(|d| !state.legal_moves.contains(d));
+    if let Some(d) = illegal {
+        assert!(matches!(
+            client.make_move(game_id, *d),
+            Err(ProtocolError::Rejected(_))
+        ));
+        assert_eq!(other.state(game_id).unwrap(), state);
+    }
+}
+
+#[test]
+fn test_idle_games_are_forgotten() {
+    let server = Arc::new(
+        Server::bind("127.0.0.1:0")
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(200)),
+    );
+    let addr = server.local_addr().unwrap();
+    let runner = server.clone();
+    thread::spawn(move || runner.run());
+
+    let mut client = Client::connect(addr).unwrap();
+    let (game_id, state) = client.new_game(1, None).unwrap();
+    assert_eq!(client.state(game_id).unwrap(), state);
+    thread::sleep(Duration::from_millis(400));
+    assert!(matches!(
+        client.state(game_id),
+        Err(ProtocolError::Rejected(_))
+    ));
+}